@@ -0,0 +1,66 @@
+//! Finding the rule for every process of a large process table, with the
+//! compiled index against trying each rule in turn.
+//!
+//! Run with `cargo bench --bench matcher`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rust_ananicy::matcher::{self, Matcher, ProcessView};
+use rust_ananicy::Rule;
+
+const NAME_RULES: usize = 1800;
+const CMDLINE_RULES: usize = 200;
+const PROCESSES: usize = 1500;
+
+fn rules() -> Vec<Rule> {
+    let names = (0..NAME_RULES).map(|i| format!(r#"{{"name": "proc{}", "nice": 5}}"#, i));
+    let cmdlines = (0..CMDLINE_RULES)
+        .map(|i| format!(r#"{{"cmdlines": ["--job", "worker{}.jar"], "nice": 10}}"#, i));
+    names
+        .chain(cmdlines)
+        .map(|json| serde_json::from_str(&json).unwrap())
+        .collect()
+}
+
+/// Processes of which about a third match a name rule, a few a cmdline rule
+/// and the rest nothing.
+fn processes() -> Vec<(String, Vec<String>)> {
+    (0..PROCESSES)
+        .map(|i| match i % 3 {
+            0 => (format!("proc{}", i), vec![format!("/usr/bin/proc{}", i)]),
+            1 if i % 10 == 1 => (
+                "java".to_string(),
+                vec!["java".to_string(), "--job".to_string(), format!("/opt/worker{}.jar", i % CMDLINE_RULES)],
+            ),
+            _ => (format!("other{}", i), vec![format!("/usr/lib/other{}", i), "--flag".to_string()]),
+        })
+        .collect()
+}
+
+fn find_rules(c: &mut Criterion) {
+    let rules = rules();
+    let processes = processes();
+    let views: Vec<ProcessView> = processes
+        .iter()
+        .map(|(name, cmdline)| ProcessView { name, exe: Some(name), cmdline, uid: Some(1000) })
+        .collect();
+    let matcher = Matcher::new(rules.clone());
+
+    let mut group = c.benchmark_group("find_rules");
+    group.bench_function("linear", |b| {
+        b.iter(|| {
+            views
+                .iter()
+                .filter(|process| rules.iter().any(|rule| matcher::matches(rule, process)))
+                .count()
+        })
+    });
+    group.bench_function("indexed", |b| {
+        b.iter(|| views.iter().filter(|process| matcher.find(process).is_some()).count())
+    });
+    group.finish();
+
+    c.bench_function("compile_rules", |b| b.iter(|| Matcher::new(black_box(rules.clone()))));
+}
+
+criterion_group!(benches, find_rules);
+criterion_main!(benches);
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus};
+use log::{debug, warn};
+use thiserror::Error;
+use crate::error::Error;
+use crate::process::ProcessInfo;
+use crate::rules::Rule;
+use crate::topology::{self, CpuSet, TOPOLOGY};
+
+/// A failed rule application, tagged with the action that failed.
+#[derive(Debug, Error)]
+#[error("{action}: {source}")]
+pub struct ApplyError {
+    pub action: &'static str,
+    #[source]
+    pub source: ActionError,
+}
+
+/// Why a single action of a rule could not be applied.
+#[derive(Debug, Error)]
+pub enum ActionError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("{command} failed ({status})")]
+    Command { command: &'static str, status: ExitStatus },
+
+    #[error(transparent)]
+    Config(#[from] Error),
+
+    /// Placing the process in its cgroup failed, with the OS error if
+    /// there was one.
+    #[error("cgroup {cgroup}: {message}")]
+    Cgroup { cgroup: String, message: String, errno: Option<i32> },
+}
+
+impl ApplyError {
+    fn new<E: Into<ActionError>>(action: &'static str) -> impl FnOnce(E) -> Self {
+        move |error| Self { action, source: error.into() }
+    }
+
+    /// Coarse error classification used as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        let os_error = match self.source {
+            ActionError::Io(ref error) => error.raw_os_error(),
+            ActionError::Command { .. } => return "command_failed",
+            ActionError::Config(_) => return "invalid_argument",
+            ActionError::Cgroup { errno, .. } => errno,
+        };
+        match os_error {
+            Some(libc::EPERM) | Some(libc::EACCES) => "permission_denied",
+            Some(libc::ESRCH) | Some(libc::ENOENT) => "no_such_process",
+            Some(libc::EINVAL) => "invalid_argument",
+            Some(_) => "other",
+            None => "command_failed",
+        }
+    }
+}
+
+/// Performs the individual actions of a rule on a process.
+pub trait Actuator {
+    fn set_nice(&self, pid: i32, nice: i32) -> Result<(), ActionError>;
+
+    fn set_ioprio(&self, pid: i32, class: Option<&str>, level: Option<i32>) -> Result<(), ActionError>;
+
+    fn set_scheduler(&self, pid: i32, policy: &str, rtprio: Option<i32>) -> Result<(), ActionError>;
+
+    fn set_oom_score_adj(&self, pid: i32, score: i32) -> Result<(), ActionError>;
+
+    fn set_cpu_affinity(&self, pid: i32, cpus: &CpuSet) -> Result<(), ActionError>;
+
+    fn add_to_cgroup(&self, pid: i32, cgroup: &dyn CgroupHandle) -> Result<(), ActionError>;
+}
+
+/// A cgroup rules can move processes into.
+pub trait CgroupHandle {
+    /// Name of the cgroup definition, as used by the `cgroup` of rules.
+    fn name(&self) -> &str;
+
+    /// Move `pid` with all its threads into the group.
+    fn add_process(&self, pid: i32) -> Result<(), ActionError>;
+}
+
+/// Where the `cgroup` of a rule is looked up; `()` has no cgroups.
+pub trait CgroupLookup {
+    fn cgroup(&self, name: &str) -> Option<&dyn CgroupHandle>;
+}
+
+impl<C: CgroupHandle> CgroupLookup for HashMap<String, C> {
+    fn cgroup(&self, name: &str) -> Option<&dyn CgroupHandle> {
+        self.get(name).map(|cgroup| cgroup as &dyn CgroupHandle)
+    }
+}
+
+impl CgroupLookup for () {
+    fn cgroup(&self, _name: &str) -> Option<&dyn CgroupHandle> {
+        None
+    }
+}
+
+/// Applies actions to real processes through syscalls, procfs and the
+/// `ionice` and `schedtool` utilities.
+pub struct SystemActuator {
+    proc_root: PathBuf,
+}
+
+impl SystemActuator {
+    pub fn new(proc_root: impl Into<PathBuf>) -> Self {
+        Self { proc_root: proc_root.into() }
+    }
+}
+
+impl Actuator for SystemActuator {
+    fn set_nice(&self, pid: i32, nice: i32) -> Result<(), ActionError> {
+        let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, pid as u32, nice) };
+        if result != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        debug!("Set nice {} for process {}", nice, pid);
+        Ok(())
+    }
+
+    fn set_ioprio(&self, pid: i32, class: Option<&str>, level: Option<i32>) -> Result<(), ActionError> {
+        let mut cmd = Command::new("ionice");
+        cmd.arg("-p").arg(pid.to_string());
+
+        if let Some(class) = class {
+            let class_num = match class {
+                "none" | "best-effort" => 2,
+                "idle" => 3,
+                "realtime" => 1,
+                _ => 2,
+            };
+            cmd.arg("-c").arg(class_num.to_string());
+        }
+
+        if let Some(level) = level {
+            cmd.arg("-n").arg(level.to_string());
+        }
+
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(ActionError::Command { command: "ionice", status: output.status });
+        }
+
+        debug!("Set ionice for process {}", pid);
+        Ok(())
+    }
+
+    fn set_scheduler(&self, pid: i32, policy: &str, rtprio: Option<i32>) -> Result<(), ActionError> {
+        let mut cmd = Command::new("schedtool");
+
+        let sched_arg = match policy {
+            "other" | "normal" => "-N",
+            "rr" => "-R",
+            "fifo" => "-F",
+            "batch" => "-B",
+            "iso" => "-I",
+            "idle" => "-D",
+            _ => "-N",
+        };
+
+        cmd.arg(sched_arg);
+
+        if let Some(prio) = rtprio {
+            if policy == "rr" || policy == "fifo" {
+                cmd.arg("-p").arg(prio.to_string());
+            }
+        }
+
+        cmd.arg(pid.to_string());
+
+        let output = cmd.output()?;
+        if !output.status.success() {
+            warn!("Failed to set scheduler for process {}", pid);
+        } else {
+            debug!("Set scheduler {} for process {}", policy, pid);
+        }
+
+        Ok(())
+    }
+
+    fn set_oom_score_adj(&self, pid: i32, score: i32) -> Result<(), ActionError> {
+        let path = self.proc_root.join(pid.to_string()).join("oom_score_adj");
+        fs::write(&path, score.to_string())?;
+        debug!("Set OOM score {} for process {}", score, pid);
+        Ok(())
+    }
+
+    fn set_cpu_affinity(&self, pid: i32, cpus: &CpuSet) -> Result<(), ActionError> {
+        topology::set_affinity(&self.proc_root.join(pid.to_string()), cpus)?;
+        debug!("Set CPU affinity {} for process {}", cpus, pid);
+        Ok(())
+    }
+
+    fn add_to_cgroup(&self, pid: i32, cgroup: &dyn CgroupHandle) -> Result<(), ActionError> {
+        cgroup.add_process(pid)
+    }
+}
+
+/// Apply every setting of `rule` to `process`, stopping at the first action
+/// that fails. Cgroups missing from `cgroups` are skipped.
+pub fn apply_rule(
+    actuator: &dyn Actuator,
+    process: &ProcessInfo,
+    rule: &Rule,
+    cgroups: &dyn CgroupLookup,
+) -> Result<(), ApplyError> {
+    let pid = process.pid;
+
+    // User rules may only make a process nicer than it already is ...
+    if let Some(nice) = rule.nice.filter(|&nice| rule.owner.is_none() || nice > process.nice) {
+        actuator.set_nice(pid, nice).map_err(ApplyError::new("nice"))?;
+    }
+
+    if rule.ioclass.is_some() || rule.ionice.is_some() {
+        actuator.set_ioprio(pid, rule.ioclass.as_deref(), rule.ionice)
+            .map_err(ApplyError::new("ionice"))?;
+    }
+
+    // ... to a policy below the one it has ...
+    let sched = rule.sched.as_deref()
+        .filter(|&sched| rule.owner.is_none() || policy_rank(sched) < policy_rank_of(process.sched_policy));
+    if let Some(sched) = sched {
+        actuator.set_scheduler(pid, sched, rule.rtprio).map_err(ApplyError::new("sched"))?;
+    }
+
+    // ... and more likely to be chosen by the OOM killer
+    let oom_score_adj = rule.oom_score_adj.filter(|&oom| rule.owner.is_none() || oom > process.oom_score_adj);
+    if let Some(oom) = oom_score_adj {
+        actuator.set_oom_score_adj(pid, oom).map_err(ApplyError::new("oom_score_adj"))?;
+    }
+
+    let cgroup = rule.cgroup.as_deref().and_then(|name| cgroups.cgroup(name));
+    if let Some(cgroup) = cgroup {
+        actuator.add_to_cgroup(pid, cgroup).map_err(ApplyError::new("cgroup"))?;
+    }
+
+    // Processes in a managed cgroup get their CPUs from its AllowedCPUs
+    if let Some(spec) = rule.cpu_affinity.as_deref().filter(|_| cgroup.is_none()) {
+        let cpus = TOPOLOGY.resolve_affinity(spec).map_err(ApplyError::new("cpu_affinity"))?;
+        actuator.set_cpu_affinity(pid, &cpus).map_err(ApplyError::new("cpu_affinity"))?;
+    }
+
+    Ok(())
+}
+
+/// Order of scheduling policies by the CPU time they get, lowest first.
+fn policy_rank(policy: &str) -> u8 {
+    match policy {
+        "idle" => 0,
+        "batch" => 1,
+        "other" | "normal" => 2,
+        _ => 3,
+    }
+}
+
+/// [`policy_rank`] of a kernel `SCHED_*` number.
+fn policy_rank_of(policy: u32) -> u8 {
+    match policy as i32 {
+        libc::SCHED_IDLE => 0,
+        libc::SCHED_BATCH => 1,
+        libc::SCHED_OTHER => 2,
+        _ => 3,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{Action, RecordingActuator};
+
+    struct Group;
+
+    impl CgroupHandle for Group {
+        fn name(&self) -> &str {
+            "games"
+        }
+
+        fn add_process(&self, _pid: i32) -> Result<(), ActionError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn affinity_is_left_to_managed_cgroups() {
+        let rule: Rule = serde_json::from_str(r#"{"name": "game", "cgroup": "games", "cpu_affinity": "0"}"#).unwrap();
+        let process = ProcessInfo { pid: 7, name: "game".to_string(), ..ProcessInfo::default() };
+        let actuator = RecordingActuator::new();
+
+        let cgroups = HashMap::from([("games".to_string(), Group)]);
+        apply_rule(&actuator, &process, &rule, &cgroups).unwrap();
+        assert_eq!(actuator.take(), vec![Action::Cgroup { pid: 7, cgroup: "games".to_string() }]);
+
+        // Without the group the rule pins the process itself
+        apply_rule(&actuator, &process, &rule, &()).unwrap();
+        assert_eq!(actuator.take(), vec![Action::CpuAffinity { pid: 7, cpus: "0".to_string() }]);
+    }
+}
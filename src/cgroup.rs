@@ -1,12 +1,37 @@
 use std::path::{Path, PathBuf};
 use std::fs;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, anyhow, bail};
 use log::{info, warn};
+use once_cell::sync::Lazy;
+use procfs::{FromRead, Meminfo};
+use crate::actuator::{ActionError, CgroupHandle};
+use crate::compat::{self, Dialect};
+use crate::metrics::METRICS;
+use crate::power::PowerSource;
+use crate::psi::Adaptive;
+use crate::hierarchy::{CgroupFs, Mode};
+use crate::systemd::{self, Systemd};
+use crate::topology::{self, CpuSet, TOPOLOGY};
+use crate::profile::PROFILES_DIR;
+use crate::schema;
+use crate::users::USERS_DIR;
 
-const CGROUP_FS: &str = "/sys/fs/cgroup";
 const PERIOD_US: u64 = 100000;
+/// State file listing the cgroups created by the daemon, one relative path per line.
+const MANAGED_FILE: &str = "cgroups";
+/// Limit files compared before and after applying a definition.
+const LIMIT_FILES: [&str; 18] = [
+    "cpu.max", "cpu.weight", "cpu.cfs_period_us", "cpu.cfs_quota_us", "cpu.shares",
+    "memory.low", "memory.high", "memory.max", "memory.swap.max",
+    "memory.soft_limit_in_bytes", "memory.limit_in_bytes", "memory.memsw.limit_in_bytes",
+    "io.weight", "io.max", "blkio.weight", "cpuset.cpus", "cpuset.mems", "pids.max",
+];
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum CgroupVersion {
@@ -14,145 +39,1821 @@ enum CgroupVersion {
     V2,
 }
 
+/// How cgroups are created and processes placed in them.
+#[derive(Debug, Clone, Default)]
+pub enum Backend {
+    /// Directories below the daemon's own cgroup, written directly.
+    #[default]
+    Cgroupfs,
+    /// Transient slices and scopes created through systemd.
+    Systemd(Arc<Systemd>),
+}
+
+impl Backend {
+    /// Select the backend named by the `cgroup_backend` setting.
+    pub fn select(name: &str, fs: &CgroupFs) -> Result<Self> {
+        match name {
+            "cgroupfs" => Ok(Backend::Cgroupfs),
+            "systemd" => Self::systemd(fs),
+            "auto" | "" => {
+                if !Path::new("/run/systemd/system").exists() || cgroup_version(fs) != CgroupVersion::V2 {
+                    return Ok(Backend::Cgroupfs);
+                }
+                match Self::systemd(fs) {
+                    Ok(backend) => Ok(backend),
+                    Err(e) => {
+                        warn!("systemd is not reachable, writing cgroups directly: {:#}", e);
+                        Ok(Backend::Cgroupfs)
+                    }
+                }
+            }
+            other => bail!("unknown cgroup backend '{}' (expected auto, systemd or cgroupfs)", other),
+        }
+    }
+
+    fn systemd(fs: &CgroupFs) -> Result<Self> {
+        if cgroup_version(fs) != CgroupVersion::V2 {
+            bail!("the systemd cgroup backend requires the unified (v2) hierarchy");
+        }
+        let systemd = Systemd::connect()?;
+        systemd.ensure_slice(systemd::ROOT_SLICE, Vec::new())?;
+        Ok(Backend::Systemd(Arc::new(systemd)))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CgroupController {
     name: String,
+    /// Location below the daemon's cgroup, `parent/.../name`.
+    rel_path: PathBuf,
     path: PathBuf,
     version: CgroupVersion,
-    cpu_quota: u32,
-    quota_us: u64,
+    def: CgroupDef,
+    cpu_quota: CpuQuota,
+    /// CPUs the quota was computed for.
+    cpus: usize,
+    /// Bandwidth per period, `None` when the quota does not limit.
+    quota_us: Option<u64>,
     cpu_shares: u64,
+    io_weight: Option<u32>,
+    /// Additional v1 hierarchies (besides cpu) this cgroup was created in.
+    v1_hierarchies: Vec<PathBuf>,
+    /// Last seen `max` count of `pids.events`.
+    pids_max_events: u64,
+    frozen: bool,
+    reconciled: Reconciled,
+    backend: Backend,
+    fs: Arc<CgroupFs>,
+}
+
+/// What creating a controller did to its cgroup directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reconciled {
+    Created,
+    Unchanged,
+    /// The directory existed with different limits, which were rewritten.
+    Corrected(Vec<String>),
+    /// The directory has different limits than the definition; only reported
+    /// by [`CgroupController::inspect`], which leaves them as they are.
+    Drifted(Vec<String>),
+}
+
+impl std::fmt::Display for Reconciled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reconciled::Created => write!(f, "created"),
+            Reconciled::Unchanged => write!(f, "unchanged"),
+            Reconciled::Corrected(files) => write!(f, "corrected {}", files.join(", ")),
+            Reconciled::Drifted(files) => write!(f, "drifted {}", files.join(", ")),
+        }
+    }
+}
+
+/// The daemon's cgroups as found on disk, without creating or changing any.
+#[derive(Debug, Default)]
+pub struct Inspection {
+    pub cgroups: Vec<CgroupController>,
+    /// Defined groups that have no directory yet.
+    pub missing: Vec<PathBuf>,
+    /// Groups of an earlier run that are no longer defined, with their process count.
+    pub stale: Vec<(PathBuf, usize)>,
+}
+
+/// Result of removing cgroups that are no longer defined.
+#[derive(Debug, Default)]
+pub struct Cleanup {
+    pub removed: Vec<PathBuf>,
+    /// Still holding processes, retried on the next pass.
+    pub kept: Vec<PathBuf>,
+}
+
+/// Cgroup directories processes were in before the daemon moved them, one
+/// per hierarchy, so they can be put back on shutdown. Kept across reloads.
+static ORIGINS: Lazy<Mutex<HashMap<i32, Origin>>> = Lazy::new(Default::default);
+
+/// Where a process came from, tied to its start time so a reused pid does
+/// not inherit the origin of the process that had it before.
+#[derive(Debug, Default)]
+struct Origin {
+    start_time: Option<u64>,
+    dirs: Vec<PathBuf>,
+}
+
+/// Number of recorded origins above which exited processes are dropped.
+const ORIGINS_PRUNE: usize = 1024;
+
+/// Highest `CPUQuotaTotal`, all usable CPUs.
+const CPU_QUOTA_TOTAL_MAX: u32 = 100;
+
+pub(crate) const IO_WEIGHT_MIN: u32 = 1;
+pub(crate) const IO_WEIGHT_MAX: u32 = 10000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CgroupDef {
+    pub cgroup: String,
+    /// Name of the group this one is nested in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Percent of a single CPU, like systemd's `CPUQuota=` (200 allows two CPUs).
+    #[serde(rename = "CPUQuota", default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota: Option<u32>,
+    /// Percent of all CPUs the group may run on.
+    #[serde(rename = "CPUQuotaTotal", default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(max = "CPU_QUOTA_TOTAL_MAX"))]
+    pub cpu_quota_total: Option<u32>,
+    /// Quota used instead while running on battery, in the same unit.
+    #[serde(rename = "CPUQuotaBattery", default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota_battery: Option<u32>,
+    /// Bounds for pressure-driven adjustment of the limits.
+    #[serde(rename = "Adaptive", default, skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<Adaptive>,
+    /// Throttle the group above this amount of memory.
+    #[serde(rename = "MemoryHigh", default, skip_serializing_if = "Option::is_none")]
+    pub memory_high: Option<MemoryLimit>,
+    /// Hard limit, the OOM killer is invoked above it.
+    #[serde(rename = "MemoryMax", default, skip_serializing_if = "Option::is_none")]
+    pub memory_max: Option<MemoryLimit>,
+    /// Best-effort protection from reclaim.
+    #[serde(rename = "MemoryLow", default, skip_serializing_if = "Option::is_none")]
+    pub memory_low: Option<MemoryLimit>,
+    #[serde(rename = "MemorySwapMax", default, skip_serializing_if = "Option::is_none")]
+    pub memory_swap_max: Option<MemoryLimit>,
+    /// Kill the whole group rather than a single process on OOM.
+    #[serde(rename = "OOMGroup", default, skip_serializing_if = "Option::is_none")]
+    pub oom_group: Option<bool>,
+    /// Proportional I/O weight, 1-10000 (default 100).
+    #[serde(rename = "IOWeight", default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(min = "IO_WEIGHT_MIN", max = "IO_WEIGHT_MAX"))]
+    pub io_weight: Option<u32>,
+    /// Per-device limits keyed by device node or any path on the device.
+    #[serde(rename = "IOReadBandwidthMax", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub io_read_bandwidth_max: BTreeMap<String, IoLimit>,
+    #[serde(rename = "IOWriteBandwidthMax", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub io_write_bandwidth_max: BTreeMap<String, IoLimit>,
+    #[serde(rename = "IOReadIOPSMax", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub io_read_iops_max: BTreeMap<String, IoLimit>,
+    #[serde(rename = "IOWriteIOPSMax", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub io_write_iops_max: BTreeMap<String, IoLimit>,
+    #[serde(rename = "IODeviceLatencyTargetSec", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub io_latency_target: BTreeMap<String, LatencyTarget>,
+    /// CPU list or symbolic set (`performance`, `efficiency`, `vcache`).
+    #[serde(rename = "AllowedCPUs", default, skip_serializing_if = "Option::is_none")]
+    pub allowed_cpus: Option<String>,
+    /// NUMA memory node list.
+    #[serde(rename = "AllowedMemoryNodes", default, skip_serializing_if = "Option::is_none")]
+    pub allowed_memory_nodes: Option<String>,
+    /// Maximum number of tasks (processes and threads) in the group.
+    #[serde(rename = "TasksMax", default, skip_serializing_if = "Option::is_none")]
+    pub tasks_max: Option<TasksLimit>,
+    /// Keep every process of the group stopped while this definition is loaded.
+    #[serde(rename = "Freeze", default, skip_serializing_if = "Option::is_none")]
+    pub freeze: Option<bool>,
+}
+
+/// A CPU limit in the unit it was configured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuQuota {
+    /// `CPUQuota`: percent of one CPU.
+    PerCpu(u32),
+    /// `CPUQuotaTotal`: percent of all usable CPUs.
+    Total(u32),
+}
+
+impl CpuQuota {
+    pub fn percent(self) -> u32 {
+        match self {
+            CpuQuota::PerCpu(percent) | CpuQuota::Total(percent) => percent,
+        }
+    }
+
+    /// The same unit with another value.
+    pub fn with_percent(self, percent: u32) -> Self {
+        match self {
+            CpuQuota::PerCpu(_) => CpuQuota::PerCpu(percent),
+            CpuQuota::Total(_) => CpuQuota::Total(percent),
+        }
+    }
+
+    /// CPU time in microseconds per second on `ncpu` CPUs, `None` if that is no limit.
+    pub fn usec_per_sec(self, ncpu: usize) -> Option<u64> {
+        let ncpu = ncpu.max(1) as u64;
+        let usec = match self {
+            CpuQuota::PerCpu(percent) => 10_000 * percent as u64,
+            CpuQuota::Total(percent) => 10_000 * ncpu * percent as u64,
+        };
+        (usec < 1_000_000 * ncpu).then_some(usec)
+    }
+
+    /// Share of `ncpu` CPUs in percent, which scales `cpu.weight` and `cpu.shares`.
+    pub fn total_percent(self, ncpu: usize) -> u32 {
+        match self {
+            CpuQuota::PerCpu(percent) => (percent / ncpu.max(1) as u32).min(100),
+            CpuQuota::Total(percent) => percent,
+        }
+    }
+}
+
+impl std::fmt::Display for CpuQuota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CpuQuota::PerCpu(percent) => write!(f, "CPUQuota={}%", percent),
+            CpuQuota::Total(percent) => write!(f, "CPUQuotaTotal={}%", percent),
+        }
+    }
+}
+
+/// A memory amount: bytes (with optional K/M/G/T suffix), a percentage of
+/// physical memory, or `max`/`infinity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "LimitValue", into = "String")]
+pub enum MemoryLimit {
+    Bytes(u64),
+    Percent(u32),
+    Max,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LimitValue {
+    Number(u64),
+    Text(String),
+}
+
+impl TryFrom<LimitValue> for MemoryLimit {
+    type Error = String;
+
+    fn try_from(value: LimitValue) -> std::result::Result<Self, Self::Error> {
+        match value {
+            LimitValue::Number(bytes) => Ok(MemoryLimit::Bytes(bytes)),
+            LimitValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl std::str::FromStr for MemoryLimit {
+    type Err = String;
+
+    fn from_str(text: &str) -> std::result::Result<Self, Self::Err> {
+        let text = text.trim();
+        if text == "max" || text == "infinity" {
+            return Ok(MemoryLimit::Max);
+        }
+        if let Some(percent) = text.strip_suffix('%') {
+            return percent.trim().parse().map(MemoryLimit::Percent)
+                .map_err(|_| format!("invalid memory percentage '{}'", text));
+        }
+        parse_size(text).map(MemoryLimit::Bytes)
+    }
+}
+
+impl JsonSchema for MemoryLimit {
+    fn schema_name() -> String {
+        "MemoryLimit".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schema::number_or_string(
+            InstanceType::Integer,
+            r"^\s*(max|infinity|\d+\s*%|\d+\s*[KkMmGgTt]?)\s*$",
+            "Bytes with an optional K/M/G/T suffix, a percentage of physical memory, or max",
+        )
+    }
+}
+
+/// Parse a size with an optional binary K/M/G/T suffix.
+fn parse_size(text: &str) -> std::result::Result<u64, String> {
+    let (number, multiplier) = match text.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&text[..i], 1u64 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&text[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&text[..i], 1 << 30),
+        Some((i, 'T')) | Some((i, 't')) => (&text[..i], 1 << 40),
+        _ => (text, 1),
+    };
+    number.trim().parse::<u64>()
+        .map(|n| n.saturating_mul(multiplier))
+        .map_err(|_| format!("invalid size '{}'", text))
+}
+
+/// A per-device bandwidth (bytes/s, K/M/G/T suffixes) or IOPS limit, or `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "LimitValue", into = "String")]
+pub enum IoLimit {
+    Value(u64),
+    Max,
+}
+
+impl TryFrom<LimitValue> for IoLimit {
+    type Error = String;
+
+    fn try_from(value: LimitValue) -> std::result::Result<Self, Self::Error> {
+        match value {
+            LimitValue::Number(n) => Ok(IoLimit::Value(n)),
+            LimitValue::Text(text) if text == "max" || text == "infinity" => Ok(IoLimit::Max),
+            LimitValue::Text(text) => parse_size(text.trim()).map(IoLimit::Value),
+        }
+    }
+}
+
+impl From<IoLimit> for String {
+    fn from(limit: IoLimit) -> Self {
+        match limit {
+            IoLimit::Value(value) => value.to_string(),
+            IoLimit::Max => "max".to_string(),
+        }
+    }
+}
+
+impl JsonSchema for IoLimit {
+    fn schema_name() -> String {
+        "IoLimit".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schema::number_or_string(
+            InstanceType::Integer,
+            r"^(max|infinity|\s*\d+\s*[KkMmGgTt]?\s*)$",
+            "Bytes per second with an optional K/M/G/T suffix, IOPS, or max",
+        )
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct CgroupDef {
-    cgroup: String,
-    #[serde(rename = "CPUQuota")]
-    cpu_quota: u32,
+/// A task count, a percentage of `kernel.pid_max`, or `max`/`infinity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "LimitValue", into = "String")]
+pub enum TasksLimit {
+    Count(u64),
+    Percent(u32),
+    Max,
+}
+
+impl TryFrom<LimitValue> for TasksLimit {
+    type Error = String;
+
+    fn try_from(value: LimitValue) -> std::result::Result<Self, Self::Error> {
+        match value {
+            LimitValue::Number(n) => Ok(TasksLimit::Count(n)),
+            LimitValue::Text(text) => {
+                let text = text.trim();
+                if text == "max" || text == "infinity" {
+                    Ok(TasksLimit::Max)
+                } else if let Some(percent) = text.strip_suffix('%') {
+                    percent.trim().parse().map(TasksLimit::Percent)
+                        .map_err(|_| format!("invalid tasks percentage '{}'", text))
+                } else {
+                    text.parse().map(TasksLimit::Count)
+                        .map_err(|_| format!("invalid tasks limit '{}'", text))
+                }
+            }
+        }
+    }
+}
+
+impl From<TasksLimit> for String {
+    fn from(limit: TasksLimit) -> Self {
+        match limit {
+            TasksLimit::Count(count) => count.to_string(),
+            TasksLimit::Percent(percent) => format!("{}%", percent),
+            TasksLimit::Max => "max".to_string(),
+        }
+    }
+}
+
+impl JsonSchema for TasksLimit {
+    fn schema_name() -> String {
+        "TasksLimit".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schema::number_or_string(
+            InstanceType::Integer,
+            r"^\s*(max|infinity|\d+\s*%|\d+)\s*$",
+            "A task count, a percentage of kernel.pid_max, or max",
+        )
+    }
+}
+
+impl TasksLimit {
+    /// Value for `pids.max`, which is the same on v1 and v2; percentages are
+    /// of `sys/kernel/pid_max` below `proc_root`.
+    pub fn value(self, proc_root: &Path) -> String {
+        match self {
+            TasksLimit::Count(count) => count.to_string(),
+            TasksLimit::Percent(percent) => {
+                let pid_max: u64 = fs::read_to_string(proc_root.join("sys/kernel/pid_max")).ok()
+                    .and_then(|v| v.trim().parse().ok())
+                    .unwrap_or(32768);
+                (pid_max * percent as u64 / 100).max(1).to_string()
+            }
+            TasksLimit::Max => "max".to_string(),
+        }
+    }
+}
+
+/// A latency target in microseconds, written as `25ms`, `500us`, `0.1s` or
+/// plain seconds like systemd's `IODeviceLatencyTargetSec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "DurationValue", into = "String")]
+pub struct LatencyTarget(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(f64),
+    Text(String),
+}
+
+impl TryFrom<DurationValue> for LatencyTarget {
+    type Error = String;
+
+    fn try_from(value: DurationValue) -> std::result::Result<Self, Self::Error> {
+        let (number, scale) = match value {
+            DurationValue::Seconds(seconds) => return Ok(LatencyTarget((seconds * 1e6) as u64)),
+            DurationValue::Text(ref text) => {
+                let text = text.trim();
+                if let Some(n) = text.strip_suffix("us") {
+                    (n, 1.0)
+                } else if let Some(n) = text.strip_suffix("ms") {
+                    (n, 1e3)
+                } else if let Some(n) = text.strip_suffix('s') {
+                    (n, 1e6)
+                } else {
+                    (text, 1e6)
+                }
+            }
+        };
+        number.trim().parse::<f64>()
+            .map(|n| LatencyTarget((n * scale) as u64))
+            .map_err(|_| format!("invalid latency target '{}'", number))
+    }
+}
+
+impl JsonSchema for LatencyTarget {
+    fn schema_name() -> String {
+        "LatencyTarget".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        schema::number_or_string(
+            InstanceType::Number,
+            r"^\s*\d+(\.\d+)?\s*(us|ms|s)?\s*$",
+            "Seconds, or a duration with a us, ms or s suffix",
+        )
+    }
+}
+
+impl From<LatencyTarget> for String {
+    fn from(target: LatencyTarget) -> Self {
+        format!("{}us", target.0)
+    }
+}
+
+impl From<MemoryLimit> for String {
+    fn from(limit: MemoryLimit) -> Self {
+        match limit {
+            MemoryLimit::Bytes(bytes) => bytes.to_string(),
+            MemoryLimit::Percent(percent) => format!("{}%", percent),
+            MemoryLimit::Max => "max".to_string(),
+        }
+    }
+}
+
+impl MemoryLimit {
+    /// Absolute byte count, `None` for no limit; percentages are of the
+    /// `MemTotal` in `meminfo` below `proc_root`, and an error if it cannot
+    /// be read rather than a limit of zero.
+    pub fn bytes(self, proc_root: &Path) -> Result<Option<u64>> {
+        match self {
+            MemoryLimit::Bytes(bytes) => Ok(Some(bytes)),
+            MemoryLimit::Percent(percent) => {
+                let path = proc_root.join("meminfo");
+                let meminfo = Meminfo::from_file(&path)
+                    .with_context(|| format!("{}% of memory: cannot read {}", percent, path.display()))?;
+                Ok(Some(meminfo.mem_total / 100 * percent as u64))
+            }
+            MemoryLimit::Max => Ok(None),
+        }
+    }
+
+    fn v2_value(self, proc_root: &Path) -> Result<String> {
+        Ok(self.bytes(proc_root)?.map_or_else(|| "max".to_string(), |b| b.to_string()))
+    }
+
+    fn v1_value(self, proc_root: &Path) -> Result<String> {
+        Ok(self.bytes(proc_root)?.map_or_else(|| "-1".to_string(), |b| b.to_string()))
+    }
+}
+
+impl CgroupDef {
+    /// CPU quota that applies for the given power source; no limit if none is set.
+    pub fn cpu_quota_for(&self, source: PowerSource) -> CpuQuota {
+        let quota = match (self.cpu_quota, self.cpu_quota_total) {
+            (Some(percent), _) => CpuQuota::PerCpu(percent),
+            (None, Some(percent)) => CpuQuota::Total(percent),
+            (None, None) => CpuQuota::Total(100),
+        };
+        match (source, self.cpu_quota_battery) {
+            (PowerSource::Battery, Some(percent)) => quota.with_percent(percent),
+            _ => quota,
+        }
+    }
+    
+    pub fn validate(&self) -> Result<()> {
+        if self.cpu_quota.is_some() && self.cpu_quota_total.is_some() {
+            bail!("set either CPUQuota (percent of one CPU) or CPUQuotaTotal (percent of all CPUs), not both");
+        }
+        if self.cpu_quota_total.is_some_and(|percent| percent > CPU_QUOTA_TOTAL_MAX) {
+            bail!("CPUQuotaTotal is a share of the machine and cannot exceed {}", CPU_QUOTA_TOTAL_MAX);
+        }
+        for (key, percent) in [
+            ("CPUQuota", self.cpu_quota),
+            ("CPUQuotaTotal", self.cpu_quota_total),
+            ("CPUQuotaBattery", self.cpu_quota_battery),
+        ] {
+            if percent == Some(0) {
+                bail!("{} must be above 0, the kernel does not accept an empty quota", key);
+            }
+        }
+        // The battery quota takes the unit of the AC one
+        let total_unit = matches!(self.cpu_quota_for(PowerSource::Ac), CpuQuota::Total(_));
+        if total_unit && self.cpu_quota_battery.is_some_and(|percent| percent > CPU_QUOTA_TOTAL_MAX) {
+            bail!("CPUQuotaBattery is a share of the machine here and cannot exceed {}", CPU_QUOTA_TOTAL_MAX);
+        }
+        if let Some(weight) = self.io_weight.filter(|weight| !(IO_WEIGHT_MIN..=IO_WEIGHT_MAX).contains(weight)) {
+            bail!("IOWeight must be between {} and {}, got {}", IO_WEIGHT_MIN, IO_WEIGHT_MAX, weight);
+        }
+        for (key, limit) in [
+            ("MemoryHigh", self.memory_high),
+            ("MemoryMax", self.memory_max),
+            ("MemoryLow", self.memory_low),
+            ("MemorySwapMax", self.memory_swap_max),
+        ] {
+            if let Some(MemoryLimit::Percent(percent)) = limit {
+                if percent > 100 {
+                    bail!("{} is a share of physical memory and cannot exceed 100%, got {}%", key, percent);
+                }
+            }
+        }
+        if let Some(TasksLimit::Percent(percent)) = self.tasks_max {
+            if percent > 100 {
+                bail!("TasksMax is a share of kernel.pid_max and cannot exceed 100%, got {}%", percent);
+            }
+        }
+        if let Some(adaptive) = &self.adaptive {
+            adaptive.validate()?;
+        }
+        Ok(())
+    }
+    
+    /// Controllers this group needs enabled in its parent's subtree.
+    pub fn controllers(&self) -> Vec<&'static str> {
+        let mut controllers = vec!["cpu"];
+        if self.has_io_settings() {
+            controllers.push("io");
+        }
+        if self.has_memory_settings() {
+            controllers.push("memory");
+        }
+        if self.has_cpuset_settings() {
+            controllers.push("cpuset");
+        }
+        if self.tasks_max.is_some() {
+            controllers.push("pids");
+        }
+        controllers
+    }
+    
+    pub fn has_cpuset_settings(&self) -> bool {
+        self.allowed_cpus.is_some() || self.allowed_memory_nodes.is_some()
+    }
+    
+    pub fn has_io_settings(&self) -> bool {
+        self.io_weight.is_some()
+            || !self.io_read_bandwidth_max.is_empty()
+            || !self.io_write_bandwidth_max.is_empty()
+            || !self.io_read_iops_max.is_empty()
+            || !self.io_write_iops_max.is_empty()
+            || !self.io_latency_target.is_empty()
+            || self.adaptive.as_ref().is_some_and(|a| a.io_weight_max.is_some())
+    }
+    
+    pub fn has_memory_settings(&self) -> bool {
+        self.memory_high.is_some()
+            || self.memory_max.is_some()
+            || self.memory_low.is_some()
+            || self.memory_swap_max.is_some()
+            || self.oom_group.is_some()
+    }
+}
+
+impl CgroupHandle for CgroupController {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn add_process(&self, pid: i32) -> std::result::Result<(), ActionError> {
+        self.add_pid(pid).map_err(|e| ActionError::Cgroup {
+            cgroup: self.name.clone(),
+            message: format!("{:#}", e),
+            errno: e.chain().find_map(|e| e.downcast_ref::<std::io::Error>()).and_then(std::io::Error::raw_os_error),
+        })
+    }
 }
 
 impl CgroupController {
-    pub fn new(name: String, cpu_quota: u32) -> Result<Self> {
-        let version = detect_cgroup_version();
-        let ncpu = num_cpus::get() as u64;
-        let quota_us = PERIOD_US * ncpu * (cpu_quota as u64) / 100;
-        let cpu_shares = 1024 * (cpu_quota as u64) / 100;
+    /// Create the group at `rel_path`; parents must have been created before.
+    pub fn new(
+        def: &CgroupDef,
+        rel_path: &Path,
+        source: PowerSource,
+        backend: &Backend,
+        cgroup_fs: &Arc<CgroupFs>,
+    ) -> Result<Self> {
+        let name = def.cgroup.clone();
+        let version = cgroup_version(cgroup_fs);
+
+        if let Backend::Systemd(ref systemd) = backend {
+            let unit = systemd::slice_name(rel_path);
+            systemd.ensure_slice(&unit, systemd::slice_properties(def, source, cgroup_fs))?;
+            let mut controller = Self {
+                name,
+                rel_path: rel_path.to_path_buf(),
+                path: cgroup_fs.hierarchies().unified_mount().join(systemd::slice_cgroup(rel_path)),
+                version,
+                def: def.clone(),
+                cpu_quota: CpuQuota::Total(100),
+                cpus: 0,
+                quota_us: None,
+                cpu_shares: 0,
+                io_weight: def.io_weight,
+                v1_hierarchies: Vec::new(),
+                pids_max_events: 0,
+                frozen: false,
+                reconciled: Reconciled::Unchanged,
+                backend: backend.clone(),
+                fs: cgroup_fs.clone(),
+            };
+            // Corrected by `refresh_cpus` if systemd's effective cpuset differs
+            controller.record_cpu_quota(def.cpu_quota_for(source), systemd::slice_cpus(def, cgroup_fs.sys_root()));
+            controller.pids_max_events = controller.read_pids_max_events().unwrap_or(0);
+            controller.frozen = controller.read_frozen();
+            if def.freeze == Some(true) {
+                controller.set_frozen(true)?;
+            }
+            return Ok(controller);
+        }
+
+        let path = match version {
+            CgroupVersion::V1 => v1_mount(cgroup_fs, "cpu")?.join(rel_path),
+            CgroupVersion::V2 => {
+                // A controller is only usable if every ancestor delegates it
+                let base = v2_delegated_base(cgroup_fs)?;
+                let mut level = base.clone();
+                let mut levels = vec![base.clone()];
+                for component in rel_path.parent().into_iter().flat_map(Path::components) {
+                    level = level.join(component);
+                    levels.push(level.clone());
+                }
+                for level in &levels {
+                    for controller in def.controllers() {
+                        enable_controller_v2_at(level, controller)?;
+                    }
+                }
+                base.join(rel_path)
+            }
+        };
+        let before = path.exists().then(|| snapshot(cgroup_fs, rel_path));
+        if !path.exists() {
+            if let Err(e) = fs::create_dir_all(&path)
+                .with_context(|| format!("mkdir {}", path.display())) {
+                warn!("Could not create {:?} cgroup directory: {e}", version);
+            }
+        }
+
+        let mut controller = Self {
+            name,
+            rel_path: rel_path.to_path_buf(),
+            path,
+            version,
+            def: def.clone(),
+            cpu_quota: CpuQuota::Total(100),
+            cpus: 0,
+            quota_us: None,
+            cpu_shares: 0,
+            io_weight: None,
+            v1_hierarchies: Vec::new(),
+            pids_max_events: 0,
+            frozen: false,
+            reconciled: Reconciled::Created,
+            backend: Backend::Cgroupfs,
+            fs: cgroup_fs.clone(),
+        };
+        if version == CgroupVersion::V1 {
+            // Accounting only, but lets `dump cgroups` report CPU time
+            if let Err(e) = controller.v1_hierarchy("cpuacct") {
+                warn!("Cgroup {}: no cpuacct hierarchy: {:#}", controller.name, e);
+            }
+            // Placed in the freezer hierarchy up front so it can be frozen on demand
+            if cgroup_fs.hierarchies().v1_mount("freezer").is_some() {
+                controller.v1_hierarchy("freezer")?;
+            }
+        }
+        // The cpuset decides how many CPUs the quota is computed for
+        controller.apply_cpuset_settings()?;
+        controller.set_cpu_quota(def.cpu_quota_for(source))?;
+        controller.apply_memory_settings()?;
+        controller.apply_io_settings()?;
+        controller.apply_tasks_max()?;
+        // A group left frozen by an earlier run is thawed by the daemon's next freezer pass
+        controller.frozen = controller.read_frozen();
+        if def.freeze == Some(true) {
+            controller.set_frozen(true)?;
+        }
+
+        if let Some(before) = before {
+            let after = snapshot(cgroup_fs, rel_path);
+            let changed: Vec<String> = after.iter()
+                .filter(|(file, value)| before.get(*file) != Some(*value))
+                .map(|(file, _)| file.clone())
+                .collect();
+            if changed.is_empty() {
+                controller.reconciled = Reconciled::Unchanged;
+            } else {
+                info!("Cgroup {}: corrected {}", controller.name, changed.join(", "));
+                controller.reconciled = Reconciled::Corrected(changed);
+            }
+        }
+        Ok(controller)
+    }
+    
+    /// Read the existing group at `rel_path` without writing anything; `None`
+    /// if its directory does not exist.
+    pub fn inspect(
+        def: &CgroupDef,
+        rel_path: &Path,
+        source: PowerSource,
+        backend: &Backend,
+        cgroup_fs: &Arc<CgroupFs>,
+    ) -> Result<Option<Self>> {
+        let version = cgroup_version(cgroup_fs);
+        let path = match (backend, version) {
+            (Backend::Systemd(_), _) => cgroup_fs.hierarchies().unified_mount().join(systemd::slice_cgroup(rel_path)),
+            (Backend::Cgroupfs, CgroupVersion::V1) => v1_mount(cgroup_fs, "cpu")?.join(rel_path),
+            (Backend::Cgroupfs, CgroupVersion::V2) => v2_delegated_base(cgroup_fs)?.join(rel_path),
+        };
+        if !path.is_dir() {
+            return Ok(None);
+        }
+
+        let v1_hierarchies = match version {
+            CgroupVersion::V1 => ["cpuacct", "freezer", "memory", "blkio", "cpuset", "pids"].into_iter()
+                .filter_map(|controller| cgroup_fs.hierarchies().v1_mount(controller))
+                .map(|mount| mount.join(rel_path))
+                .filter(|dir| *dir != path && dir.is_dir())
+                .collect(),
+            CgroupVersion::V2 => Vec::new(),
+        };
+        let mut controller = Self {
+            name: def.cgroup.clone(),
+            rel_path: rel_path.to_path_buf(),
+            path,
+            version,
+            def: def.clone(),
+            cpu_quota: CpuQuota::Total(100),
+            cpus: 0,
+            quota_us: None,
+            cpu_shares: 0,
+            io_weight: def.io_weight,
+            v1_hierarchies,
+            pids_max_events: 0,
+            frozen: false,
+            reconciled: Reconciled::Unchanged,
+            backend: backend.clone(),
+            fs: cgroup_fs.clone(),
+        };
+        let expected = controller.effective_cpus();
+        controller.record_cpu_quota(def.cpu_quota_for(source), expected);
+        if let Some(quota_us) = controller.read_quota_us() {
+            if quota_us != controller.quota_us {
+                let file = match version {
+                    CgroupVersion::V1 => "cpu.cfs_quota_us",
+                    CgroupVersion::V2 => "cpu.max",
+                };
+                controller.reconciled = Reconciled::Drifted(vec![file.to_string()]);
+                controller.quota_us = quota_us;
+            }
+        }
+        controller.pids_max_events = controller.read_pids_max_events().unwrap_or(0);
+        controller.frozen = controller.read_frozen();
+        Ok(Some(controller))
+    }
+
+    /// Bandwidth per period currently in `cpu.max` (v2) or `cpu.cfs_quota_us`
+    /// (v1), `Some(None)` when unlimited and `None` if unreadable.
+    fn read_quota_us(&self) -> Option<Option<u64>> {
+        let value = match self.version {
+            CgroupVersion::V1 => read_trimmed(&self.path.join("cpu.cfs_quota_us")),
+            CgroupVersion::V2 => read_trimmed(&self.path.join("cpu.max")).split_whitespace().next()?.to_string(),
+        };
+        match value.as_str() {
+            "max" | "-1" => Some(None),
+            value => value.parse().ok().map(Some),
+        }
+    }
+
+    /// Directory of this cgroup in the v1 hierarchy of `controller`, created on demand.
+    fn v1_hierarchy(&mut self, controller: &str) -> Result<PathBuf> {
+        let path = v1_mount(&self.fs, controller)?.join(&self.rel_path);
+        // Co-mounted with cpu, e.g. `cpu,cpuacct`
+        if path == self.path {
+            return Ok(path);
+        }
+        if !path.exists() {
+            fs::create_dir_all(&path)
+                .with_context(|| format!("mkdir {}", path.display()))?;
+        }
+        if !self.v1_hierarchies.contains(&path) {
+            self.v1_hierarchies.push(path.clone());
+        }
+        Ok(path)
+    }
+    
+    /// Write the memory controller settings of the definition.
+    fn apply_memory_settings(&mut self) -> Result<()> {
+        let def = self.def.clone();
+        if !def.has_memory_settings() {
+            return Ok(());
+        }
+
+        match self.version {
+            CgroupVersion::V2 => {
+                let settings = [
+                    ("memory.low", def.memory_low),
+                    ("memory.high", def.memory_high),
+                    ("memory.max", def.memory_max),
+                    ("memory.swap.max", def.memory_swap_max),
+                ];
+                for (file, limit) in settings {
+                    match limit.map(|limit| limit.v2_value(self.fs.proc_root())) {
+                        Some(Ok(value)) => write_setting(&self.path, file, &value),
+                        Some(Err(e)) => warn!("Cgroup {}: not setting {}: {:#}", self.name, file, e),
+                        None => {}
+                    }
+                }
+                if let Some(oom_group) = def.oom_group {
+                    write_setting(&self.path, "memory.oom.group", if oom_group { "1" } else { "0" });
+                }
+            }
+            CgroupVersion::V1 => {
+                let path = self.v1_hierarchy("memory")?;
+                let proc_root = self.fs.proc_root();
+                let settings = [
+                    ("memory.soft_limit_in_bytes", def.memory_high),
+                    ("memory.limit_in_bytes", def.memory_max),
+                ];
+                for (file, limit) in settings {
+                    match limit.map(|limit| limit.v1_value(proc_root)) {
+                        Some(Ok(value)) => write_setting(&path, file, &value),
+                        Some(Err(e)) => warn!("Cgroup {}: not setting {}: {:#}", self.name, file, e),
+                        None => {}
+                    }
+                }
+                // v1 limits memory+swap together, so the swap limit builds on MemoryMax
+                if let Some(swap) = def.memory_swap_max {
+                    let memory = def.memory_max.map(|limit| limit.bytes(proc_root)).transpose().map(Option::flatten);
+                    match (memory, swap.bytes(proc_root)) {
+                        (Err(e), _) | (_, Err(e)) => {
+                            warn!("Cgroup {}: not setting memory.memsw.limit_in_bytes: {:#}", self.name, e);
+                        }
+                        (Ok(Some(memory)), Ok(Some(swap))) => {
+                            write_setting(&path, "memory.memsw.limit_in_bytes", &(memory + swap).to_string());
+                        }
+                        (_, Ok(None)) => write_setting(&path, "memory.memsw.limit_in_bytes", "-1"),
+                        (Ok(None), Ok(Some(_))) => {
+                            warn!("Cgroup {}: MemorySwapMax on cgroup v1 requires MemoryMax", self.name);
+                        }
+                    }
+                }
+                if def.memory_low.is_some() {
+                    warn!("Cgroup {}: MemoryLow is not supported on cgroup v1", self.name);
+                }
+                if def.oom_group.is_some() {
+                    warn!("Cgroup {}: OOMGroup is not supported on cgroup v1", self.name);
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Write CPU limits for `cpu_quota`, scaled to the CPUs of the effective cpuset.
+    pub fn set_cpu_quota(&mut self, cpu_quota: CpuQuota) -> Result<()> {
+        let ncpu = self.effective_cpus();
+        if let Backend::Systemd(ref systemd) = self.backend {
+            systemd.set_properties(&systemd::slice_name(&self.rel_path), systemd::cpu_quota_properties(cpu_quota, ncpu))?;
+            self.record_cpu_quota(cpu_quota, ncpu);
+            return Ok(());
+        }
+
+        let (quota_us, cpu_shares) = quota_values(cpu_quota, ncpu);
+        let path = &self.path;
+
+        match self.version {
+            CgroupVersion::V1 => {
+                let quota_us = quota_us.map_or("-1".to_string(), |quota_us| quota_us.to_string());
+                fs::write(path.join("cpu.cfs_period_us"), PERIOD_US.to_string())
+                    .with_context(|| format!("write {}", path.join("cpu.cfs_period_us").display()))?;
+                fs::write(path.join("cpu.cfs_quota_us"), quota_us)
+                    .with_context(|| format!("write {}", path.join("cpu.cfs_quota_us").display()))?;
+                fs::write(path.join("cpu.shares"), cpu_shares.to_string())
+                    .with_context(|| format!("write {}", path.join("cpu.shares").display()))?;
+            }
+            CgroupVersion::V2 => {
+                let max_value = quota_us.map_or("max".to_string(), |quota_us| quota_us.to_string());
+                let cpu_max = format!("{} {}", max_value, PERIOD_US);
+                if let Err(e) = fs::write(path.join("cpu.max"), cpu_max)
+                    .with_context(|| format!("write {}", path.join("cpu.max").display())) {
+                    warn!("Could not set cpu.max: {e}");
+                }
+                let weight = (cpu_quota.total_percent(ncpu) as u64 * 100).clamp(1, 10000);
+                if let Err(e) = fs::write(path.join("cpu.weight"), weight.to_string())
+                    .with_context(|| format!("write {}", path.join("cpu.weight").display())) {
+                    warn!("Could not set cpu.weight: {e}");
+                }
+            }
+        }
+
+        self.record_cpu_quota(cpu_quota, ncpu);
+        Ok(())
+    }
+    
+    fn record_cpu_quota(&mut self, cpu_quota: CpuQuota, ncpu: usize) {
+        let (quota_us, cpu_shares) = quota_values(cpu_quota, ncpu);
+        self.cpu_quota = cpu_quota;
+        self.cpus = ncpu;
+        self.quota_us = quota_us;
+        self.cpu_shares = cpu_shares;
+    }
+    
+    /// Number of CPUs in the effective cpuset of the group, or of its nearest
+    /// ancestor with one; all online CPUs if neither is readable.
+    pub fn effective_cpus(&self) -> usize {
+        let (dir, root, files) = match self.version {
+            CgroupVersion::V2 => (self.path.clone(), self.fs.hierarchies().unified_mount(), &["cpuset.cpus.effective"][..]),
+            CgroupVersion::V1 => match v1_mount(&self.fs, "cpuset") {
+                Ok(mount) => (mount.join(&self.rel_path), mount, &["cpuset.effective_cpus", "cpuset.cpus"][..]),
+                Err(_) => return topology::online_cpus(self.fs.sys_root()),
+            },
+        };
+        for level in dir.ancestors().take_while(|level| level.starts_with(root)) {
+            for file in files {
+                if let Ok(cpus) = CpuSet::parse_list(&read_trimmed(&level.join(file))) {
+                    if !cpus.is_empty() {
+                        return cpus.len();
+                    }
+                }
+            }
+        }
+        topology::online_cpus(self.fs.sys_root())
+    }
+    
+    /// Recompute the CPU limits if the usable CPUs changed, e.g. by hotplug.
+    pub fn refresh_cpus(&mut self) -> Result<()> {
+        let ncpu = self.effective_cpus();
+        if ncpu != self.cpus {
+            info!("Cgroup {}: {} usable CPUs (was {}), updating {}", self.name, ncpu, self.cpus, self.cpu_quota);
+            self.set_cpu_quota(self.cpu_quota)?;
+        }
+        Ok(())
+    }
+    
+    /// Write the proportional I/O weight (1-10000, v1 blkio clamps to 10-1000).
+    pub fn set_io_weight(&mut self, weight: u32) -> Result<()> {
+        if let Backend::Systemd(ref systemd) = self.backend {
+            systemd.set_properties(&systemd::slice_name(&self.rel_path), systemd::io_weight_properties(weight))?;
+            self.io_weight = Some(weight);
+            return Ok(());
+        }
+
+        let (path, value) = match self.version {
+            CgroupVersion::V2 => {
+                (self.path.join("io.weight"), format!("default {}", weight.clamp(1, 10000)))
+            }
+            CgroupVersion::V1 => {
+                (self.v1_hierarchy("blkio")?.join("blkio.weight"), weight.clamp(10, 1000).to_string())
+            }
+        };
+        fs::write(&path, value)
+            .with_context(|| format!("write {}", path.display()))?;
+        self.io_weight = Some(weight);
+        Ok(())
+    }
+    
+    /// Write the cpuset controller settings of the definition.
+    fn apply_cpuset_settings(&mut self) -> Result<()> {
+        let def = self.def.clone();
+        if !def.has_cpuset_settings() {
+            return Ok(());
+        }
+
+        let cpus = match def.allowed_cpus {
+            Some(ref spec) => match TOPOLOGY.resolve(spec) {
+                Ok(cpus) => Some(cpus.to_string()),
+                Err(e) => {
+                    warn!("Cgroup {}: ignoring AllowedCPUs: {:#}", self.name, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let mems = def.allowed_memory_nodes.clone();
+
+        match self.version {
+            CgroupVersion::V2 => {
+                if let Some(cpus) = cpus {
+                    write_setting(&self.path, "cpuset.cpus", &cpus);
+                }
+                if let Some(mems) = mems {
+                    write_setting(&self.path, "cpuset.mems", &mems);
+                }
+            }
+            CgroupVersion::V1 => {
+                // v1 cpusets refuse tasks until both cpus and mems are set,
+                // including intermediate groups without cpuset settings
+                let path = self.v1_hierarchy("cpuset")?;
+                let root = v1_mount(&self.fs, "cpuset")?.to_path_buf();
+                let mut level = root.clone();
+                for component in self.rel_path.parent().into_iter().flat_map(Path::components) {
+                    let parent = level.clone();
+                    level = level.join(component);
+                    for file in ["cpuset.cpus", "cpuset.mems"] {
+                        if read_trimmed(&level.join(file)).is_empty() {
+                            write_setting(&level, file, &read_trimmed(&parent.join(file)));
+                        }
+                    }
+                }
+                let parent = path.parent().unwrap_or(&root);
+                write_setting(&path, "cpuset.cpus", &cpus.unwrap_or_else(|| read_trimmed(&parent.join("cpuset.cpus"))));
+                write_setting(&path, "cpuset.mems", &mems.unwrap_or_else(|| read_trimmed(&parent.join("cpuset.mems"))));
+            }
+        }
+        Ok(())
+    }
+    
+    /// Write `pids.max` and remember how often the limit was already hit.
+    fn apply_tasks_max(&mut self) -> Result<()> {
+        let limit = match self.def.tasks_max {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let path = match self.version {
+            CgroupVersion::V2 => self.path.clone(),
+            CgroupVersion::V1 => self.v1_hierarchy("pids")?,
+        };
+        write_setting(&path, "pids.max", &limit.value(self.fs.proc_root()));
+        self.pids_max_events = self.read_pids_max_events().unwrap_or(0);
+        Ok(())
+    }
+    
+    fn pids_path(&self) -> PathBuf {
+        match self.version {
+            CgroupVersion::V2 => self.path.clone(),
+            CgroupVersion::V1 => self.fs.hierarchies().v1_mount("pids").unwrap_or(Path::new("/")).join(&self.rel_path),
+        }
+    }
+    
+    /// The `max` counter of `pids.events`: forks refused because of `pids.max`.
+    fn read_pids_max_events(&self) -> Option<u64> {
+        let events = fs::read_to_string(self.pids_path().join("pids.events")).ok()?;
+        events.lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(key, _)| *key == "max")
+            .and_then(|(_, value)| value.trim().parse().ok())
+    }
+    
+    /// Current `pids.events` max counter and how much it grew since the last check.
+    pub fn check_pids_events(&mut self) -> Option<(u64, u64)> {
+        self.def.tasks_max?;
+        let count = self.read_pids_max_events()?;
+        let new = count.saturating_sub(self.pids_max_events);
+        self.pids_max_events = count;
+        Some((count, new))
+    }
+    
+    /// Current `pids.max` of the group, if a `TasksMax` is configured.
+    pub fn tasks_max(&self) -> Option<String> {
+        self.def.tasks_max?;
+        fs::read_to_string(self.pids_path().join("pids.max")).ok()
+            .map(|value| value.trim().to_string())
+    }
+    
+    pub fn pids_max_events(&self) -> u64 {
+        self.pids_max_events
+    }
+    
+    /// Write the I/O controller settings of the definition.
+    fn apply_io_settings(&mut self) -> Result<()> {
+        let def = self.def.clone();
+        if let Some(weight) = def.io_weight {
+            if let Err(e) = self.set_io_weight(weight) {
+                warn!("Could not set IO weight of cgroup {}: {:#}", self.name, e);
+            }
+        }
+
+        let limits = [
+            ("rbps", "blkio.throttle.read_bps_device", &def.io_read_bandwidth_max),
+            ("wbps", "blkio.throttle.write_bps_device", &def.io_write_bandwidth_max),
+            ("riops", "blkio.throttle.read_iops_device", &def.io_read_iops_max),
+            ("wiops", "blkio.throttle.write_iops_device", &def.io_write_iops_max),
+        ];
+
+        match self.version {
+            CgroupVersion::V2 => {
+                // io.max takes all limits of one device on a single line
+                let mut io_max: BTreeMap<String, Vec<String>> = BTreeMap::new();
+                for (key, _, devices) in limits {
+                    for (device, limit) in devices {
+                        if let Some(devno) = resolve_device(&self.fs, &self.name, device) {
+                            io_max.entry(devno).or_default().push(format!("{}={}", key, String::from(*limit)));
+                        }
+                    }
+                }
+                for (devno, settings) in io_max {
+                    write_setting(&self.path, "io.max", &format!("{} {}", devno, settings.join(" ")));
+                }
+                for (device, target) in &def.io_latency_target {
+                    if let Some(devno) = resolve_device(&self.fs, &self.name, device) {
+                        write_setting(&self.path, "io.latency", &format!("{} target={}", devno, target.0));
+                    }
+                }
+            }
+            CgroupVersion::V1 => {
+                if limits.iter().any(|(_, _, devices)| !devices.is_empty()) {
+                    let path = self.v1_hierarchy("blkio")?;
+                    for (_, file, devices) in limits {
+                        for (device, limit) in devices {
+                            if let Some(devno) = resolve_device(&self.fs, &self.name, device) {
+                                let value = match limit {
+                                    IoLimit::Value(value) => value.to_string(),
+                                    IoLimit::Max => "0".to_string(),
+                                };
+                                write_setting(&path, file, &format!("{} {}", devno, value));
+                            }
+                        }
+                    }
+                }
+                if !def.io_latency_target.is_empty() {
+                    warn!("Cgroup {}: IODeviceLatencyTargetSec is not supported on cgroup v1", self.name);
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Switch to the limits configured for `source`, if they differ.
+    pub fn apply_power_source(&mut self, source: PowerSource) -> Result<()> {
+        let cpu_quota = self.def.cpu_quota_for(source);
+        if cpu_quota != self.cpu_quota {
+            info!("Setting CPU quota of cgroup {} to {} ({:?})", self.name, cpu_quota, source);
+            self.set_cpu_quota(cpu_quota)?;
+        }
+        Ok(())
+    }
+    
+    /// Current state of `cgroup.freeze` (v2) or `freezer.state` (v1).
+    fn read_frozen(&self) -> bool {
+        match self.version {
+            CgroupVersion::V2 => read_trimmed(&self.path.join("cgroup.freeze")) == "1",
+            CgroupVersion::V1 => v1_mount(&self.fs, "freezer")
+                .map(|mount| read_trimmed(&mount.join(&self.rel_path).join("freezer.state")))
+                .is_ok_and(|state| !state.is_empty() && state != "THAWED"),
+        }
+    }
+    
+    /// Stop (`true`) or resume every process in the group.
+    pub fn set_frozen(&mut self, frozen: bool) -> Result<()> {
+        match self.backend {
+            Backend::Systemd(ref systemd) => systemd.set_frozen(&systemd::slice_name(&self.rel_path), frozen)?,
+            Backend::Cgroupfs => {
+                let path = match self.version {
+                    CgroupVersion::V2 => self.path.join("cgroup.freeze"),
+                    CgroupVersion::V1 => v1_mount(&self.fs, "freezer")?.join(&self.rel_path).join("freezer.state"),
+                };
+                let value = match (self.version, frozen) {
+                    (CgroupVersion::V2, true) => "1",
+                    (CgroupVersion::V2, false) => "0",
+                    (CgroupVersion::V1, true) => "FROZEN",
+                    (CgroupVersion::V1, false) => "THAWED",
+                };
+                fs::write(&path, value).with_context(|| format!("write {}", path.display()))?;
+            }
+        }
+        self.frozen = frozen;
+        Ok(())
+    }
+    
+    /// Whether the group is frozen.
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+    
+    pub fn add_pid(&self, pid: i32) -> Result<()> {
+        if let Backend::Systemd(ref systemd) = self.backend {
+            if self.contains(pid) {
+                return Ok(());
+            }
+            self.record_origin(pid, std::slice::from_ref(&self.path));
+            let scope = systemd::scope_name(&self.name, pid);
+            return systemd.start_scope(&scope, &systemd::slice_name(&self.rel_path), pid)
+                .inspect_err(|_| METRICS.record_cgroup_add_failure(&self.name));
+        }
 
-        match version {
-            CgroupVersion::V1 => {
-                let cpu_path = Path::new(CGROUP_FS).join("cpu").join(&name);
-                if !cpu_path.exists() {
-                    if let Err(e) = fs::create_dir_all(&cpu_path)
-                        .with_context(|| format!("mkdir {}", cpu_path.display())) {
-                        warn!("Could not create v1 cgroup directory: {e}");
-                    }
-                }
-                fs::write(cpu_path.join("cpu.cfs_period_us"), PERIOD_US.to_string())
-                    .with_context(|| format!("write {}", cpu_path.join("cpu.cfs_period_us").display()))?;
-                fs::write(cpu_path.join("cpu.cfs_quota_us"), quota_us.to_string())
-                    .with_context(|| format!("write {}", cpu_path.join("cpu.cfs_quota_us").display()))?;
-                fs::write(cpu_path.join("cpu.shares"), cpu_shares.to_string())
-                    .with_context(|| format!("write {}", cpu_path.join("cpu.shares").display()))?;
-                Ok(Self { name, path: cpu_path, version, cpu_quota, quota_us, cpu_shares })
+        let dirs: Vec<PathBuf> = std::iter::once(self.path.clone())
+            .chain(self.v1_hierarchies.iter().cloned())
+            .collect();
+        self.record_origin(pid, &dirs);
+        // cgroup.procs moves every thread of the process, v1 `tasks` only one
+        for procs_file in dirs.iter().map(|dir| dir.join("cgroup.procs")) {
+            if let Err(e) = fs::write(&procs_file, pid.to_string()) {
+                METRICS.record_cgroup_add_failure(&self.name);
+                return Err(e).with_context(|| format!("write {}", procs_file.display()));
             }
-            CgroupVersion::V2 => {
-                let base = v2_delegated_base()?;
-                enable_cpu_controller_v2_at(&base)?;
-                let v2_path = base.join(&name);
-                if !v2_path.exists() {
-                    if let Err(e) = fs::create_dir_all(&v2_path)
-                        .with_context(|| format!("mkdir {}", v2_path.display())) {
-                        warn!("Could not create v2 cgroup directory: {e}");
-                    }
-                }
-                let max_value = if cpu_quota >= 100 { String::from("max") } else { quota_us.to_string() };
-                let cpu_max = format!("{} {}", max_value, PERIOD_US);
-                if let Err(e) = fs::write(v2_path.join("cpu.max"), cpu_max)
-                    .with_context(|| format!("write {}", v2_path.join("cpu.max").display())) {
-                    warn!("Could not set cpu.max: {e}");
-                }
-                let mut weight: u64 = (cpu_quota as u64) * 100;
-                if weight == 0 { weight = 1; }
-                if weight > 10000 { weight = 10000; }
-                if let Err(e) = fs::write(v2_path.join("cpu.weight"), weight.to_string())
-                    .with_context(|| format!("write {}", v2_path.join("cpu.weight").display())) {
-                    warn!("Could not set cpu.weight: {e}");
-                }
-                Ok(Self { name, path: v2_path, version, cpu_quota, quota_us, cpu_shares })
+        }
+        Ok(())
+    }
+    
+    /// Remember where `pid` lives in the hierarchies of `dirs` before it is
+    /// moved there; the first origin seen is kept while the process runs.
+    fn record_origin(&self, pid: i32, dirs: &[PathBuf]) {
+        let start_time = start_time(&self.fs, pid);
+        let mut origins = ORIGINS.lock().unwrap();
+        if origins.len() >= ORIGINS_PRUNE {
+            origins.retain(|pid, origin| origin.start_time.is_some() && start_time_matches(&self.fs, *pid, origin));
+        }
+        let origin = origins.entry(pid).or_default();
+        if origin.start_time != start_time {
+            *origin = Origin { start_time, dirs: Vec::new() };
+        }
+        for dir in dirs {
+            let Some(mount) = mount_of(&self.fs, dir) else { continue };
+            if origin.dirs.iter().any(|recorded| recorded.starts_with(mount)) {
+                continue;
+            }
+            if let Some(current) = current_dir(&self.fs, pid, mount).filter(|current| !current.starts_with(dir)) {
+                origin.dirs.push(current);
             }
         }
     }
+
+    /// Number of processes (v2) or tasks (v1) currently in this cgroup.
+    pub fn pid_count(&self) -> usize {
+        if let Backend::Systemd(_) = self.backend {
+            // Processes live in one scope per placement below the slice
+            return fs::read_dir(&self.path).map(|entries| {
+                entries.filter_map(Result::ok)
+                    .filter(|entry| entry.file_name().to_string_lossy().ends_with(".scope"))
+                    .map(|entry| read_trimmed(&entry.path().join("cgroup.procs")).lines().count())
+                    .sum()
+            }).unwrap_or(0);
+        }
+        fs::read_to_string(self.procs_file())
+            .map(|content| content.lines().count())
+            .unwrap_or(0)
+    }
     
-    pub fn add_pid(&self, pid: i32) -> Result<()> {
+    /// Whether `pid` already lives somewhere below this cgroup (v2 only).
+    fn contains(&self, pid: i32) -> bool {
+        let rel = match self.path.strip_prefix(self.fs.hierarchies().unified_mount()) {
+            Ok(rel) => Path::new("/").join(rel),
+            Err(_) => return false,
+        };
+        let content = read_trimmed(&self.fs.proc_dir(pid).join("cgroup"));
+        content.lines()
+            .filter_map(|line| line.strip_prefix("0::"))
+            .any(|path| Path::new(path).starts_with(&rel))
+    }
+    
+    /// Whether the group is a plain directory managed by the daemon itself.
+    pub fn is_cgroupfs(&self) -> bool {
+        matches!(self.backend, Backend::Cgroupfs)
+    }
+    
+    fn procs_file(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+    
+    /// Total CPU time used by the group, from `cpuacct.usage` (v1) or `cpu.stat` (v2).
+    pub fn cpu_usage(&self) -> Option<std::time::Duration> {
         match self.version {
             CgroupVersion::V1 => {
-                let tasks_file = self.path.join("tasks");
-                fs::write(&tasks_file, pid.to_string())
-                    .with_context(|| format!("write {}", tasks_file.display()))?;
+                let dir = v1_mount(&self.fs, "cpuacct").ok()?.join(&self.rel_path);
+                let nanos: u64 = read_trimmed(&dir.join("cpuacct.usage")).parse().ok()?;
+                Some(std::time::Duration::from_nanos(nanos))
             }
             CgroupVersion::V2 => {
-                let procs_file = self.path.join("cgroup.procs");
-                fs::write(&procs_file, pid.to_string())
-                    .with_context(|| format!("write {}", procs_file.display()))?;
+                let stat = read_trimmed(&self.path.join("cpu.stat"));
+                let micros: u64 = stat.lines()
+                    .find_map(|line| line.strip_prefix("usage_usec "))?
+                    .trim().parse().ok()?;
+                Some(std::time::Duration::from_micros(micros))
             }
         }
-        Ok(())
     }
     
-    pub fn cpu_quota(&self) -> u32 {
+    pub fn cpu_quota(&self) -> CpuQuota {
         self.cpu_quota
     }
     
+    pub fn io_weight(&self) -> Option<u32> {
+        self.io_weight
+    }
+    
+    pub fn def(&self) -> &CgroupDef {
+        &self.def
+    }
+    
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    
+    pub fn cpus(&self) -> usize {
+        self.cpus
+    }
+    
+    pub fn quota_us(&self) -> Option<u64> {
+        self.quota_us
+    }
+    
+    pub fn cpu_shares(&self) -> u64 {
+        self.cpu_shares
+    }
+    
     pub fn name(&self) -> &str {
         &self.name
     }
+    
+    pub fn rel_path(&self) -> &Path {
+        &self.rel_path
+    }
+    
+    pub fn reconciled(&self) -> &Reconciled {
+        &self.reconciled
+    }
 }
 
-pub fn load_cgroups(config_dir: &Path) -> Result<HashMap<String, CgroupController>> {
-    let mut cgroups = HashMap::new();
+/// Load cgroup definitions from all `layers`; later layers override by name.
+pub fn load_cgroup_defs(layers: &[PathBuf]) -> Result<BTreeMap<String, CgroupDef>> {
+    let mut defs = BTreeMap::new();
     
-    for entry in walkdir(config_dir)? {
-        if let Some(file_name) = entry.file_name() {
-            if file_name.to_string_lossy().ends_with(".cgroups") {
-                info!("Loading cgroups from: {:?}", entry);
-                let content = fs::read_to_string(&entry)?;
+    for layer in layers {
+        for entry in walkdir(layer)? {
+            if !entry.to_string_lossy().ends_with(".cgroups") {
+                continue;
+            }
+            info!("Loading cgroups from: {:?}", entry);
+            let content = fs::read_to_string(&entry)?;
+            // Groups whose CPUQuota may have been meant as upstream's share of the machine
+            let mut ambiguous = Vec::new();
             
-                for line in content.lines() {
-                    let line = line.trim();
-                    if line.is_empty() || line.starts_with('#') {
-                        continue;
-                    }
-                    
-                    if let Ok(cgroup_def) = serde_json::from_str::<CgroupDef>(line) {
-                        match CgroupController::new(
-                            cgroup_def.cgroup.clone(),
-                            cgroup_def.cpu_quota
-                        ) {
-                            Ok(controller) => {
-                                cgroups.insert(cgroup_def.cgroup, controller);
-                            }
-                            Err(e) => {
-                                warn!("Skipping cgroup '{}' due to error: {e}", cgroup_def.cgroup);
-                            }
+            for (number, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                
+                let cgroup_def = match compat::parse::<CgroupDef>(line, Dialect::Native) {
+                    Ok(parsed) => {
+                        for note in parsed.notes {
+                            warn!("{}:{}: {}", entry.display(), number + 1, note);
                         }
+                        parsed.value
+                    }
+                    Err(e) => {
+                        warn!("Skipping cgroup at {}:{}: {:#}", entry.display(), number + 1, e);
+                        continue;
                     }
+                };
+                if let Err(e) = cgroup_def.validate() {
+                    warn!("Skipping cgroup '{}': {:#}", cgroup_def.cgroup, e);
+                    continue;
+                }
+                if cgroup_def.cpu_quota.is_some_and(|percent| percent <= 100) {
+                    ambiguous.push(cgroup_def.cgroup.clone());
                 }
+                defs.insert(cgroup_def.cgroup.clone(), cgroup_def);
+            }
+            if !ambiguous.is_empty() {
+                warn!(
+                    "{}: CPUQuota of {} is read as percent of ONE CPU; files from ananicy-cpp mean percent of all CPUs \
+                     and must be converted with `rust-ananicy import`, or use CPUQuotaTotal",
+                    entry.display(), ambiguous.join(", "),
+                );
+            }
         }
     }
+    
+    Ok(defs)
+}
+
+pub fn load_cgroups(
+    layers: &[PathBuf],
+    source: PowerSource,
+    backend: &Backend,
+    cgroup_fs: &Arc<CgroupFs>,
+) -> Result<HashMap<String, CgroupController>> {
+    let defs = load_cgroup_defs(layers)?;
+    let mut cgroups: HashMap<String, CgroupController> = HashMap::new();
+    
+    for name in creation_order(&defs) {
+        let cgroup_def = &defs[&name];
+        let rel_path = match cgroup_def.parent {
+            Some(ref parent) => match cgroups.get(parent) {
+                Some(parent) => parent.rel_path().join(&name),
+                None => {
+                    warn!("Skipping cgroup '{}' because its parent '{}' could not be created", name, parent);
+                    continue;
+                }
+            },
+            None => PathBuf::from(&name),
+        };
+        match CgroupController::new(cgroup_def, &rel_path, source, backend, cgroup_fs) {
+            Ok(controller) => {
+                cgroups.insert(name, controller);
+            }
+            Err(e) => {
+                warn!("Skipping cgroup '{}' due to error: {e}", name);
+            }
+        }
     }
     
     Ok(cgroups)
 }
 
+/// Read the defined cgroups and the stale ones of an earlier run as they are,
+/// for `dump cgroups`; unlike [`load_cgroups`] and [`remove_stale`] nothing is
+/// created, written or removed.
+pub fn inspect_cgroups(
+    layers: &[PathBuf],
+    state_dir: &Path,
+    source: PowerSource,
+    backend: &Backend,
+    cgroup_fs: &Arc<CgroupFs>,
+) -> Result<Inspection> {
+    let defs = load_cgroup_defs(layers)?;
+    let mut inspection = Inspection::default();
+    let mut rel_paths: HashMap<String, PathBuf> = HashMap::new();
+
+    for name in creation_order(&defs) {
+        let cgroup_def = &defs[&name];
+        let rel_path = match cgroup_def.parent {
+            Some(ref parent) => rel_paths[parent].join(&name),
+            None => PathBuf::from(&name),
+        };
+        rel_paths.insert(name.clone(), rel_path.clone());
+        match CgroupController::inspect(cgroup_def, &rel_path, source, backend, cgroup_fs) {
+            Ok(Some(controller)) => inspection.cgroups.push(controller),
+            Ok(None) => inspection.missing.push(rel_path),
+            Err(e) => warn!("Cannot inspect cgroup '{}': {:#}", name, e),
+        }
+    }
+
+    let current: Vec<PathBuf> = match backend {
+        Backend::Cgroupfs => rel_paths.into_values().collect(),
+        Backend::Systemd(_) => Vec::new(),
+    };
+    for rel in load_managed(state_dir).into_iter().filter(|rel| !current.contains(rel)) {
+        let procs = cgroup_dirs(cgroup_fs, &rel).iter()
+            .map(|dir| read_trimmed(&dir.join("cgroup.procs")).lines().count())
+            .max()
+            .unwrap_or(0);
+        inspection.stale.push((rel, procs));
+    }
+    inspection.cgroups.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    inspection.missing.sort();
+    inspection.stale.sort();
+    Ok(inspection)
+}
+
+/// Remove empty cgroups created by an earlier run that are no longer defined,
+/// and record the current set in `state_dir`.
+pub fn remove_stale(
+    state_dir: &Path,
+    cgroups: &HashMap<String, CgroupController>,
+    cgroup_fs: &CgroupFs,
+) -> Result<Cleanup> {
+    let current: Vec<PathBuf> = cgroups.values()
+        .filter(|cg| cg.is_cgroupfs())
+        .map(|cg| cg.rel_path.clone())
+        .collect();
+    let mut stale: Vec<PathBuf> = load_managed(state_dir).into_iter()
+        .filter(|rel| !current.contains(rel))
+        .collect();
+    sort_deepest_first(&mut stale);
+
+    let mut cleanup = Cleanup::default();
+    for rel in stale {
+        let dirs = cgroup_dirs(cgroup_fs, &rel);
+        let busy = dirs.iter().any(|dir| !read_trimmed(&dir.join("cgroup.procs")).is_empty());
+        if busy {
+            // Nothing defines the group any more, so nothing may keep it frozen
+            dirs.iter().for_each(|dir| thaw_dir(dir));
+        }
+        if busy || remove_dirs(&dirs).is_err() {
+            warn!("Keeping stale cgroup {} because it is not empty", rel.display());
+            cleanup.kept.push(rel);
+        } else {
+            info!("Removed stale cgroup {}", rel.display());
+            cleanup.removed.push(rel);
+        }
+    }
+
+    let mut managed = current;
+    managed.extend(cleanup.kept.iter().cloned());
+    save_managed(state_dir, &managed)?;
+    Ok(cleanup)
+}
+
+/// Move all processes out of the daemon's cgroups, back to the cgroup they
+/// came from, and remove the groups; transient slices are stopped.
+pub fn remove_all(
+    state_dir: &Path,
+    cgroups: &HashMap<String, CgroupController>,
+    cgroup_fs: &CgroupFs,
+) -> Result<Cleanup> {
+    let mut rels: Vec<PathBuf> = load_managed(state_dir);
+    rels.extend(cgroups.values().filter(|cg| cg.is_cgroupfs()).map(|cg| cg.rel_path.clone()));
+    rels.sort();
+    rels.dedup();
+    sort_deepest_first(&mut rels);
+    let mut slices: Vec<&CgroupController> = cgroups.values().filter(|cg| !cg.is_cgroupfs()).collect();
+    slices.sort_by_key(|cg| std::cmp::Reverse(cg.rel_path.components().count()));
+
+    // Processes are never put back into a group that is about to go away
+    let ours: Vec<PathBuf> = rels.iter()
+        .flat_map(|rel| cgroup_dirs(cgroup_fs, rel))
+        .chain(slices.iter().map(|cg| cg.path.clone()))
+        .collect();
+
+    let mut cleanup = Cleanup::default();
+    for rel in rels {
+        let dirs = cgroup_dirs(cgroup_fs, &rel);
+        for dir in &dirs {
+            migrate_procs(cgroup_fs, dir, &ours);
+        }
+        match remove_dirs(&dirs) {
+            Ok(()) => cleanup.removed.push(rel),
+            Err(e) => {
+                warn!("Could not remove cgroup {}: {:#}", rel.display(), e);
+                cleanup.kept.push(rel);
+            }
+        }
+    }
+
+    let mut systemd = None;
+    for cg in slices {
+        let Backend::Systemd(ref handle) = cg.backend else { continue };
+        // Stopping a slice stops its scopes, which would kill their processes
+        for scope in fs::read_dir(&cg.path).into_iter().flatten().filter_map(|entry| entry.ok()) {
+            if scope.file_name().to_string_lossy().ends_with(".scope") {
+                migrate_procs(cgroup_fs, &scope.path(), &ours);
+            }
+        }
+        match handle.stop_unit(&systemd::slice_name(&cg.rel_path)) {
+            Ok(()) => cleanup.removed.push(cg.rel_path.clone()),
+            Err(e) => {
+                warn!("Could not stop slice of cgroup {}: {:#}", cg.name, e);
+                cleanup.kept.push(cg.rel_path.clone());
+            }
+        }
+        systemd = Some(handle);
+    }
+    if let Some(systemd) = systemd {
+        if let Err(e) = systemd.stop_unit(systemd::ROOT_SLICE) {
+            warn!("Could not stop {}: {:#}", systemd::ROOT_SLICE, e);
+        }
+    }
+
+    save_managed(state_dir, &cleanup.kept)?;
+    Ok(cleanup)
+}
+
+/// Move the processes of `dir` back to the cgroup they were in before the
+/// daemon moved them, or to the root of the hierarchy if that is unknown,
+/// gone, or one of the daemon's own groups in `ours`.
+fn migrate_procs(cgroup_fs: &CgroupFs, dir: &Path, ours: &[PathBuf]) {
+    let Some(mount) = mount_of(cgroup_fs, dir) else { return };
+    thaw_dir(dir);
+    let origins = ORIGINS.lock().unwrap();
+    for pid in read_trimmed(&dir.join("cgroup.procs")).lines() {
+        let origin = pid.parse().ok()
+            .and_then(|pid: i32| origins.get(&pid).filter(|origin| start_time_matches(cgroup_fs, pid, origin)))
+            .and_then(|origin| origin.dirs.iter().find(|dir| dir.starts_with(mount)))
+            .filter(|origin| !ours.iter().any(|our| origin.starts_with(our)));
+        let moved = origin.into_iter()
+            .chain(std::iter::once(&mount.to_path_buf()))
+            .any(|target| fs::write(target.join("cgroup.procs"), pid).is_ok());
+        if !moved {
+            warn!("Could not move process {} out of {}", pid, dir.display());
+        }
+    }
+}
+
+/// Start time of `pid` in clock ticks since boot, field 22 of `/proc/<pid>/stat`.
+fn start_time(cgroup_fs: &CgroupFs, pid: i32) -> Option<u64> {
+    let stat = fs::read_to_string(cgroup_fs.proc_dir(pid).join("stat")).ok()?;
+    // The command name may contain spaces and parentheses
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Whether `origin` was recorded for the process that has `pid` now.
+fn start_time_matches(cgroup_fs: &CgroupFs, pid: i32, origin: &Origin) -> bool {
+    origin.start_time == start_time(cgroup_fs, pid)
+}
+
+/// Mount point of the hierarchy `dir` belongs to.
+fn mount_of<'a>(cgroup_fs: &'a CgroupFs, dir: &Path) -> Option<&'a Path> {
+    let hierarchies = cgroup_fs.hierarchies();
+    hierarchies.v1_mounts().into_iter()
+        .chain(std::iter::once(hierarchies.unified_mount()))
+        .filter(|mount| dir.starts_with(mount))
+        .max_by_key(|mount| mount.components().count())
+}
+
+/// Directory of `pid` in the hierarchy mounted at `mount`, from `/proc/<pid>/cgroup`.
+fn current_dir(cgroup_fs: &CgroupFs, pid: i32, mount: &Path) -> Option<PathBuf> {
+    let controllers: Vec<&str> = cgroup_fs.hierarchies().v1.iter()
+        .filter(|(_, path)| path.as_path() == mount)
+        .map(|(controller, _)| controller.as_str())
+        .collect();
+    let content = read_trimmed(&cgroup_fs.proc_dir(pid).join("cgroup"));
+    content.lines().find_map(|line| {
+        let mut fields = line.splitn(3, ':');
+        let (_, names, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let matches = match controllers.is_empty() {
+            // The unified hierarchy has a single line without controllers
+            true => names.is_empty(),
+            false => names.split(',').any(|name| controllers.contains(&name)),
+        };
+        matches.then(|| mount.join(path.trim_start_matches('/')))
+    })
+}
+
+/// Resume the processes of `dir` if it is a frozen v2 or v1 freezer cgroup.
+fn thaw_dir(dir: &Path) {
+    for (file, thawed) in [("cgroup.freeze", "0"), ("freezer.state", "THAWED")] {
+        let state = read_trimmed(&dir.join(file));
+        // v1 reports FREEZING while the freeze is still in progress
+        if !state.is_empty() && state != thawed {
+            info!("Thawing {}", dir.display());
+            write_setting(dir, file, thawed);
+        }
+    }
+}
+
+/// Directories of `rel` in every hierarchy it exists in.
+fn cgroup_dirs(cgroup_fs: &CgroupFs, rel: &Path) -> Vec<PathBuf> {
+    let dirs = match cgroup_version(cgroup_fs) {
+        CgroupVersion::V2 => v2_delegated_base(cgroup_fs).map(|base| vec![base.join(rel)]).unwrap_or_default(),
+        CgroupVersion::V1 => cgroup_fs.hierarchies().v1_mounts().into_iter()
+            .map(|mount| mount.join(rel))
+            .collect(),
+    };
+    dirs.into_iter().filter(|dir| dir.is_dir()).collect()
+}
+
+fn remove_dirs(dirs: &[PathBuf]) -> Result<()> {
+    for dir in dirs {
+        fs::remove_dir(dir).with_context(|| format!("rmdir {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Current values of the limit files of `rel`, keyed by file name.
+fn snapshot(cgroup_fs: &CgroupFs, rel: &Path) -> BTreeMap<String, String> {
+    let mut values = BTreeMap::new();
+    for dir in cgroup_dirs(cgroup_fs, rel) {
+        for file in LIMIT_FILES {
+            if let Ok(value) = fs::read_to_string(dir.join(file)) {
+                values.insert(file.to_string(), value.trim().to_string());
+            }
+        }
+    }
+    values
+}
+
+fn sort_deepest_first(rels: &mut [PathBuf]) {
+    rels.sort_by_key(|rel| std::cmp::Reverse(rel.components().count()));
+}
+
+fn load_managed(state_dir: &Path) -> Vec<PathBuf> {
+    read_trimmed(&state_dir.join(MANAGED_FILE)).lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn save_managed(state_dir: &Path, rels: &[PathBuf]) -> Result<()> {
+    fs::create_dir_all(state_dir)
+        .with_context(|| format!("mkdir {}", state_dir.display()))?;
+    let mut content: Vec<String> = rels.iter().map(|rel| rel.display().to_string()).collect();
+    content.sort();
+    content.dedup();
+    let path = state_dir.join(MANAGED_FILE);
+    fs::write(&path, content.join("\n") + "\n")
+        .with_context(|| format!("write {}", path.display()))
+}
+
+/// Order definitions so that every parent comes before its children.
+/// Definitions in a parent cycle or below an unknown parent are left out with
+/// a warning, together with everything nested in them.
+pub fn creation_order(defs: &BTreeMap<String, CgroupDef>) -> Vec<String> {
+    fn visit<'a>(
+        name: &'a str,
+        defs: &'a BTreeMap<String, CgroupDef>,
+        visiting: &mut Vec<&'a str>,
+        order: &mut Vec<String>,
+        dropped: &mut BTreeSet<&'a str>,
+    ) -> bool {
+        if order.iter().any(|n| n == name) {
+            return true;
+        }
+        if dropped.contains(name) {
+            return false;
+        }
+        let reason = if let Some(start) = visiting.iter().position(|n| *n == name) {
+            let mut cycle = visiting[start..].to_vec();
+            cycle.push(name);
+            Some(format!("parent cycle {}", cycle.join(" -> ")))
+        } else {
+            visiting.push(name);
+            let reason = match defs[name].parent {
+                Some(ref parent) if !defs.contains_key(parent) => Some(format!("unknown parent '{}'", parent)),
+                Some(ref parent) if !visit(parent, defs, visiting, order, dropped) => {
+                    Some(format!("parent '{}' is skipped", parent))
+                }
+                _ => None,
+            };
+            visiting.pop();
+            reason
+        };
+        match reason {
+            None => {
+                order.push(name.to_string());
+                true
+            }
+            Some(reason) => {
+                if dropped.insert(name) {
+                    warn!("Skipping cgroup '{}': {}", name, reason);
+                }
+                false
+            }
+        }
+    }
+
+    let mut order = Vec::new();
+    let mut dropped = BTreeSet::new();
+    for name in defs.keys() {
+        visit(name, defs, &mut Vec::new(), &mut order, &mut dropped);
+    }
+    order
+}
+
 fn walkdir(dir: &Path) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
     
     if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
+        let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        
+        for entry in entries {
             let path = entry.path();
             
             if path.is_dir() {
+                // Profiles are loaded explicitly as their own layer, user
+                // directories cannot define cgroups
+                if entry.file_name() == PROFILES_DIR || entry.file_name() == USERS_DIR {
+                    continue;
+                }
                 files.extend(walkdir(&path)?);
             } else {
                 files.push(path);
@@ -163,43 +1864,579 @@ fn walkdir(dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
-fn detect_cgroup_version() -> CgroupVersion {
-    let v2_marker = Path::new(CGROUP_FS).join("cgroup.controllers");
-    if v2_marker.exists() { CgroupVersion::V2 } else { CgroupVersion::V1 }
+/// Resolve a device node, or any path on a block device, to the `MAJ:MIN` of
+/// the whole disk as expected by the io controller.
+fn resolve_device(cgroup_fs: &CgroupFs, cgroup: &str, device: &str) -> Option<String> {
+    match device_number(Path::new(device), cgroup_fs.sys_root()) {
+        Ok(devno) => Some(devno),
+        Err(e) => {
+            warn!("Cgroup {}: ignoring IO limit for {}: {:#}", cgroup, device, e);
+            None
+        }
+    }
+}
+
+fn device_number(path: &Path, sys_root: &Path) -> Result<String> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let metadata = fs::metadata(path)
+        .with_context(|| format!("stat {}", path.display()))?;
+    let dev = if metadata.file_type().is_block_device() { metadata.rdev() } else { metadata.dev() };
+    let devno = format!("{}:{}", libc::major(dev), libc::minor(dev));
+
+    // Partitions are not accepted by io.max, use the disk they belong to
+    let sys_dev = sys_root.join("dev/block").join(&devno);
+    if sys_dev.join("partition").exists() {
+        let parent = fs::canonicalize(&sys_dev)?.join("..").join("dev");
+        if let Ok(parent_devno) = fs::read_to_string(&parent) {
+            return Ok(parent_devno.trim().to_string());
+        }
+    }
+    Ok(devno)
+}
+
+/// Quota in microseconds per period and v1 shares for `cpu_quota` on `ncpu` CPUs.
+fn quota_values(cpu_quota: CpuQuota, ncpu: usize) -> (Option<u64>, u64) {
+    let quota_us = cpu_quota.usec_per_sec(ncpu).map(|usec| usec * PERIOD_US / 1_000_000);
+    // The kernel rejects shares below 2
+    (quota_us, (1024 * cpu_quota.total_percent(ncpu) as u64 / 100).max(2))
+}
+
+fn read_trimmed(path: &Path) -> String {
+    fs::read_to_string(path).map(|v| v.trim().to_string()).unwrap_or_default()
+}
+
+fn write_setting(dir: &Path, file: &str, value: &str) {
+    let path = dir.join(file);
+    if let Err(e) = fs::write(&path, value) {
+        warn!("Could not write {} to {}: {}", value, path.display(), e);
+    }
+}
+
+/// Version used for resource control; hybrid hosts keep their controllers on v1.
+fn cgroup_version(cgroup_fs: &CgroupFs) -> CgroupVersion {
+    match cgroup_fs.hierarchies().mode() {
+        Mode::Unified => CgroupVersion::V2,
+        Mode::Hybrid | Mode::Legacy => CgroupVersion::V1,
+    }
+}
+
+fn v1_mount<'a>(cgroup_fs: &'a CgroupFs, controller: &str) -> Result<&'a Path> {
+    cgroup_fs.hierarchies().v1_mount(controller)
+        .ok_or_else(|| anyhow!("the {} controller is not mounted", controller))
 }
 
-fn enable_cpu_controller_v2_at(base: &Path) -> Result<()> {
+fn enable_controller_v2_at(base: &Path, controller: &str) -> Result<()> {
     let controllers_path = base.join("cgroup.controllers");
     if !controllers_path.exists() {
         return Ok(());
     }
 
     let available = fs::read_to_string(&controllers_path).unwrap_or_default();
-    if !available.split_whitespace().any(|c| c == "cpu") {
+    if !available.split_whitespace().any(|c| c == controller) {
         return Ok(());
     }
 
     let subtree = base.join("cgroup.subtree_control");
     let current = fs::read_to_string(&subtree).unwrap_or_default();
-    let has_cpu = current.split_whitespace().any(|c| c == "+cpu" || c == "cpu");
-    if !has_cpu {
-        let _ = fs::write(&subtree, "+cpu");
+    let enabled = current.split_whitespace()
+        .any(|c| c.trim_start_matches('+') == controller);
+    if !enabled {
+        let _ = fs::write(&subtree, format!("+{}", controller));
     }
     Ok(())
 }
 
-fn v2_delegated_base() -> Result<PathBuf> {
+fn v2_delegated_base(cgroup_fs: &CgroupFs) -> Result<PathBuf> {
     // Parse /proc/self/cgroup to find our cgroup v2 path and use it as base
-    let content = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let content = fs::read_to_string(cgroup_fs.proc_root().join("self/cgroup")).unwrap_or_default();
     for line in content.lines() {
         // v2 lines look like: 0::/system.slice/rust-ananicy.service
         if let Some(rest) = line.split("::").nth(1) {
             let rel = rest.trim();
             if rel.starts_with('/') {
-                return Ok(Path::new(CGROUP_FS).join(rel.trim_start_matches('/')));
+                return Ok(cgroup_fs.hierarchies().unified_mount().join(rel.trim_start_matches('/')));
             }
         }
     }
     // Fallback to root if parsing failed
-    Ok(PathBuf::from(CGROUP_FS))
-}
\ No newline at end of file
+    Ok(cgroup_fs.hierarchies().unified_mount().to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Unified hierarchy below `<tmp>/cgroup` with the daemon in `ananicy.service`.
+    fn fake_v2() -> (TempDir, Arc<CgroupFs>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("cgroup");
+        let service = root.join("ananicy.service");
+        fs::create_dir_all(&service).unwrap();
+        for dir in [&root, &service] {
+            fs::write(dir.join("cgroup.controllers"), "cpuset cpu io memory pids").unwrap();
+            fs::write(dir.join("cgroup.subtree_control"), "").unwrap();
+        }
+        fs::write(root.join("cpuset.cpus.effective"), "0-3").unwrap();
+        let proc = tmp.path().join("proc");
+        fs::create_dir_all(proc.join("self")).unwrap();
+        fs::write(proc.join("self/cgroup"), "0::/ananicy.service\n").unwrap();
+        let cgroup_fs = Arc::new(CgroupFs::detect(&proc, Some(&root)));
+        (tmp, cgroup_fs)
+    }
+
+    /// Hybrid layout: one directory per v1 controller plus an empty `unified`.
+    fn fake_hybrid() -> (TempDir, Arc<CgroupFs>) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("cgroup");
+        for hierarchy in ["cpu", "cpuacct", "memory", "blkio", "cpuset", "pids", "freezer", "unified"] {
+            fs::create_dir_all(root.join(hierarchy)).unwrap();
+        }
+        fs::write(root.join("unified/cgroup.controllers"), "").unwrap();
+        fs::write(root.join("cpuset/cpuset.cpus"), "0-3").unwrap();
+        fs::write(root.join("cpuset/cpuset.mems"), "0").unwrap();
+        let proc = tmp.path().join("proc");
+        fs::create_dir_all(&proc).unwrap();
+        let cgroup_fs = Arc::new(CgroupFs::detect(&proc, Some(&root)));
+        (tmp, cgroup_fs)
+    }
+
+    fn def(json: &str) -> CgroupDef {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn create(cgroup_fs: &Arc<CgroupFs>, json: &str) -> CgroupController {
+        let def = def(json);
+        let rel = PathBuf::from(&def.cgroup);
+        CgroupController::new(&def, &rel, PowerSource::Ac, &Backend::Cgroupfs, cgroup_fs).unwrap()
+    }
+
+    #[test]
+    fn v2_group_is_created_below_own_cgroup() {
+        let (tmp, cgroup_fs) = fake_v2();
+        assert_eq!(cgroup_fs.hierarchies().mode(), Mode::Unified);
+
+        let cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 50, "MemoryMax": "1G", "TasksMax": 64}"#);
+        let dir = tmp.path().join("cgroup/ananicy.service/games");
+        assert_eq!(cg.path(), dir);
+        assert_eq!(cg.reconciled(), &Reconciled::Created);
+
+        // Half of one of the four CPUs
+        assert_eq!(cg.cpus(), 4);
+        assert_eq!(read_trimmed(&dir.join("cpu.max")), "50000 100000");
+        assert_eq!(read_trimmed(&dir.join("cpu.weight")), "1200");
+        assert_eq!(read_trimmed(&dir.join("memory.max")), (1u64 << 30).to_string());
+        assert_eq!(read_trimmed(&dir.join("pids.max")), "64");
+    }
+
+    #[test]
+    fn v2_pid_placement_and_count() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 90}"#);
+
+        cg.add_pid(4242).unwrap();
+        let procs = tmp.path().join("cgroup/ananicy.service/games/cgroup.procs");
+        assert_eq!(read_trimmed(&procs), "4242");
+        assert_eq!(cg.pid_count(), 1);
+    }
+
+    #[test]
+    fn failed_placement_keeps_the_os_error() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 50}"#);
+        fs::remove_dir_all(tmp.path().join("cgroup/ananicy.service/games")).unwrap();
+
+        let error = cg.add_process(4242).unwrap_err();
+        assert!(matches!(error, ActionError::Cgroup { ref cgroup, errno: Some(libc::ENOENT), .. } if cgroup == "games"), "{:?}", error);
+    }
+
+    #[test]
+    fn cpu_quota_units() {
+        assert_eq!(CpuQuota::PerCpu(50).usec_per_sec(8), Some(500_000));
+        assert_eq!(CpuQuota::PerCpu(200).usec_per_sec(8), Some(2_000_000));
+        assert_eq!(CpuQuota::PerCpu(800).usec_per_sec(8), None);
+        assert_eq!(CpuQuota::Total(50).usec_per_sec(8), Some(4_000_000));
+        assert_eq!(CpuQuota::Total(100).usec_per_sec(8), None);
+        assert_eq!(CpuQuota::PerCpu(200).total_percent(8), 25);
+        assert_eq!(quota_values(CpuQuota::Total(25), 2), (Some(50_000), 256));
+        assert_eq!(quota_values(CpuQuota::PerCpu(1), 64), (Some(1_000), 2));
+
+        let battery = def(r#"{"cgroup": "a", "CPUQuota": 200, "CPUQuotaBattery": 50}"#);
+        assert_eq!(battery.cpu_quota_for(PowerSource::Battery), CpuQuota::PerCpu(50));
+        assert_eq!(def(r#"{"cgroup": "a"}"#).cpu_quota_for(PowerSource::Ac), CpuQuota::Total(100));
+        assert!(def(r#"{"cgroup": "a", "CPUQuota": 50, "CPUQuotaTotal": 50}"#).validate().is_err());
+        assert!(def(r#"{"cgroup": "a", "CPUQuotaTotal": 150}"#).validate().is_err());
+        for json in [
+            r#"{"cgroup": "a", "CPUQuota": 0}"#,
+            r#"{"cgroup": "a", "CPUQuotaTotal": 0}"#,
+            r#"{"cgroup": "a", "CPUQuota": 50, "CPUQuotaBattery": 0}"#,
+            r#"{"cgroup": "a", "CPUQuotaTotal": 50, "CPUQuotaBattery": 150}"#,
+            r#"{"cgroup": "a", "CPUQuotaBattery": 150}"#,
+        ] {
+            assert!(def(json).validate().is_err(), "{}", json);
+        }
+        // Percent of one CPU may exceed 100 on battery as well
+        assert!(def(r#"{"cgroup": "a", "CPUQuota": 200, "CPUQuotaBattery": 150}"#).validate().is_ok());
+
+        let adaptive = |json: &str| def(&format!(r#"{{"cgroup": "a", "Adaptive": {}}}"#, json)).validate();
+        assert!(adaptive(r#"{"CPUQuotaMin": 10, "CPUQuotaMax": 60, "IOWeightMin": 10, "IOWeightMax": 100}"#).is_ok());
+        assert!(adaptive(r#"{"CPUQuotaMin": 60, "CPUQuotaMax": 10}"#).is_err());
+        assert!(adaptive(r#"{"IOWeightMin": 100, "IOWeightMax": 10}"#).is_err());
+        assert!(adaptive(r#"{"IOWeightMin": 0, "IOWeightMax": 10}"#).is_err());
+        assert!(adaptive(r#"{"PressureHigh": 10, "PressureLow": 10}"#).is_err());
+        assert!(adaptive(r#"{"PressureHigh": 140}"#).is_err());
+    }
+
+    #[test]
+    fn memory_percent_is_of_physical_memory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lines = [
+            "MemTotal", "MemFree", "Buffers", "Cached", "SwapCached", "Active", "Inactive",
+            "Active(anon)", "Inactive(anon)", "Active(file)", "Inactive(file)", "Unevictable",
+            "Mlocked", "SwapTotal", "SwapFree", "Dirty", "Writeback", "AnonPages", "Mapped",
+            "Shmem", "Slab", "SReclaimable", "SUnreclaim", "KernelStack", "PageTables",
+            "NFS_Unstable", "Bounce", "WritebackTmp", "CommitLimit", "Committed_AS",
+            "VmallocTotal", "VmallocUsed", "VmallocChunk",
+        ];
+        let meminfo: String = lines.iter()
+            .map(|key| format!("{}: {} kB\n", key, if *key == "MemTotal" { 8_000_000 } else { 0 }))
+            .collect();
+        fs::write(tmp.path().join("meminfo"), meminfo).unwrap();
+
+        let total = 8_000_000u64 * 1024;
+        assert_eq!(MemoryLimit::Percent(25).bytes(tmp.path()).unwrap(), Some(total / 100 * 25));
+        assert_eq!(MemoryLimit::Bytes(4096).bytes(tmp.path()).unwrap(), Some(4096));
+        assert_eq!(MemoryLimit::Max.bytes(tmp.path()).unwrap(), None);
+        assert_eq!(MemoryLimit::Max.v1_value(tmp.path()).unwrap(), "-1");
+        assert_eq!(MemoryLimit::Percent(50).v2_value(tmp.path()).unwrap(), (total / 2).to_string());
+        // Without meminfo a percentage has no size, and must not become zero
+        let missing = tmp.path().join("missing");
+        assert!(MemoryLimit::Percent(50).bytes(&missing).is_err());
+        assert_eq!(MemoryLimit::Bytes(4096).bytes(&missing).unwrap(), Some(4096));
+        let (tmp, cgroup_fs) = fake_v2();
+        create(&cgroup_fs, r#"{"cgroup": "apps", "CPUQuota": 50, "MemoryHigh": "50%", "MemoryMax": "2G"}"#);
+        let dir = tmp.path().join("cgroup/ananicy.service/apps");
+        assert!(!dir.join("memory.high").exists());
+        assert_eq!(read_trimmed(&dir.join("memory.max")), (2u64 << 30).to_string());
+        assert_eq!("1G".parse(), Ok(MemoryLimit::Bytes(1 << 30)));
+        assert_eq!(" 40 %".parse(), Ok(MemoryLimit::Percent(40)));
+        assert!("lots".parse::<MemoryLimit>().is_err());
+
+        assert!(def(r#"{"cgroup": "a", "MemoryHigh": "100%", "MemoryMax": "max"}"#).validate().is_ok());
+        for key in ["MemoryHigh", "MemoryMax", "MemoryLow", "MemorySwapMax"] {
+            let err = def(&format!(r#"{{"cgroup": "a", "{}": "150%"}}"#, key)).validate().unwrap_err();
+            assert!(err.to_string().starts_with(key), "{}", err);
+        }
+    }
+
+    #[test]
+    fn tasks_percent_is_of_pid_max() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir_all(tmp.path().join("sys/kernel")).unwrap();
+        fs::write(tmp.path().join("sys/kernel/pid_max"), "4194304\n").unwrap();
+
+        assert_eq!(TasksLimit::Percent(5).value(tmp.path()), "209715");
+        assert_eq!(TasksLimit::Count(64).value(tmp.path()), "64");
+        assert_eq!(TasksLimit::Max.value(tmp.path()), "max");
+        // Without pid_max the kernel default of 32768 applies
+        assert_eq!(TasksLimit::Percent(50).value(&tmp.path().join("missing")), "16384");
+
+        assert!(def(r#"{"cgroup": "a", "TasksMax": "100%"}"#).validate().is_ok());
+        assert!(def(r#"{"cgroup": "a", "TasksMax": "150%"}"#).validate().is_err());
+    }
+
+    #[test]
+    fn io_limits_are_written_for_the_whole_disk() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (tmp, cgroup_fs) = fake_v2();
+        // Any path on a partition resolves to the disk it belongs to
+        let data = tmp.path().join("data");
+        fs::write(&data, "").unwrap();
+        let dev = fs::metadata(&data).unwrap().dev();
+        let devno = format!("{}:{}", libc::major(dev), libc::minor(dev));
+        let sys = tmp.path().join("sys");
+        let partition = sys.join("devices/disk/part1");
+        fs::create_dir_all(&partition).unwrap();
+        fs::write(partition.join("partition"), "1\n").unwrap();
+        fs::write(sys.join("devices/disk/dev"), "259:0\n").unwrap();
+        fs::create_dir_all(sys.join("dev/block")).unwrap();
+        std::os::unix::fs::symlink("../../devices/disk/part1", sys.join("dev/block").join(&devno)).unwrap();
+
+        assert_eq!(device_number(&data, tmp.path()).unwrap(), devno);
+        assert_eq!(device_number(&data, &sys).unwrap(), "259:0");
+        assert!(device_number(&tmp.path().join("missing"), &sys).is_err());
+
+        let cgroup_fs = Arc::new(CgroupFs::clone(&cgroup_fs).with_sys_root(&sys));
+        create(&cgroup_fs, &format!(
+            r#"{{"cgroup": "backup", "CPUQuota": 50, "IOReadBandwidthMax": {{"{0}": "50M"}}, "IOWriteIOPSMax": {{"{0}": 500, "/missing": 10}}}}"#,
+            data.display(),
+        ));
+        let io_max = read_trimmed(&tmp.path().join("cgroup/ananicy.service/backup/io.max"));
+        assert_eq!(io_max, format!("259:0 rbps={} wiops=500", 50u64 << 20));
+    }
+
+    #[test]
+    fn quota_follows_effective_cpuset() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let dir = tmp.path().join("cgroup/ananicy.service/games");
+        let mut cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuotaTotal": 50, "AllowedCPUs": "0-1"}"#);
+        // A fake has no kernel to derive the effective set, so the parent's applies
+        assert_eq!(read_trimmed(&dir.join("cpu.max")), "200000 100000");
+
+        // Hotplug: the kernel shrinks the effective set of the group
+        fs::write(dir.join("cpuset.cpus.effective"), "0-1").unwrap();
+        cg.refresh_cpus().unwrap();
+        assert_eq!(cg.cpus(), 2);
+        assert_eq!(read_trimmed(&dir.join("cpu.max")), "100000 100000");
+    }
+
+    #[test]
+    fn existing_group_is_reconciled() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let dir = tmp.path().join("cgroup/ananicy.service/games");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cpu.max"), "max 100000").unwrap();
+
+        let cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 50}"#);
+        match cg.reconciled() {
+            Reconciled::Corrected(files) => assert!(files.contains(&"cpu.max".to_string())),
+            other => panic!("expected corrected limits, got {:?}", other),
+        }
+
+        let cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 50}"#);
+        assert_eq!(cg.reconciled(), &Reconciled::Unchanged);
+    }
+
+    #[test]
+    fn inspection_changes_nothing() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let layer = tmp.path().join("ananicy.d");
+        fs::create_dir_all(&layer).unwrap();
+        fs::write(layer.join("00-test.cgroups"), concat!(
+            r#"{"cgroup": "games", "CPUQuota": 50, "Freeze": true}"#, "\n",
+            r#"{"cgroup": "builds", "CPUQuota": 20}"#, "\n",
+        )).unwrap();
+        let state_dir = tmp.path().join("state");
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(state_dir.join(MANAGED_FILE), "old\ngames\n").unwrap();
+        let base = tmp.path().join("cgroup/ananicy.service");
+        fs::create_dir_all(base.join("old")).unwrap();
+        fs::write(base.join("old/cgroup.procs"), "42\n").unwrap();
+        fs::create_dir_all(base.join("games")).unwrap();
+        fs::write(base.join("games/cpu.max"), "max 100000").unwrap();
+
+        let inspection = inspect_cgroups(&[layer], &state_dir, PowerSource::Ac, &Backend::Cgroupfs, &cgroup_fs).unwrap();
+        assert_eq!(inspection.cgroups.len(), 1);
+        let games = &inspection.cgroups[0];
+        assert_eq!(games.reconciled(), &Reconciled::Drifted(vec!["cpu.max".to_string()]));
+        assert_eq!(games.quota_us(), None);
+        assert!(!games.frozen());
+        assert_eq!(inspection.missing, vec![PathBuf::from("builds")]);
+        assert_eq!(inspection.stale, vec![(PathBuf::from("old"), 1)]);
+
+        assert!(!base.join("builds").exists());
+        assert!(!base.join("games/cgroup.freeze").exists());
+        assert_eq!(read_trimmed(&base.join("games/cpu.max")), "max 100000");
+        assert!(base.join("old").is_dir());
+        assert_eq!(load_managed(&state_dir), vec![PathBuf::from("old"), PathBuf::from("games")]);
+    }
+
+    #[test]
+    fn nested_groups_are_created_inside_parents() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let layer = tmp.path().join("ananicy.d");
+        fs::create_dir_all(&layer).unwrap();
+        fs::write(layer.join("00-test.cgroups"), concat!(
+            r#"{"cgroup": "indexers", "parent": "background", "CPUQuota": 20}"#, "\n",
+            r#"{"cgroup": "background", "CPUQuota": 40}"#, "\n",
+        )).unwrap();
+
+        let cgroups = load_cgroups(&[layer], PowerSource::Ac, &Backend::Cgroupfs, &cgroup_fs).unwrap();
+        assert_eq!(cgroups["indexers"].rel_path(), Path::new("background/indexers"));
+        assert!(tmp.path().join("cgroup/ananicy.service/background/indexers/cpu.max").exists());
+    }
+
+    #[test]
+    fn creation_order_skips_cycles_and_missing_parents() {
+        let mut defs = BTreeMap::new();
+        for json in [
+            r#"{"cgroup": "a", "parent": "b", "CPUQuota": 10}"#,
+            r#"{"cgroup": "b", "parent": "a", "CPUQuota": 10}"#,
+            r#"{"cgroup": "c", "parent": "b", "CPUQuota": 10}"#,
+            r#"{"cgroup": "orphan", "parent": "gone", "CPUQuota": 10}"#,
+            r#"{"cgroup": "child", "parent": "orphan", "CPUQuota": 10}"#,
+            r#"{"cgroup": "games", "CPUQuota": 10}"#,
+            r#"{"cgroup": "indexers", "parent": "background", "CPUQuota": 10}"#,
+            r#"{"cgroup": "background", "CPUQuota": 10}"#,
+        ] {
+            let def = def(json);
+            defs.insert(def.cgroup.clone(), def);
+        }
+        assert_eq!(creation_order(&defs), ["background", "games", "indexers"]);
+    }
+
+    #[test]
+    fn bad_parent_does_not_block_other_groups() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let layer = tmp.path().join("ananicy.d");
+        fs::create_dir_all(&layer).unwrap();
+        fs::write(layer.join("00-test.cgroups"), concat!(
+            r#"{"cgroup": "games", "CPUQuota": 50}"#, "\n",
+            r#"{"cgroup": "indexers", "parent": "missing", "CPUQuota": 20}"#, "\n",
+            r#"{"cgroup": "background", "CPUQuota": 40}"#, "\n",
+        )).unwrap();
+
+        let cgroups = load_cgroups(&[layer], PowerSource::Ac, &Backend::Cgroupfs, &cgroup_fs).unwrap();
+        let mut names: Vec<&str> = cgroups.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, ["background", "games"]);
+        assert!(!tmp.path().join("cgroup/ananicy.service/indexers").exists());
+    }
+
+    #[test]
+    fn hybrid_places_process_in_every_hierarchy() {
+        let (tmp, cgroup_fs) = fake_hybrid();
+        assert_eq!(cgroup_fs.hierarchies().mode(), Mode::Hybrid);
+
+        let cg = create(&cgroup_fs, r#"{"cgroup": "builds", "CPUQuotaTotal": 50, "MemoryMax": "512M", "TasksMax": 32}"#);
+        let root = tmp.path().join("cgroup");
+        // Half of the four CPUs of the root cpuset
+        assert_eq!(read_trimmed(&root.join("cpu/builds/cpu.cfs_quota_us")), "200000");
+        assert_eq!(read_trimmed(&root.join("cpu/builds/cpu.shares")), "512");
+        assert_eq!(read_trimmed(&root.join("memory/builds/memory.limit_in_bytes")), (512u64 << 20).to_string());
+        assert_eq!(read_trimmed(&root.join("pids/builds/pids.max")), "32");
+
+        cg.add_pid(7).unwrap();
+        for hierarchy in ["cpu", "cpuacct", "memory", "pids", "freezer"] {
+            assert_eq!(read_trimmed(&root.join(hierarchy).join("builds/cgroup.procs")), "7", "{}", hierarchy);
+        }
+        assert!(!root.join("blkio/builds").exists());
+    }
+
+    #[test]
+    fn v2_freeze_and_thaw() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let freeze = tmp.path().join("cgroup/ananicy.service/background/cgroup.freeze");
+
+        let mut cg = create(&cgroup_fs, r#"{"cgroup": "background", "CPUQuota": 50, "Freeze": true}"#);
+        assert!(cg.frozen());
+        assert_eq!(read_trimmed(&freeze), "1");
+
+        cg.set_frozen(false).unwrap();
+        assert_eq!(read_trimmed(&freeze), "0");
+
+        // Recreating keeps the state the daemon left, so a restart does not thaw
+        fs::write(&freeze, "1").unwrap();
+        assert!(create(&cgroup_fs, r#"{"cgroup": "background", "CPUQuota": 50}"#).frozen());
+    }
+
+    #[test]
+    fn v1_freezer_state() {
+        let (tmp, cgroup_fs) = fake_hybrid();
+        let mut cg = create(&cgroup_fs, r#"{"cgroup": "background", "CPUQuota": 50}"#);
+        let state = tmp.path().join("cgroup/freezer/background/freezer.state");
+
+        cg.set_frozen(true).unwrap();
+        assert_eq!(read_trimmed(&state), "FROZEN");
+        cg.set_frozen(false).unwrap();
+        assert_eq!(read_trimmed(&state), "THAWED");
+    }
+
+    #[test]
+    fn remove_all_thaws_before_migrating() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let state_dir = tmp.path().join("state");
+        let mut cgroups = HashMap::new();
+        cgroups.insert("background".to_string(), create(&cgroup_fs, r#"{"cgroup": "background", "CPUQuota": 50, "Freeze": true}"#));
+        let dir = tmp.path().join("cgroup/ananicy.service/background");
+
+        // Regular files stand in for the kernel interface, so rmdir fails and the state stays
+        let cleanup = remove_all(&state_dir, &cgroups, &cgroup_fs).unwrap();
+        assert_eq!(cleanup.kept, vec![PathBuf::from("background")]);
+        assert_eq!(read_trimmed(&dir.join("cgroup.freeze")), "0");
+    }
+
+    #[test]
+    fn remove_all_restores_original_cgroups() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let root = tmp.path().join("cgroup");
+        let app = root.join("user.slice/app.scope");
+        fs::create_dir_all(&app).unwrap();
+        fs::create_dir_all(tmp.path().join("proc/7007")).unwrap();
+        fs::write(tmp.path().join("proc/7007/cgroup"), "0::/user.slice/app.scope\n").unwrap();
+
+        let cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 50}"#);
+        cg.add_pid(7007).unwrap();
+        // A process placed by an earlier run has no known origin
+        fs::write(root.join("ananicy.service/games/cgroup.procs"), "7007\n7008\n").unwrap();
+
+        let mut cgroups = HashMap::new();
+        cgroups.insert("games".to_string(), cg);
+        remove_all(&tmp.path().join("state"), &cgroups, &cgroup_fs).unwrap();
+        assert_eq!(read_trimmed(&app.join("cgroup.procs")), "7007");
+        // Never the daemon's own cgroup, which may not hold processes next to its children
+        assert_eq!(read_trimmed(&root.join("cgroup.procs")), "7008");
+        assert!(!root.join("ananicy.service/cgroup.procs").exists());
+    }
+
+    #[test]
+    fn reused_pid_does_not_inherit_origin() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let root = tmp.path().join("cgroup");
+        let proc = tmp.path().join("proc/7100");
+        fs::create_dir_all(&proc).unwrap();
+        let spawn = |scope: &str, start_time: u64| {
+            fs::create_dir_all(root.join("user.slice").join(scope)).unwrap();
+            fs::write(proc.join("cgroup"), format!("0::/user.slice/{}\n", scope)).unwrap();
+            // The command name may hold parentheses and spaces
+            fs::write(proc.join("stat"), format!("7100 (a (b) c) S{} {} 0\n", " 0".repeat(18), start_time)).unwrap();
+        };
+        let cg = create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 50}"#);
+        let games = root.join("ananicy.service/games");
+
+        spawn("old.scope", 100);
+        assert_eq!(start_time(&cgroup_fs, 7100), Some(100));
+        cg.add_pid(7100).unwrap();
+
+        // The pid now belongs to another process that was moved in by someone else
+        spawn("new.scope", 200);
+        migrate_procs(&cgroup_fs, &games, &[]);
+        assert_eq!(read_trimmed(&root.join("cgroup.procs")), "7100");
+        assert!(!root.join("user.slice/old.scope/cgroup.procs").exists());
+
+        // Placing it again records its own origin
+        cg.add_pid(7100).unwrap();
+        migrate_procs(&cgroup_fs, &games, &[]);
+        assert_eq!(read_trimmed(&root.join("user.slice/new.scope/cgroup.procs")), "7100");
+    }
+
+    #[test]
+    fn v1_cpuset_inherits_unset_values() {
+        let (tmp, cgroup_fs) = fake_hybrid();
+        create(&cgroup_fs, r#"{"cgroup": "pinned", "CPUQuota": 90, "AllowedCPUs": "1"}"#);
+
+        let dir = tmp.path().join("cgroup/cpuset/pinned");
+        assert_eq!(read_trimmed(&dir.join("cpuset.cpus")), "1");
+        assert_eq!(read_trimmed(&dir.join("cpuset.mems")), "0");
+    }
+
+    #[test]
+    fn stale_groups_are_removed_when_empty() {
+        let (tmp, cgroup_fs) = fake_v2();
+        let state_dir = tmp.path().join("state");
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(state_dir.join(MANAGED_FILE), "old\ngames\n").unwrap();
+        let old = tmp.path().join("cgroup/ananicy.service/old");
+        fs::create_dir_all(&old).unwrap();
+
+        let mut cgroups = HashMap::new();
+        cgroups.insert("games".to_string(), create(&cgroup_fs, r#"{"cgroup": "games", "CPUQuota": 50}"#));
+
+        let cleanup = remove_stale(&state_dir, &cgroups, &cgroup_fs).unwrap();
+        assert_eq!(cleanup.removed, vec![PathBuf::from("old")]);
+        assert!(!old.exists());
+        assert_eq!(load_managed(&state_dir), vec![PathBuf::from("games")]);
+    }
+}
@@ -0,0 +1,358 @@
+//! Reading rule sets written for ananicy, ananicy-cpp and CachyOS-ananicy-rules,
+//! and converting them to this daemon's format.
+//!
+//! Entries are parsed leniently: numbers may be given as strings, percentages
+//! as `"50%"`, and fields without a counterpart here are dropped with a note
+//! instead of failing the whole entry.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::cgroup::CgroupDef;
+use crate::rules::{Rule, Type};
+
+/// A configuration file, by extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Rules,
+    Types,
+    Cgroups,
+}
+
+impl Kind {
+    pub fn of(path: &Path) -> Option<Self> {
+        Self::from_extension(path.extension()?.to_str()?)
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "rules" => Some(Kind::Rules),
+            "types" => Some(Kind::Types),
+            "cgroups" => Some(Kind::Cgroups),
+            _ => None,
+        }
+    }
+}
+
+/// Which meaning `CPUQuota` of a cgroup has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    /// This daemon's format: percent of one CPU, like systemd.
+    Native,
+    /// ananicy and ananicy-cpp: percent of all CPUs.
+    Upstream,
+}
+
+/// Upstream fields that have no counterpart here, with the reason.
+const UNSUPPORTED: &[(&str, &str)] = &[
+    ("latency_nice", "latency nice is not in mainline kernels"),
+];
+
+const INTEGER_FIELDS: &[&str] = &["nice", "ionice", "rtprio", "oom_score_adj", "priority", "IOWeight"];
+
+const PERCENT_FIELDS: &[&str] = &["CPUQuota", "CPUQuotaTotal", "CPUQuotaBattery"];
+
+/// A parsed entry together with its normalized JSON form.
+#[derive(Debug)]
+pub struct Entry<T> {
+    pub value: T,
+    /// The entry as it should be written in this daemon's format.
+    pub json: Map<String, Value>,
+    /// Fields that were dropped or converted.
+    pub notes: Vec<String>,
+}
+
+/// Parse one entry, accepting the value forms used by upstream rule sets.
+pub fn parse<T: DeserializeOwned + Serialize>(line: &str, dialect: Dialect) -> Result<Entry<T>> {
+    parse_map(serde_json::from_str(strip_trailing_comment(line))?, dialect)
+}
+
+/// Like [`parse`], for an entry that was already read into a map.
+pub fn parse_map<T: DeserializeOwned + Serialize>(mut json: Map<String, Value>, dialect: Dialect) -> Result<Entry<T>> {
+    let mut notes = Vec::new();
+
+    for field in INTEGER_FIELDS {
+        if let Some(value) = json.get_mut(*field) {
+            coerce_integer(field, value, false)?;
+        }
+    }
+    for field in PERCENT_FIELDS {
+        if let Some(value) = json.get_mut(*field) {
+            coerce_integer(field, value, true)?;
+        }
+    }
+    if let Some(Value::String(class)) = json.get_mut("ioclass") {
+        *class = match class.to_lowercase().replace('_', "-").as_str() {
+            "be" => "best-effort".to_string(),
+            "rt" => "realtime".to_string(),
+            other => other.to_string(),
+        };
+    }
+
+    if dialect == Dialect::Upstream {
+        if let Some(quota) = json.shift_remove("CPUQuota") {
+            let percent = quota.as_u64()
+                .ok_or_else(|| anyhow!("CPUQuota must be a whole, non-negative percentage, got {}", quota))?;
+            if percent > 100 {
+                notes.push(format!("CPUQuota {}% is more than all CPUs, limited to 100%", percent));
+            }
+            notes.push("CPUQuota (percent of all CPUs) converted to CPUQuotaTotal".to_string());
+            json.insert("CPUQuotaTotal".to_string(), percent.min(100).into());
+        }
+    }
+
+    for (field, reason) in UNSUPPORTED {
+        if json.shift_remove(*field).is_some() {
+            notes.push(format!("{} is not supported ({}), ignored", field, reason));
+        }
+    }
+
+    let value: T = serde_json::from_value(Value::Object(json.clone()))?;
+
+    // Whatever serde skipped is unknown here
+    let known = serde_json::to_value(&value)?;
+    let unknown: Vec<String> = json.iter()
+        .filter(|(key, value)| known.get(key.as_str()).is_none() && !is_empty(value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in unknown {
+        json.shift_remove(&key);
+        notes.push(format!("unknown field {}, ignored", key));
+    }
+
+    Ok(Entry { value, json, notes })
+}
+
+/// Drop a `# comment` following the closing brace of an entry.
+fn strip_trailing_comment(line: &str) -> &str {
+    match object_end(line) {
+        Some(end) if line[end + 1..].trim_start().starts_with('#') => &line[..=end],
+        _ => line,
+    }
+}
+
+/// Index of the `}` closing the object `line` starts with, skipping braces
+/// inside strings.
+fn object_end(line: &str) -> Option<usize> {
+    let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '{' if !in_string => depth += 1,
+            '}' if !in_string => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Turn `"-5"`, or `"50%"` if it is a `percent` field, into a number.
+fn coerce_integer(field: &str, value: &mut Value, percent: bool) -> Result<()> {
+    if let Value::String(text) = value {
+        let digits = match percent {
+            true => text.trim().strip_suffix('%').unwrap_or(text).trim(),
+            false => text.trim(),
+        };
+        let number: i64 = digits.parse()
+            .map_err(|_| anyhow!("{} must be a number, got '{}'", field, text))?;
+        *value = number.into();
+    }
+    Ok(())
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Array(items) => items.is_empty(),
+        Value::Object(map) => map.is_empty(),
+        _ => false,
+    }
+}
+
+/// Outcome of importing one file.
+#[derive(Debug, Default)]
+pub struct FileReport {
+    /// Path relative to the imported directory.
+    pub path: PathBuf,
+    pub converted: usize,
+    /// Line numbers and reasons of entries that could not be imported.
+    pub skipped: Vec<(usize, String)>,
+    /// Line numbers and notes about dropped or converted fields.
+    pub notes: Vec<(usize, String)>,
+}
+
+/// Convert all rules, types and cgroups below `source` into `target`,
+/// keeping the directory layout. Existing files are only replaced with `force`.
+///
+/// Entries that cannot be imported are kept as comments so nothing is lost.
+pub fn import_dir(source: &Path, target: &Path, force: bool) -> Result<Vec<FileReport>> {
+    let mut files = Vec::new();
+    collect_files(source, &mut files)?;
+    if files.is_empty() {
+        bail!("no .rules, .types or .cgroups files in {}", source.display());
+    }
+    if !force {
+        if let Some(existing) = files.iter().map(|(path, _)| target.join(path)).find(|path| path.exists()) {
+            bail!("{} already exists, use --force to overwrite", existing.display());
+        }
+    }
+
+    let mut reports = Vec::new();
+    for (path, kind) in files {
+        let content = fs::read_to_string(source.join(&path))
+            .with_context(|| format!("reading {}", source.join(&path).display()))?;
+        let mut report = FileReport { path, ..FileReport::default() };
+        let mut output = String::new();
+
+        for (number, line) in content.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                output.push_str(line);
+                output.push('\n');
+                continue;
+            }
+            match convert(kind, trimmed) {
+                Ok((json, notes)) => {
+                    output.push_str(&serde_json::to_string(&json)?);
+                    report.converted += 1;
+                    report.notes.extend(notes.into_iter().map(|note| (number + 1, note)));
+                }
+                Err(e) => {
+                    output.push_str(&format!("# skipped ({:#}): {}", e, trimmed));
+                    report.skipped.push((number + 1, format!("{:#}", e)));
+                }
+            }
+            output.push('\n');
+        }
+
+        let destination = target.join(&report.path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+        }
+        fs::write(&destination, output).with_context(|| format!("writing {}", destination.display()))?;
+        reports.push(report);
+    }
+
+    Ok(reports)
+}
+
+/// Convert one upstream entry and check it like the loaders do.
+fn convert(kind: Kind, line: &str) -> Result<(Map<String, Value>, Vec<String>)> {
+    match kind {
+        Kind::Rules => {
+            let entry = parse::<Rule>(line, Dialect::Upstream)?;
+            entry.value.validate()?;
+            Ok((entry.json, entry.notes))
+        }
+        Kind::Types => {
+            let entry = parse::<Type>(line, Dialect::Upstream)?;
+            entry.value.validate()?;
+            Ok((entry.json, entry.notes))
+        }
+        Kind::Cgroups => {
+            let entry = parse::<CgroupDef>(line, Dialect::Upstream)?;
+            entry.value.validate()?;
+            Ok((entry.json, entry.notes))
+        }
+    }
+}
+
+/// Files of a known kind below `dir`, relative to `root`, in name order.
+fn collect_files(root: &Path, files: &mut Vec<(PathBuf, Kind)>) -> Result<()> {
+    fn walk(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, Kind)>) -> Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+            .with_context(|| format!("reading {}", dir.display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if path.is_dir() {
+                walk(root, &path, files)?;
+            } else if let Some(kind) = Kind::of(&path) {
+                files.push((path.strip_prefix(root)?.to_path_buf(), kind));
+            }
+        }
+        Ok(())
+    }
+
+    walk(root, root, files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upstream_entries_are_normalized() {
+        let entry = parse::<Rule>(
+            r#"{"name": "steam", "type": "Game", "nice": "-5", "latency_nice": -10, "ioclass": "BE", "foo": 1} # launcher"#,
+            Dialect::Upstream,
+        ).unwrap();
+        assert_eq!(entry.value.nice, Some(-5));
+        assert_eq!(entry.value.ioclass.as_deref(), Some("best-effort"));
+        assert_eq!(serde_json::to_string(&entry.json).unwrap(),
+                   r#"{"name":"steam","type":"Game","nice":-5,"ioclass":"best-effort"}"#);
+        assert_eq!(entry.notes.len(), 2);
+
+        let entry = parse::<CgroupDef>(r#"{"cgroup": "cpu80", "CPUQuota": "80%"}"#, Dialect::Upstream).unwrap();
+        assert_eq!((entry.value.cpu_quota, entry.value.cpu_quota_total), (None, Some(80)));
+        let entry = parse::<CgroupDef>(r#"{"cgroup": "cpu80", "CPUQuota": "80%"}"#, Dialect::Native).unwrap();
+        assert_eq!((entry.value.cpu_quota, entry.value.cpu_quota_total), (Some(80), None));
+
+        assert!(parse::<Rule>(r#"{"name": "x", "nice": "low"}"#, Dialect::Upstream).is_err());
+        assert!(parse::<Rule>(r#"{"name": "x", "nice": "5%"}"#, Dialect::Upstream).is_err());
+        let entry = parse::<Rule>(r#"{"name": "x}", "nice": 5} # see {x} and }"#, Dialect::Native).unwrap();
+        assert_eq!((entry.value.name.as_deref(), entry.value.nice), (Some("x}"), Some(5)));
+        assert_eq!(object_end(r#"{"a": "\"}", "b": {}} # }"#), Some(20));
+        assert_eq!(object_end(r#"{"a": 1"#), None);
+        for quota in ["-50", "\"-50%\"", "12.5"] {
+            let line = format!(r#"{{"cgroup": "cpu", "CPUQuota": {}}}"#, quota);
+            assert!(parse::<CgroupDef>(&line, Dialect::Upstream).is_err(), "{}", quota);
+        }
+    }
+
+    #[test]
+    fn import_converts_and_reports() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = tmp.path().join("upstream");
+        fs::create_dir_all(source.join("00-default/games")).unwrap();
+        fs::write(
+            source.join("00-types.types"),
+            "{\"type\": \"Game\", \"nice\": -5, \"latency_nice\": -5}\n{\"type\": \"Broken\", \"ionice\": 9}\n",
+        ).unwrap();
+        fs::write(source.join("00-cgroups.cgroups"), "{\"cgroup\": \"cpu90\", \"CPUQuota\": 90}\n").unwrap();
+        fs::write(
+            source.join("00-default/games/steam.rules"),
+            "# Steam\n{\"name\": \"steam\", \"type\": \"Game\"}\n{\"name\": \"broken\", \"nice\": 40}\n",
+        ).unwrap();
+        fs::write(source.join("ananicy.conf"), "check_freq=5\n").unwrap();
+
+        let target = tmp.path().join("etc");
+        let reports = import_dir(&source, &target, false).unwrap();
+        assert_eq!(reports.len(), 3);
+        let rules = reports.iter().find(|report| report.path == Path::new("00-default/games/steam.rules")).unwrap();
+        assert_eq!((rules.converted, rules.skipped.len()), (1, 1));
+        assert_eq!(rules.skipped[0].0, 3);
+        let types = reports.iter().find(|report| report.path == Path::new("00-types.types")).unwrap();
+        assert_eq!((types.converted, types.skipped.len()), (1, 1));
+        assert_eq!(types.skipped[0].0, 2);
+
+        let written = fs::read_to_string(target.join("00-cgroups.cgroups")).unwrap();
+        assert_eq!(written, "{\"cgroup\":\"cpu90\",\"CPUQuotaTotal\":90}\n");
+        let written = fs::read_to_string(target.join("00-default/games/steam.rules")).unwrap();
+        assert!(written.starts_with("# Steam\n{\"name\":\"steam\",\"type\":\"Game\"}\n# skipped (nice must"));
+
+        assert!(import_dir(&source, &target, false).is_err());
+        assert!(import_dir(&source, &target, true).is_ok());
+    }
+}
@@ -1,7 +1,9 @@
 use std::path::Path;
 use std::fs;
 use serde::{Deserialize, Serialize};
-use anyhow::Result;
+use log::warn;
+use crate::error::{Error, Result};
+use crate::hierarchy::CgroupFs;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -16,6 +18,49 @@ pub struct Config {
     
     #[serde(default = "default_config_dir")]
     pub config_dir: String,
+    
+    #[serde(default = "default_true")]
+    pub check_disks_schedulers: bool,
+    
+    /// Prometheus listener, `host:port` or `unix:/path`; disabled when unset.
+    #[serde(default)]
+    pub metrics_listen: Option<String>,
+    
+    /// Unix socket accepting control commands such as profile switches.
+    #[serde(default = "default_control_socket")]
+    pub control_socket: String,
+    
+    /// Directory for state persisted across restarts, e.g. the active profile.
+    #[serde(default = "default_state_dir")]
+    pub state_dir: String,
+    
+    /// Root of the power supply class in sysfs, overridable for testing.
+    #[serde(default = "default_power_supply_root")]
+    pub power_supply_root: String,
+    
+    /// `auto`, `systemd` (transient slices and scopes) or `cgroupfs`.
+    #[serde(default = "default_cgroup_backend")]
+    pub cgroup_backend: String,
+    
+    /// Directory holding the cgroup mounts; discovered from mountinfo when unset.
+    #[serde(default)]
+    pub cgroup_root: Option<String>,
+    
+    #[serde(default = "default_proc_root")]
+    pub proc_root: String,
+    
+    /// Root of sysfs, used to resolve the disks of IO limits.
+    #[serde(default = "default_sys_root")]
+    pub sys_root: String,
+    
+    /// Load the restricted rules of users from `users/<name>/` and
+    /// `~/.config/ananicy.d/` of logged-in users.
+    #[serde(default = "default_true")]
+    pub user_rules: bool,
+    
+    /// Where logind creates a directory per logged-in uid, overridable for testing.
+    #[serde(default = "default_user_runtime_root")]
+    pub user_runtime_root: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -51,21 +96,42 @@ pub struct VerboseOpts {
 fn default_check_freq() -> f64 { 5.0 }
 fn default_true() -> bool { true }
 fn default_config_dir() -> String { "/etc/ananicy.d/".to_string() }
+fn default_control_socket() -> String { "/run/rust-ananicy/control.sock".to_string() }
+fn default_state_dir() -> String { "/var/lib/rust-ananicy".to_string() }
+fn default_power_supply_root() -> String { "/sys/class/power_supply".to_string() }
+fn default_cgroup_backend() -> String { "auto".to_string() }
+fn default_proc_root() -> String { "/proc".to_string() }
+fn default_sys_root() -> String { "/sys".to_string() }
+fn default_user_runtime_root() -> String { "/run/user".to_string() }
 
 impl Config {
+    /// Read `ananicy.toml` or, if there is none, `ananicy.conf` from `config_dir`.
     pub fn load(config_dir: &Path) -> Result<Self> {
         let config_file = config_dir.join("ananicy.conf");
+        let toml_file = config_dir.join("ananicy.toml");
         
-        if !config_file.exists() {
-            return Ok(Self::default());
-        }
-        
-        let content = fs::read_to_string(&config_file)?;
-        let config = Self::parse_config(&content)?;
+        let mut config = if toml_file.exists() {
+            if config_file.exists() {
+                warn!("Both ananicy.toml and ananicy.conf exist, ignoring ananicy.conf");
+            }
+            let content = fs::read_to_string(&toml_file).map_err(Error::io(&toml_file))?;
+            toml::from_str(&content).map_err(|source| Error::Toml { path: toml_file, source: Box::new(source) })?
+        } else if config_file.exists() {
+            let content = fs::read_to_string(&config_file).map_err(Error::io(&config_file))?;
+            Self::parse_config(&content)?
+        } else {
+            Self::default()
+        };
+        config.config_dir = config_dir.to_string_lossy().into_owned();
         
         Ok(config)
     }
     
+    pub fn cgroup_fs(&self) -> CgroupFs {
+        CgroupFs::detect(Path::new(&self.proc_root), self.cgroup_root.as_deref().map(Path::new))
+            .with_sys_root(Path::new(&self.sys_root))
+    }
+    
     fn parse_config(content: &str) -> Result<Self> {
         let mut config = Self::default();
         
@@ -81,7 +147,10 @@ impl Config {
                 
                 match key {
                     "check_freq" => {
-                        config.check_freq = value.parse()?;
+                        config.check_freq = value.parse().map_err(|_| Error::Setting {
+                            key: key.to_string(),
+                            value: value.to_string(),
+                        })?;
                     }
                     "verbose" => {
                         config.verbose = parse_bool(value);
@@ -113,6 +182,39 @@ impl Config {
                     "apply_cgroup" => {
                         config.verbose_opts.apply_cgroup = parse_bool(value);
                     }
+                    "check_disks_schedulers" => {
+                        config.check_disks_schedulers = parse_bool(value);
+                    }
+                    "metrics_listen" => {
+                        config.metrics_listen = Some(value.to_string()).filter(|v| !v.is_empty());
+                    }
+                    "control_socket" => {
+                        config.control_socket = value.to_string();
+                    }
+                    "state_dir" => {
+                        config.state_dir = value.to_string();
+                    }
+                    "power_supply_root" => {
+                        config.power_supply_root = value.to_string();
+                    }
+                    "cgroup_backend" => {
+                        config.cgroup_backend = value.to_string();
+                    }
+                    "cgroup_root" => {
+                        config.cgroup_root = Some(value.to_string()).filter(|v| !v.is_empty());
+                    }
+                    "proc_root" => {
+                        config.proc_root = value.to_string();
+                    }
+                    "sys_root" => {
+                        config.sys_root = value.to_string();
+                    }
+                    "user_rules" => {
+                        config.user_rules = parse_bool(value);
+                    }
+                    "user_runtime_root" => {
+                        config.user_runtime_root = value.to_string();
+                    }
                     _ => {}
                 }
             }
@@ -129,10 +231,48 @@ impl Default for Config {
             verbose: true,
             verbose_opts: VerboseOpts::default(),
             config_dir: default_config_dir(),
+            check_disks_schedulers: true,
+            metrics_listen: None,
+            control_socket: default_control_socket(),
+            state_dir: default_state_dir(),
+            power_supply_root: default_power_supply_root(),
+            cgroup_backend: default_cgroup_backend(),
+            cgroup_root: None,
+            proc_root: default_proc_root(),
+            sys_root: default_sys_root(),
+            user_rules: true,
+            user_runtime_root: default_user_runtime_root(),
         }
     }
 }
 
 fn parse_bool(value: &str) -> bool {
     matches!(value.to_lowercase().as_str(), "true" | "yes" | "1")
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_config_takes_precedence() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("ananicy.conf"), "check_freq=5\n").unwrap();
+        fs::write(tmp.path().join("ananicy.toml"), r#"
+check_freq = 2  # seconds
+cgroup_backend = "cgroupfs"
+
+[verbose_opts]
+rule_load = false
+"#).unwrap();
+
+        let config = Config::load(tmp.path()).unwrap();
+        assert_eq!(config.check_freq, 2.0);
+        assert_eq!(config.cgroup_backend, "cgroupfs");
+        assert!(!config.verbose_opts.rule_load);
+        assert!(config.verbose_opts.type_load);
+
+        fs::write(tmp.path().join("ananicy.toml"), "check_freq = \"often\"\n").unwrap();
+        assert!(matches!(Config::load(tmp.path()), Err(Error::Toml { .. })));
+    }
+}
@@ -0,0 +1,173 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use anyhow::{Result, Context, bail, anyhow};
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use crate::profile;
+
+/// Mode of the control socket: commands change priorities system-wide, so
+/// only root may connect, whatever the daemon's umask.
+const SOCKET_MODE: u32 = 0o600;
+
+/// A command accepted on the control socket, one per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    ProfileGet,
+    ProfileSet(Option<String>),
+    Reload,
+    Freeze(String),
+    Thaw(String),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["profile", "get"] => Ok(Command::ProfileGet),
+            ["profile", "set", name] => Ok(Command::ProfileSet(profile::parse_name(name))),
+            ["reload"] => Ok(Command::Reload),
+            ["freeze", cgroup] => Ok(Command::Freeze(cgroup.to_string())),
+            ["thaw", cgroup] => Ok(Command::Thaw(cgroup.to_string())),
+            _ => bail!("unknown command '{}'", line.trim()),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            Command::ProfileGet => "profile get".to_string(),
+            Command::ProfileSet(name) => format!("profile set {}", name.as_deref().unwrap_or("none")),
+            Command::Reload => "reload".to_string(),
+            Command::Freeze(cgroup) => format!("freeze {}", cgroup),
+            Command::Thaw(cgroup) => format!("thaw {}", cgroup),
+        }
+    }
+}
+
+/// A command forwarded to the daemon loop, answered through `reply`.
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Result<String, String>>,
+}
+
+/// Accept control connections on `path` and forward commands to `tx`.
+pub async fn serve(path: &Path, tx: mpsc::Sender<Request>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("mkdir {}", dir.display()))?;
+    }
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("bind control socket {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(SOCKET_MODE))
+        .with_context(|| format!("chmod {}", path.display()))?;
+    info!("Listening for control commands on {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(handle(stream, tx.clone()));
+    }
+}
+
+async fn handle(stream: UnixStream, tx: mpsc::Sender<Request>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = AsyncBufReader::new(read).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let result = match Command::parse(&line) {
+            Ok(command) => {
+                let (reply, response) = oneshot::channel();
+                if tx.send(Request { command, reply }).await.is_err() {
+                    return;
+                }
+                response.await.unwrap_or_else(|_| Err("daemon is shutting down".to_string()))
+            }
+            Err(e) => Err(e.to_string()),
+        };
+
+        let response = match result {
+            Ok(message) => format!("ok {}\n", message),
+            Err(message) => format!("error {}\n", message),
+        };
+        if let Err(e) = write.write_all(response.as_bytes()).await {
+            warn!("Failed to write control response: {}", e);
+            return;
+        }
+    }
+}
+
+/// Whether a daemon is accepting connections on `path`.
+pub fn is_running(path: &Path) -> bool {
+    StdUnixStream::connect(path).is_ok()
+}
+
+/// Send `command` to a running daemon and return its reply.
+pub fn send(path: &Path, command: &Command) -> Result<String> {
+    let mut stream = StdUnixStream::connect(path)
+        .with_context(|| format!("connect to {}", path.display()))?;
+    writeln!(stream, "{}", command.to_line())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    let response = response.trim_end();
+
+    if let Some(message) = response.strip_prefix("ok") {
+        Ok(message.trim_start().to_string())
+    } else if let Some(message) = response.strip_prefix("error") {
+        Err(anyhow!("{}", message.trim_start()))
+    } else {
+        Err(anyhow!("unexpected response '{}'", response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn socket_is_private_and_answers_commands() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("run/control.sock");
+        let (tx, mut rx) = mpsc::channel::<Request>(1);
+        tokio::spawn({
+            let path = path.clone();
+            async move { serve(&path, tx).await }
+        });
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                let _ = request.reply.send(match request.command {
+                    Command::ProfileGet => Ok("gaming".to_string()),
+                    other => Err(format!("unexpected {}", other.to_line())),
+                });
+            }
+        });
+        while !is_running(&path) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, SOCKET_MODE);
+
+        let reply = tokio::task::spawn_blocking(move || {
+            (send(&path, &Command::ProfileGet).unwrap(), send(&path, &Command::Reload).unwrap_err().to_string())
+        }).await.unwrap();
+        assert_eq!(reply, ("gaming".to_string(), "unexpected reload".to_string()));
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        for command in [
+            Command::ProfileGet,
+            Command::ProfileSet(Some("gaming".to_string())),
+            Command::ProfileSet(None),
+            Command::Reload,
+            Command::Freeze("background".to_string()),
+            Command::Thaw("background".to_string()),
+        ] {
+            assert_eq!(Command::parse(&command.to_line()).unwrap(), command);
+        }
+        assert!(Command::parse("profile").is_err());
+    }
+}
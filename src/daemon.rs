@@ -0,0 +1,524 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{Result, Context, bail};
+use log::{info, warn, error};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time;
+use crate::cgroup::{self, Backend, CgroupController};
+use crate::config::Config;
+use crate::control::{self, Command, Request};
+use crate::hierarchy::CgroupFs;
+use crate::matcher::Matcher;
+use crate::metrics::{self, METRICS};
+use crate::power::{self, PowerState};
+use crate::actuator::{Actuator, SystemActuator};
+use crate::process::{self, ProcFs, ProcessSource};
+use crate::psi::{self, Throttle};
+use crate::profile;
+use crate::rules::{self, Rule};
+use crate::users;
+use crate::utils;
+
+/// Rules and cgroups of the active profile, swapped as a unit.
+struct Daemon {
+    config: Config,
+    profile: Option<String>,
+    power: PowerState,
+    backend: Backend,
+    cgroup_fs: Arc<CgroupFs>,
+    rules: Vec<Rule>,
+    /// Rules of users, ranked after `rules`.
+    user_rules: Vec<Rule>,
+    /// User rule files seen when `user_rules` was loaded.
+    user_stamp: users::Stamp,
+    /// Subset of `rules` and `user_rules` whose `when` condition holds for `power`.
+    active_rules: Matcher,
+    cgroups: HashMap<String, CgroupController>,
+    throttles: HashMap<String, Throttle>,
+    /// Cgroups frozen through the control socket.
+    frozen: BTreeSet<String>,
+    /// Cgroups frozen by `freeze_cgroups` of rules that matched in the last scan.
+    rule_frozen: BTreeSet<String>,
+    source: Box<dyn ProcessSource>,
+    actuator: Box<dyn Actuator>,
+}
+
+fn load_ruleset(
+    config_dir: &Path,
+    profile: Option<&str>,
+    power: &PowerState,
+    backend: &Backend,
+    cgroup_fs: &Arc<CgroupFs>,
+) -> Result<(Vec<Rule>, HashMap<String, CgroupController>)> {
+    let layers = profile::layers(config_dir, profile)?;
+    let rules = rules::load_all_rules(&layers)?;
+    let cgroups = cgroup::load_cgroups(&layers, power.source, backend, cgroup_fs)?;
+    Ok((rules, cgroups))
+}
+
+/// Rules of the users with rule directories, and the stamp of their files.
+fn load_user_rules(config: &Config, profile: Option<&str>) -> Result<(Vec<Rule>, users::Stamp)> {
+    if !config.user_rules {
+        return Ok(Default::default());
+    }
+    let config_dir = Path::new(&config.config_dir);
+    let accounts = users::accounts(config_dir, Path::new(&config.user_runtime_root));
+    let stamp = users::stamp(config_dir, &accounts);
+    let layers = profile::layers(config_dir, profile)?;
+    Ok((users::load_rules(config_dir, &layers, &accounts)?, stamp))
+}
+
+impl Daemon {
+    fn new(config: Config) -> Result<Self> {
+        let source = Box::new(ProcFs::new(&config.proc_root));
+        let actuator = Box::new(SystemActuator::new(&config.proc_root));
+        Self::with_io(config, source, actuator)
+    }
+
+    /// Set up the daemon with processes read from `source` and changed through
+    /// `actuator` instead of the configured procfs.
+    fn with_io(config: Config, source: Box<dyn ProcessSource>, actuator: Box<dyn Actuator>) -> Result<Self> {
+        let config_dir = PathBuf::from(&config.config_dir);
+        let mut profile = profile::active_profile(Path::new(&config.state_dir));
+        let power = power::read_power_state(Path::new(&config.power_supply_root));
+        info!("Power source: {:?}", power.source);
+        let cgroup_fs = Arc::new(config.cgroup_fs());
+        info!("Cgroup mode: {:?}", cgroup_fs.hierarchies().mode());
+        let backend = Backend::select(&config.cgroup_backend, &cgroup_fs)?;
+        if let Backend::Systemd(_) = backend {
+            info!("Placing processes in transient systemd scopes");
+        }
+
+        let (rules, cgroups) = match load_ruleset(&config_dir, profile.as_deref(), &power, &backend, &cgroup_fs) {
+            Ok(ruleset) => ruleset,
+            Err(e) if profile.is_some() => {
+                warn!("Could not load profile {:?}, falling back to base rules: {}", profile, e);
+                profile = None;
+                load_ruleset(&config_dir, None, &power, &backend, &cgroup_fs)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let (user_rules, user_stamp) = load_user_rules(&config, profile.as_deref())?;
+
+        METRICS.set_active_profile(profile.as_deref());
+        remove_stale_cgroups(Path::new(&config.state_dir), &cgroups, &cgroup_fs);
+        let active_rules = active_rules(&rules, &user_rules, &power);
+        let mut daemon = Self {
+            config,
+            profile,
+            power,
+            backend,
+            cgroup_fs,
+            rules,
+            user_rules,
+            user_stamp,
+            active_rules,
+            cgroups,
+            throttles: HashMap::new(),
+            frozen: BTreeSet::new(),
+            rule_frozen: BTreeSet::new(),
+            source,
+            actuator,
+        };
+        daemon.update_freezer();
+        Ok(daemon)
+    }
+
+    /// Load the rule set for `profile` and swap it in only if loading succeeded.
+    fn switch(&mut self, profile: Option<String>) -> Result<()> {
+        let (rules, cgroups) = load_ruleset(
+            Path::new(&self.config.config_dir),
+            profile.as_deref(),
+            &self.power,
+            &self.backend,
+            &self.cgroup_fs,
+        )?;
+        let (user_rules, user_stamp) = load_user_rules(&self.config, profile.as_deref())?;
+        self.active_rules = active_rules(&rules, &user_rules, &self.power);
+        self.rules = rules;
+        self.user_rules = user_rules;
+        self.user_stamp = user_stamp;
+        self.cgroups = cgroups;
+        self.throttles.clear();
+        self.update_freezer();
+        remove_stale_cgroups(Path::new(&self.config.state_dir), &self.cgroups, &self.cgroup_fs);
+        self.profile = profile;
+        METRICS.set_active_profile(self.profile.as_deref());
+        info!(
+            "Loaded {} rules, {} user rules and {} cgroups (profile: {})",
+            self.rules.len(),
+            self.user_rules.len(),
+            self.cgroups.len(),
+            self.profile.as_deref().unwrap_or("none")
+        );
+        Ok(())
+    }
+
+    /// Re-read the power state and re-evaluate conditional rules and quotas.
+    fn refresh_power(&mut self) {
+        let power = power::read_power_state(Path::new(&self.config.power_supply_root));
+        if power == self.power {
+            return;
+        }
+        if power.source != self.power.source {
+            info!("Power source changed to {:?}", power.source);
+            for cgroup in self.cgroups.values_mut() {
+                if let Err(e) = cgroup.apply_power_source(power.source) {
+                    warn!("Could not update cgroup {}: {}", cgroup.name(), e);
+                }
+            }
+        }
+        // Capacity changes every few minutes on battery, but only matters once
+        // it crosses the threshold of a rule
+        let conditions_changed = self.rules.iter().chain(&self.user_rules)
+            .any(|rule| rule.is_active(&power) != rule.is_active(&self.power));
+        self.power = power;
+        if conditions_changed {
+            self.active_rules = active_rules(&self.rules, &self.user_rules, &self.power);
+        }
+    }
+
+    /// Reload user rules after a login, a logout or a change to their files.
+    fn refresh_users(&mut self) {
+        if !self.config.user_rules {
+            return;
+        }
+        let config_dir = Path::new(&self.config.config_dir);
+        let accounts = users::accounts(config_dir, Path::new(&self.config.user_runtime_root));
+        let stamp = users::stamp(config_dir, &accounts);
+        if stamp == self.user_stamp {
+            return;
+        }
+        match load_user_rules(&self.config, self.profile.as_deref()) {
+            Ok((user_rules, user_stamp)) => {
+                info!("Loaded {} user rules", user_rules.len());
+                self.user_rules = user_rules;
+                self.user_stamp = user_stamp;
+                self.active_rules = active_rules(&self.rules, &self.user_rules, &self.power);
+            }
+            Err(e) => {
+                warn!("Could not reload user rules: {:#}", e);
+                self.user_stamp = stamp;
+            }
+        }
+    }
+
+    /// Adjust adaptive cgroups according to current pressure.
+    fn adapt(&mut self) {
+        for cgroup in self.cgroups.values_mut() {
+            let throttle = self.throttles.entry(cgroup.name().to_string()).or_default();
+            psi::adapt(cgroup, throttle, self.cgroup_fs.proc_root());
+        }
+    }
+
+    /// Recompute CPU quotas of groups whose usable CPUs changed.
+    fn refresh_cpus(&mut self) {
+        for cgroup in self.cgroups.values_mut() {
+            if let Err(e) = cgroup.refresh_cpus() {
+                warn!("Could not update CPU quota of cgroup {}: {:#}", cgroup.name(), e);
+            }
+        }
+    }
+
+    /// Report cgroups whose `TasksMax` refused new tasks since the last scan.
+    fn check_tasks_limits(&mut self) {
+        for cgroup in self.cgroups.values_mut() {
+            if let Some((count, new)) = cgroup.check_pids_events() {
+                if new > 0 {
+                    warn!("Cgroup {} hit its TasksMax limit {} times ({} in total)", cgroup.name(), new, count);
+                }
+                METRICS.set_cgroup_pids_max_events(cgroup.name(), count);
+            }
+        }
+    }
+
+    /// Freeze the cgroups requested by their definition, a matching rule or the
+    /// control socket, and thaw all others.
+    fn update_freezer(&mut self) {
+        for cgroup in self.cgroups.values_mut() {
+            let name = cgroup.name().to_string();
+            let frozen = cgroup.def().freeze == Some(true)
+                || self.frozen.contains(&name)
+                || self.rule_frozen.contains(&name);
+            if frozen != cgroup.frozen() {
+                info!("{} cgroup {}", if frozen { "Freezing" } else { "Thawing" }, name);
+                if let Err(e) = cgroup.set_frozen(frozen) {
+                    warn!("Could not update freezer of cgroup {}: {:#}", name, e);
+                }
+            }
+            METRICS.set_cgroup_frozen(&name, cgroup.frozen());
+        }
+    }
+
+    /// Resume every frozen cgroup, whatever froze it.
+    fn thaw_all(&mut self) {
+        for cgroup in self.cgroups.values_mut().filter(|cgroup| cgroup.frozen()) {
+            if let Err(e) = cgroup.set_frozen(false) {
+                warn!("Could not thaw cgroup {}: {:#}", cgroup.name(), e);
+            }
+        }
+    }
+
+    /// One pass of the main loop: refresh the environment, apply the rules to
+    /// all processes and update the freezer.
+    fn tick(&mut self) {
+        self.refresh_power();
+        self.refresh_users();
+        self.refresh_cpus();
+        self.adapt();
+        self.check_tasks_limits();
+        match process::scan_and_apply_rules(&self.active_rules, &self.cgroups, &*self.source, &*self.actuator) {
+            Ok(summary) => {
+                if self.config.verbose {
+                    info!("Applied rules to {} processes", summary.applied);
+                }
+                self.rule_frozen = summary.freeze;
+            }
+            Err(e) => error!("Error applying rules: {}", e),
+        }
+        self.update_freezer();
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let result = self.switch(self.profile.clone());
+        METRICS.record_reload(result.is_ok());
+        result
+    }
+
+    fn handle(&mut self, command: Command) -> Result<String> {
+        match command {
+            Command::ProfileGet => Ok(self.profile.clone().unwrap_or_else(|| "none".to_string())),
+            Command::ProfileSet(profile) => {
+                self.switch(profile)?;
+                profile::save_active_profile(Path::new(&self.config.state_dir), self.profile.as_deref())
+                    .context("profile switched but could not be persisted")?;
+                Ok(format!("active profile: {}", self.profile.as_deref().unwrap_or("none")))
+            }
+            Command::Reload => {
+                self.reload()?;
+                Ok("reloaded".to_string())
+            }
+            Command::Freeze(name) => {
+                if !self.cgroups.contains_key(&name) {
+                    bail!("unknown cgroup '{}'", name);
+                }
+                self.frozen.insert(name.clone());
+                self.update_freezer();
+                match self.cgroups.get(&name) {
+                    Some(cgroup) if cgroup.frozen() => Ok(format!("froze {}", name)),
+                    _ => bail!("could not freeze {}", name),
+                }
+            }
+            Command::Thaw(name) => {
+                if !self.cgroups.contains_key(&name) {
+                    bail!("unknown cgroup '{}'", name);
+                }
+                self.frozen.remove(&name);
+                self.update_freezer();
+                match self.cgroups.get(&name) {
+                    Some(cgroup) if cgroup.frozen() => Ok(format!("{} stays frozen by its definition or a running rule match", name)),
+                    _ => Ok(format!("thawed {}", name)),
+                }
+            }
+        }
+    }
+}
+
+fn remove_stale_cgroups(state_dir: &Path, cgroups: &HashMap<String, CgroupController>, cgroup_fs: &CgroupFs) {
+    if let Err(e) = cgroup::remove_stale(state_dir, cgroups, cgroup_fs) {
+        warn!("Could not clean up stale cgroups: {:#}", e);
+    }
+}
+
+fn active_rules(rules: &[Rule], user_rules: &[Rule], power: &PowerState) -> Matcher {
+    Matcher::new(rules.iter().chain(user_rules).filter(|rule| rule.is_active(power)).cloned().collect())
+}
+
+pub async fn run(config: Config) -> Result<()> {
+    let mut interval = time::interval(Duration::from_secs(config.check_freq as u64));
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let (control_tx, mut control_rx) = mpsc::channel::<Request>(8);
+
+    if config.check_disks_schedulers {
+        utils::check_disk_schedulers();
+    }
+
+    if let Some(listen) = config.metrics_listen.clone() {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listen).await {
+                error!("Metrics listener failed: {}", e);
+            }
+        });
+    }
+
+    let control_socket = PathBuf::from(&config.control_socket);
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(&control_socket, control_tx).await {
+            error!("Control socket failed: {}", e);
+        }
+    });
+
+    let mut daemon = Daemon::new(config)?;
+
+    // Notify systemd we're ready
+    if std::env::var("NOTIFY_SOCKET").is_ok() {
+        std::process::Command::new("systemd-notify")
+            .arg("--ready")
+            .spawn()?;
+    }
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            _ = sighup.recv() => {
+                info!("Reloading configuration");
+                if let Err(e) = daemon.reload() {
+                    warn!("Reload failed, keeping previous configuration: {}", e);
+                }
+                continue;
+            }
+            Some(request) = control_rx.recv() => {
+                let result = daemon.handle(request.command).map_err(|e| format!("{:#}", e));
+                if let Err(ref e) = result {
+                    warn!("Control command failed: {}", e);
+                }
+                let _ = request.reply.send(result);
+                continue;
+            }
+        }
+
+        daemon.tick();
+    }
+
+    daemon.thaw_all();
+
+    info!("Shutting down, removing cgroups");
+    let cleanup = cgroup::remove_all(Path::new(&daemon.config.state_dir), &daemon.cgroups, &daemon.cgroup_fs)?;
+    info!("Removed {} cgroups, {} could not be removed", cleanup.removed.len(), cleanup.kept.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use crate::fake::{Action, FakeProcesses, RecordingActuator};
+    use crate::process::ProcessInfo;
+
+    const RULES: &str = r#"
+{"name": "game", "nice": -5, "cgroup": "games"}
+{"name": "updater", "ioclass": "idle", "oom_score_adj": 500, "freeze_cgroups": ["background"]}
+"#;
+
+    const CGROUPS: &str = r#"
+{"cgroup": "games", "CPUQuotaTotal": 50}
+{"cgroup": "background"}
+"#;
+
+    /// A daemon on a fake unified hierarchy, with its process table and
+    /// actuator handles.
+    fn daemon() -> (TempDir, Daemon, FakeProcesses, RecordingActuator) {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = |name: &str| tmp.path().join(name);
+
+        let service = path("cgroup/ananicy.service");
+        fs::create_dir_all(&service).unwrap();
+        for dir in [path("cgroup"), service] {
+            fs::write(dir.join("cgroup.controllers"), "cpuset cpu io memory pids").unwrap();
+            fs::write(dir.join("cgroup.subtree_control"), "").unwrap();
+        }
+        fs::write(path("cgroup/cpuset.cpus.effective"), "0-3").unwrap();
+        fs::create_dir_all(path("proc/self")).unwrap();
+        fs::write(path("proc/self/cgroup"), "0::/ananicy.service\n").unwrap();
+        fs::create_dir_all(path("etc")).unwrap();
+        fs::write(path("etc/test.rules"), RULES).unwrap();
+        fs::write(path("etc/test.cgroups"), CGROUPS).unwrap();
+
+        let text = |name: &str| path(name).to_string_lossy().into_owned();
+        let config = Config {
+            verbose: false,
+            config_dir: text("etc"),
+            state_dir: text("state"),
+            power_supply_root: text("power_supply"),
+            cgroup_backend: "cgroupfs".to_string(),
+            cgroup_root: Some(text("cgroup")),
+            proc_root: text("proc"),
+            sys_root: text("sys"),
+            user_runtime_root: text("run/user"),
+            ..Config::default()
+        };
+        let processes = FakeProcesses::default();
+        let actuator = RecordingActuator::new();
+        let daemon = Daemon::with_io(config, Box::new(processes.clone()), Box::new(actuator.clone())).unwrap();
+        (tmp, daemon, processes, actuator)
+    }
+
+    fn process(pid: i32, name: &str) -> ProcessInfo {
+        ProcessInfo { pid, name: name.to_string(), ..ProcessInfo::default() }
+    }
+
+    fn frozen(tmp: &TempDir, cgroup: &str) -> bool {
+        let path = tmp.path().join("cgroup/ananicy.service").join(cgroup).join("cgroup.freeze");
+        fs::read_to_string(path).unwrap_or_default().trim() == "1"
+    }
+
+    #[test]
+    fn tick_applies_matching_rules() {
+        let (_tmp, mut daemon, processes, actuator) = daemon();
+        processes.spawn(process(10, "game"));
+        processes.spawn(process(11, "shell"));
+        daemon.tick();
+
+        assert_eq!(actuator.take(), vec![
+            Action::Nice { pid: 10, nice: -5 },
+            Action::Cgroup { pid: 10, cgroup: "games".to_string() },
+        ]);
+
+        // Rules are re-applied on every pass until the process exits
+        daemon.tick();
+        assert_eq!(actuator.take().len(), 2);
+        processes.exit(10);
+        daemon.tick();
+        assert!(actuator.take().is_empty());
+    }
+
+    #[test]
+    fn failed_action_stops_rule_but_not_scan() {
+        let (_tmp, mut daemon, processes, actuator) = daemon();
+        actuator.fail("nice", libc::EPERM);
+        processes.spawn(process(10, "game"));
+        processes.spawn(process(20, "updater"));
+        daemon.tick();
+
+        assert!(actuator.actions_for(10).is_empty());
+        assert_eq!(actuator.actions_for(20), vec![
+            Action::IoPrio { pid: 20, class: Some("idle".to_string()), level: None },
+            Action::OomScoreAdj { pid: 20, score: 500 },
+        ]);
+    }
+
+    #[test]
+    fn rule_match_freezes_cgroup_while_process_runs() {
+        let (tmp, mut daemon, processes, _actuator) = daemon();
+        daemon.tick();
+        assert!(!frozen(&tmp, "background"));
+
+        processes.spawn(process(20, "updater"));
+        daemon.tick();
+        assert!(frozen(&tmp, "background"));
+        assert!(!frozen(&tmp, "games"));
+
+        processes.exit(20);
+        daemon.tick();
+        assert!(!frozen(&tmp, "background"));
+    }
+}
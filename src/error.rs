@@ -0,0 +1,70 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors of the configuration, rule and CPU set APIs.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// A configuration file or directory could not be read.
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// `ananicy.toml` is not valid TOML or has values of the wrong type.
+    #[error("{}: {source}", path.display())]
+    Toml {
+        path: PathBuf,
+        #[source]
+        source: Box<toml::de::Error>,
+    },
+
+    /// A setting in `ananicy.conf` has a value of the wrong form.
+    #[error("invalid value '{value}' for {key}")]
+    Setting { key: String, value: String },
+
+    /// A rule or type property is outside its valid range.
+    #[error("{field} must be between {min} and {max}, got {value}")]
+    OutOfRange {
+        field: &'static str,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+
+    /// A rule or type property that is not one of a fixed set of values.
+    #[error("{field} must be one of {}, got '{value}'", allowed.join(", "))]
+    Choice {
+        field: &'static str,
+        value: String,
+        allowed: &'static [&'static str],
+    },
+
+    /// A type that cannot be resolved through its `extends` chain.
+    #[error("type '{name}': {reason}")]
+    Type { name: String, reason: String },
+
+    /// A process could not be read from procfs.
+    #[error("process {pid}: {source}")]
+    Process {
+        pid: i32,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A CPU list or symbolic CPU set that cannot be used.
+    #[error("invalid CPU set '{spec}': {reason}")]
+    CpuSet { spec: String, reason: String },
+}
+
+impl Error {
+    /// Wrap an I/O error with the path it occurred on.
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.into();
+        move |source| Error::Io { path, source }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
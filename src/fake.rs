@@ -0,0 +1,157 @@
+//! In-memory stand-ins for `/proc` and the actions applied to processes, for
+//! exercising rule matching and the daemon loop without root.
+//!
+//! Both fakes are cheap handles to shared state: keep a clone to change the
+//! process table or inspect recorded actions after handing one to the code
+//! under test.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::actuator::{ActionError, Actuator, CgroupHandle};
+use crate::process::{ProcessInfo, ProcessSource};
+use crate::topology::CpuSet;
+
+/// A process table that only changes when told to.
+#[derive(Debug, Clone, Default)]
+pub struct FakeProcesses {
+    processes: Arc<Mutex<Vec<ProcessInfo>>>,
+}
+
+impl FakeProcesses {
+    pub fn new(processes: impl IntoIterator<Item = ProcessInfo>) -> Self {
+        let fake = Self::default();
+        for process in processes {
+            fake.spawn(process);
+        }
+        fake
+    }
+
+    /// Add a process, replacing any process with the same pid.
+    pub fn spawn(&self, process: ProcessInfo) {
+        let mut processes = lock(&self.processes);
+        processes.retain(|existing| existing.pid != process.pid);
+        processes.push(process);
+    }
+
+    pub fn exit(&self, pid: i32) {
+        lock(&self.processes).retain(|process| process.pid != pid);
+    }
+}
+
+impl ProcessSource for FakeProcesses {
+    fn processes(&self) -> io::Result<Vec<ProcessInfo>> {
+        Ok(lock(&self.processes).clone())
+    }
+}
+
+/// An action performed through an [`Actuator`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Nice { pid: i32, nice: i32 },
+    IoPrio { pid: i32, class: Option<String>, level: Option<i32> },
+    Scheduler { pid: i32, policy: String, rtprio: Option<i32> },
+    OomScoreAdj { pid: i32, score: i32 },
+    CpuAffinity { pid: i32, cpus: String },
+    Cgroup { pid: i32, cgroup: String },
+}
+
+impl Action {
+    pub fn pid(&self) -> i32 {
+        match *self {
+            Action::Nice { pid, .. }
+            | Action::IoPrio { pid, .. }
+            | Action::Scheduler { pid, .. }
+            | Action::OomScoreAdj { pid, .. }
+            | Action::CpuAffinity { pid, .. }
+            | Action::Cgroup { pid, .. } => pid,
+        }
+    }
+
+    /// The action name used in errors and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Nice { .. } => "nice",
+            Action::IoPrio { .. } => "ionice",
+            Action::Scheduler { .. } => "sched",
+            Action::OomScoreAdj { .. } => "oom_score_adj",
+            Action::CpuAffinity { .. } => "cpu_affinity",
+            Action::Cgroup { .. } => "cgroup",
+        }
+    }
+}
+
+/// Records actions instead of performing them.
+///
+/// Actions can be made to fail with an OS error, by name as in
+/// [`Action::name`], to simulate missing privileges or exited processes.
+/// Failed actions are not recorded.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingActuator {
+    actions: Arc<Mutex<Vec<Action>>>,
+    failures: Arc<Mutex<HashMap<&'static str, i32>>>,
+}
+
+impl RecordingActuator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make every later `action` fail with `errno`.
+    pub fn fail(&self, action: &'static str, errno: i32) {
+        lock(&self.failures).insert(action, errno);
+    }
+
+    /// All actions recorded so far, oldest first.
+    pub fn actions(&self) -> Vec<Action> {
+        lock(&self.actions).clone()
+    }
+
+    /// Recorded actions for `pid`, oldest first.
+    pub fn actions_for(&self, pid: i32) -> Vec<Action> {
+        lock(&self.actions).iter().filter(|action| action.pid() == pid).cloned().collect()
+    }
+
+    /// Return and forget all recorded actions.
+    pub fn take(&self) -> Vec<Action> {
+        std::mem::take(&mut *lock(&self.actions))
+    }
+
+    fn record(&self, action: Action) -> Result<(), ActionError> {
+        if let Some(&errno) = lock(&self.failures).get(action.name()) {
+            return Err(io::Error::from_raw_os_error(errno).into());
+        }
+        lock(&self.actions).push(action);
+        Ok(())
+    }
+}
+
+impl Actuator for RecordingActuator {
+    fn set_nice(&self, pid: i32, nice: i32) -> Result<(), ActionError> {
+        self.record(Action::Nice { pid, nice })
+    }
+
+    fn set_ioprio(&self, pid: i32, class: Option<&str>, level: Option<i32>) -> Result<(), ActionError> {
+        self.record(Action::IoPrio { pid, class: class.map(str::to_string), level })
+    }
+
+    fn set_scheduler(&self, pid: i32, policy: &str, rtprio: Option<i32>) -> Result<(), ActionError> {
+        self.record(Action::Scheduler { pid, policy: policy.to_string(), rtprio })
+    }
+
+    fn set_oom_score_adj(&self, pid: i32, score: i32) -> Result<(), ActionError> {
+        self.record(Action::OomScoreAdj { pid, score })
+    }
+
+    fn set_cpu_affinity(&self, pid: i32, cpus: &CpuSet) -> Result<(), ActionError> {
+        self.record(Action::CpuAffinity { pid, cpus: cpus.to_string() })
+    }
+
+    fn add_to_cgroup(&self, pid: i32, cgroup: &dyn CgroupHandle) -> Result<(), ActionError> {
+        self.record(Action::Cgroup { pid, cgroup: cgroup.name().to_string() })
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
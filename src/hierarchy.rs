@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CGROUP_FS: &str = "/sys/fs/cgroup";
+const PROC_FS: &str = "/proc";
+const SYS_FS: &str = "/sys";
+
+/// v1 controllers the daemon manages groups in.
+pub const V1_CONTROLLERS: [&str; 7] = ["cpu", "cpuacct", "blkio", "memory", "cpuset", "pids", "freezer"];
+
+/// How cgroups are mounted on this host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Only the v2 hierarchy.
+    Unified,
+    /// v1 controllers plus an empty v2 hierarchy used by systemd for tracking.
+    Hybrid,
+    /// Only v1 hierarchies.
+    Legacy,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Hierarchies {
+    /// Mount point of each v1 controller; co-mounted controllers share one.
+    pub v1: BTreeMap<String, PathBuf>,
+    pub unified: Option<PathBuf>,
+}
+
+/// Location of procfs, sysfs and the cgroup hierarchies, injectable for tests and containers.
+#[derive(Debug, Clone)]
+pub struct CgroupFs {
+    proc_root: PathBuf,
+    sys_root: PathBuf,
+    hierarchies: Hierarchies,
+}
+
+impl Default for CgroupFs {
+    fn default() -> Self {
+        Self::detect(Path::new(PROC_FS), None)
+    }
+}
+
+impl CgroupFs {
+    /// Use the hierarchies below `cgroup_root`, or the ones mounted according to
+    /// `<proc_root>/self/mountinfo` when it is not given.
+    pub fn detect(proc_root: &Path, cgroup_root: Option<&Path>) -> Self {
+        let hierarchies = match cgroup_root {
+            Some(root) => Hierarchies::scan(root),
+            None => fs::read_to_string(proc_root.join("self/mountinfo"))
+                .map(|content| Hierarchies::parse_mountinfo(&content))
+                .unwrap_or_default(),
+        };
+        Self { proc_root: proc_root.to_path_buf(), sys_root: PathBuf::from(SYS_FS), hierarchies }
+    }
+
+    /// Read block devices from the sysfs mounted at `sys_root` instead of `/sys`.
+    pub fn with_sys_root(mut self, sys_root: &Path) -> Self {
+        self.sys_root = sys_root.to_path_buf();
+        self
+    }
+
+    pub fn proc_root(&self) -> &Path {
+        &self.proc_root
+    }
+
+    pub fn sys_root(&self) -> &Path {
+        &self.sys_root
+    }
+
+    /// `/proc/<pid>` below the configured procfs root.
+    pub fn proc_dir(&self, pid: i32) -> PathBuf {
+        self.proc_root.join(pid.to_string())
+    }
+
+    pub fn hierarchies(&self) -> &Hierarchies {
+        &self.hierarchies
+    }
+}
+
+impl Hierarchies {
+    /// Detect the layout of a directory holding cgroup mounts, such as `/sys/fs/cgroup`.
+    pub fn scan(root: &Path) -> Self {
+        let mut hierarchies = Self::default();
+        if root.join("cgroup.controllers").exists() {
+            hierarchies.unified = Some(root.to_path_buf());
+            return hierarchies;
+        }
+
+        let mut entries: Vec<_> = fs::read_dir(root).into_iter().flatten()
+            .filter_map(Result::ok)
+            // `cpu` -> `cpu,cpuacct` style links would duplicate the hierarchy
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+            .collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name == "unified" {
+                hierarchies.unified = Some(entry.path());
+                continue;
+            }
+            for controller in name.split(',') {
+                if V1_CONTROLLERS.contains(&controller) {
+                    hierarchies.v1.entry(controller.to_string()).or_insert_with(|| entry.path());
+                }
+            }
+        }
+        hierarchies
+    }
+
+    /// Collect cgroup mounts from the contents of `/proc/self/mountinfo`.
+    pub fn parse_mountinfo(content: &str) -> Self {
+        let mut hierarchies = Self::default();
+
+        for line in content.lines() {
+            // <id> <parent> <dev> <root> <mount point> <options> [optional...] - <type> <source> <super options>
+            let (mount, filesystem) = match line.split_once(" - ") {
+                Some(parts) => parts,
+                None => continue,
+            };
+            let mount_point = match mount.split_whitespace().nth(4) {
+                Some(mount_point) => PathBuf::from(unescape(mount_point)),
+                None => continue,
+            };
+            let mut filesystem = filesystem.split_whitespace();
+            let fstype = filesystem.next().unwrap_or_default();
+            let options = filesystem.nth(1).unwrap_or_default();
+
+            match fstype {
+                "cgroup2" => {
+                    hierarchies.unified.get_or_insert(mount_point);
+                }
+                "cgroup" => {
+                    for option in options.split(',') {
+                        if V1_CONTROLLERS.contains(&option) {
+                            hierarchies.v1.entry(option.to_string()).or_insert_with(|| mount_point.clone());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        hierarchies
+    }
+
+    pub fn mode(&self) -> Mode {
+        match (&self.unified, self.v1.is_empty()) {
+            (Some(_), true) => Mode::Unified,
+            (Some(_), false) => Mode::Hybrid,
+            (None, _) => Mode::Legacy,
+        }
+    }
+
+    pub fn v1_mount(&self, controller: &str) -> Option<&Path> {
+        self.v1.get(controller).map(PathBuf::as_path)
+    }
+
+    /// Distinct mount points of the managed v1 controllers.
+    pub fn v1_mounts(&self) -> Vec<&Path> {
+        let mut mounts: Vec<&Path> = self.v1.values().map(PathBuf::as_path).collect();
+        mounts.sort();
+        mounts.dedup();
+        mounts
+    }
+
+    pub fn unified_mount(&self) -> &Path {
+        self.unified.as_deref().unwrap_or(Path::new(CGROUP_FS))
+    }
+}
+
+/// Undo the octal escapes (`\040` for a space) used in mountinfo.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(u8::is_ascii_digit) {
+            if let Ok(byte) = u8::from_str_radix(&field[i + 1..i + 4], 8) {
+                out.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HYBRID: &str = "\
+32 24 0:28 / /sys/fs/cgroup ro,nosuid - tmpfs tmpfs ro,mode=755
+33 32 0:29 / /sys/fs/cgroup/unified rw,nosuid - cgroup2 cgroup2 rw,nsdelegate
+34 32 0:30 / /sys/fs/cgroup/systemd rw,nosuid - cgroup cgroup rw,xattr,name=systemd
+35 32 0:31 / /sys/fs/cgroup/cpu,cpuacct rw,nosuid shared:9 - cgroup cgroup rw,cpu,cpuacct
+36 32 0:32 / /sys/fs/cgroup/memory rw,nosuid - cgroup cgroup rw,memory
+37 32 0:33 / /mnt/my\\040cgroups rw,nosuid - cgroup cgroup rw,pids
+";
+
+    #[test]
+    fn mountinfo_hybrid_layout() {
+        let hierarchies = Hierarchies::parse_mountinfo(HYBRID);
+        assert_eq!(hierarchies.mode(), Mode::Hybrid);
+        assert_eq!(hierarchies.unified.as_deref(), Some(Path::new("/sys/fs/cgroup/unified")));
+        assert_eq!(hierarchies.v1_mount("cpu"), Some(Path::new("/sys/fs/cgroup/cpu,cpuacct")));
+        assert_eq!(hierarchies.v1_mount("cpuacct"), hierarchies.v1_mount("cpu"));
+        assert_eq!(hierarchies.v1_mount("pids"), Some(Path::new("/mnt/my cgroups")));
+        assert_eq!(hierarchies.v1_mount("blkio"), None);
+        assert_eq!(hierarchies.v1_mounts().len(), 3);
+    }
+
+    #[test]
+    fn mountinfo_unified_and_legacy() {
+        let unified = "30 24 0:26 / /sys/fs/cgroup rw,nosuid - cgroup2 cgroup2 rw,nsdelegate\n";
+        assert_eq!(Hierarchies::parse_mountinfo(unified).mode(), Mode::Unified);
+
+        let legacy = "35 32 0:31 / /sys/fs/cgroup/cpu rw - cgroup cgroup rw,cpu\n";
+        assert_eq!(Hierarchies::parse_mountinfo(legacy).mode(), Mode::Legacy);
+    }
+}
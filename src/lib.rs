@@ -0,0 +1,60 @@
+//! Process tuning rules of rust-ananicy as a library: loading the configuration,
+//! rules and types, matching rules against processes and applying them.
+//!
+//! ```no_run
+//! use std::path::{Path, PathBuf};
+//! use rust_ananicy::{apply_rule, Matcher, ProcessInfo, SystemActuator};
+//!
+//! let rules = rust_ananicy::load_all_rules(&[PathBuf::from("/etc/ananicy.d")])?;
+//! let matcher = Matcher::new(rules);
+//! let process = ProcessInfo::new(Path::new("/proc"), 4242)?;
+//! if let Some(rule) = matcher.find(&process.view()) {
+//!     // No cgroups to move the process into
+//!     apply_rule(&SystemActuator::new("/proc"), &process, rule, &())?;
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Processes are read through a [`ProcessSource`] and changed through an
+//! [`Actuator`]; the [`fake`] module has in-memory versions of both for tests.
+//!
+//! Only the items re-exported here and the modules they live in are a stable
+//! API; the hidden modules hold the daemon and may change between releases.
+
+pub mod actuator;
+pub mod config;
+pub mod error;
+pub mod fake;
+pub mod matcher;
+pub mod power;
+pub mod process;
+pub mod rules;
+pub mod topology;
+
+#[doc(hidden)]
+pub mod cgroup;
+#[doc(hidden)]
+pub mod compat;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod daemon;
+#[doc(hidden)]
+pub mod profile;
+#[doc(hidden)]
+pub mod schema;
+
+mod hierarchy;
+mod metrics;
+mod psi;
+mod systemd;
+mod toml_rules;
+mod users;
+mod utils;
+
+pub use actuator::{apply_rule, ActionError, Actuator, ApplyError, CgroupHandle, CgroupLookup, SystemActuator};
+pub use config::Config;
+pub use error::{Error, Result};
+pub use matcher::{MatchExpr, Matcher, ProcessView};
+pub use process::{ProcFs, ProcessInfo, ProcessSource};
+pub use rules::{load_all_rules, load_rules, load_types, resolve_types, Rule, Type, TypeRef};
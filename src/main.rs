@@ -1,15 +1,8 @@
-mod config;
-mod process;
-mod rules;
-mod cgroup;
-mod utils;
-
-use std::time::Duration;
 use std::path::Path;
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use log::{info, error};
-use tokio::time;
+use log::info;
+use rust_ananicy::{cgroup, compat, config, control, daemon, power, process, profile, rules, schema, topology};
 
 #[derive(Parser)]
 #[command(name = "rust-ananicy")]
@@ -37,6 +30,56 @@ enum Commands {
         #[command(subcommand)]
         target: DumpTarget,
     },
+    
+    /// Manage rule profiles
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+    
+    /// Stop all processes of a cgroup until it is thawed
+    Freeze {
+        cgroup: String,
+    },
+    
+    /// Resume a cgroup stopped with freeze
+    Thaw {
+        cgroup: String,
+    },
+    
+    /// Convert an ananicy or ananicy-cpp rule directory into the config directory
+    Import {
+        dir: String,
+        /// Write to this directory instead of the config directory
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Overwrite existing files
+        #[arg(long)]
+        force: bool,
+    },
+    
+    /// Print the JSON Schema of entries in .rules, .types or .cgroups files
+    Schema {
+        #[arg(value_parser = ["rules", "types", "cgroups"])]
+        kind: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ProfileAction {
+    /// List available profiles
+    List,
+    /// Show the active profile
+    Get,
+    /// Switch the active profile ("none" for base rules only)
+    Set {
+        name: String,
+    },
+    /// Show differences between two profiles ("none" for base rules only)
+    Diff {
+        from: String,
+        to: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -44,11 +87,19 @@ enum DumpTarget {
     /// Show loaded rules
     Rules,
     /// Show loaded types
-    Types,
+    Types {
+        /// Show each type with the attributes it inherits through `extends`
+        #[arg(long)]
+        resolved: bool,
+    },
     /// Show cgroups
     Cgroups,
     /// Show processes
     Proc,
+    /// Show the detected power source
+    Power,
+    /// Show the detected CPU topology
+    Cpus,
 }
 
 #[tokio::main]
@@ -58,64 +109,164 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     
     let config = config::Config::load(Path::new(&cli.config_dir))?;
+    topology::use_sys_root(Path::new(&config.sys_root));
+    let config_dir = Path::new(&cli.config_dir);
+    let state_dir = Path::new(&config.state_dir);
+    let control_socket = Path::new(&config.control_socket);
+    let active = profile::active_profile(state_dir);
     
     match cli.command {
         Commands::Start => {
             info!("Starting Rust Ananicy daemon");
-            run_daemon(config).await?;
+            daemon::run(config).await?;
         }
         Commands::Dump { target } => {
+            let layers = profile::layers(config_dir, active.as_deref())?;
             match target {
                 DumpTarget::Rules => {
-                    let rules = rules::load_rules(Path::new(&cli.config_dir))?;
+                    let rules = rules::load_rules(&layers)?;
                     println!("{}", serde_json::to_string_pretty(&rules)?);
                 }
-                DumpTarget::Types => {
-                    let types = rules::load_types(Path::new(&cli.config_dir))?;
+                DumpTarget::Types { resolved } => {
+                    let all = rules::load_types(&layers)?;
+                    let types: std::collections::BTreeMap<_, _> = if resolved {
+                        all.keys()
+                            .filter_map(|name| match rules::resolve_type(&all, name) {
+                                Ok(type_def) => Some((name.clone(), type_def)),
+                                Err(e) => {
+                                    eprintln!("Skipping {}", e);
+                                    None
+                                }
+                            })
+                            .collect()
+                    } else {
+                        all.into_iter().collect()
+                    };
                     println!("{}", serde_json::to_string_pretty(&types)?);
                 }
                 DumpTarget::Cgroups => {
-                    let cgroups = cgroup::load_cgroups(Path::new(&cli.config_dir))?;
-                    for (name, cg) in cgroups {
-                        println!("Cgroup: {} - CPU Quota: {}%", name, cg.cpu_quota());
+                    let power = power::read_power_state(Path::new(&config.power_supply_root));
+                    let cgroup_fs = std::sync::Arc::new(config.cgroup_fs());
+                    let backend = cgroup::Backend::select(&config.cgroup_backend, &cgroup_fs)?;
+                    let inspection = cgroup::inspect_cgroups(&layers, Path::new(&config.state_dir), power.source, &backend, &cgroup_fs)?;
+                    let hierarchies = cgroup_fs.hierarchies();
+                    println!("Cgroup mode: {:?} (v1 controllers: {}; unified: {})",
+                             hierarchies.mode(),
+                             hierarchies.v1.keys().cloned().collect::<Vec<_>>().join(","),
+                             hierarchies.unified.as_deref().map_or("none".into(), |p| p.display().to_string()));
+                    for cg in inspection.cgroups {
+                        println!("Cgroup: {} - {} on {} CPUs (quota {}, shares {}, {} members) [{}]",
+                                 cg.rel_path().display(), cg.cpu_quota(), cg.cpus(),
+                                 cg.quota_us().map_or("max".to_string(), |us| format!("{}us", us)),
+                                 cg.cpu_shares(), cg.pid_count(), cg.reconciled());
+                        if let Some(usage) = cg.cpu_usage() {
+                            println!("  CPU time: {:.1}s", usage.as_secs_f64());
+                        }
+                        if let Some(tasks_max) = cg.tasks_max() {
+                            println!("  Tasks max: {} (limit hit {} times)", tasks_max, cg.pids_max_events());
+                        }
+                        if cg.frozen() {
+                            println!("  Frozen");
+                        }
+                    }
+                    for rel in inspection.missing {
+                        println!("Cgroup: {} [not created]", rel.display());
+                    }
+                    for (rel, procs) in inspection.stale {
+                        println!("Stale: {} [{} processes]", rel.display(), procs);
+                    }
+                }
+                DumpTarget::Power => {
+                    let power = power::read_power_state(Path::new(&config.power_supply_root));
+                    match power.capacity {
+                        Some(capacity) => println!("Power: {:?} - Battery: {}%", power.source, capacity),
+                        None => println!("Power: {:?}", power.source),
                     }
                 }
+                DumpTarget::Cpus => {
+                    let topology = &*topology::TOPOLOGY;
+                    println!("online: {}", topology.online);
+                    println!("performance: {}", topology.performance);
+                    println!("efficiency: {}", topology.efficiency);
+                    println!("vcache: {}", topology.vcache);
+                }
                 DumpTarget::Proc => {
-                    let processes = process::scan_processes()?;
+                    let processes = process::scan_processes(Path::new(&config.proc_root))?;
                     for proc in processes {
                         println!("PID: {} - Name: {} - Nice: {}", 
-                                 proc.pid(), proc.name(), proc.nice());
+                                 proc.pid, proc.name, proc.nice);
                     }
                 }
             }
         }
-    }
-    
-    Ok(())
-}
-
-async fn run_daemon(config: config::Config) -> Result<()> {
-    let mut interval = time::interval(Duration::from_secs(config.check_freq as u64));
-    let rules = rules::load_all_rules(Path::new(&config.config_dir))?;
-    let cgroups = cgroup::load_cgroups(Path::new(&config.config_dir))?;
-    
-    // Notify systemd we're ready
-    if std::env::var("NOTIFY_SOCKET").is_ok() {
-        std::process::Command::new("systemd-notify")
-            .arg("--ready")
-            .spawn()?;
-    }
-    
-    loop {
-        interval.tick().await;
-        
-        match process::scan_and_apply_rules(&rules, &cgroups) {
-            Ok(applied) => {
-                if config.verbose {
-                    info!("Applied rules to {} processes", applied);
+        Commands::Profile { action } => {
+            match action {
+                ProfileAction::List => {
+                    for name in profile::list_profiles(config_dir)? {
+                        let marker = if active.as_deref() == Some(name.as_str()) { "*" } else { " " };
+                        println!("{} {}", marker, name);
+                    }
+                }
+                ProfileAction::Get => {
+                    match control::send(control_socket, &control::Command::ProfileGet) {
+                        Ok(name) => println!("{}", name),
+                        Err(_) => println!("{}", active.as_deref().unwrap_or("none")),
+                    }
+                }
+                ProfileAction::Set { name } => {
+                    let name = profile::parse_name(&name);
+                    if let Some(ref name) = name {
+                        profile::profile_dir(config_dir, name)?;
+                    }
+                    let command = control::Command::ProfileSet(name.clone());
+                    if control::is_running(control_socket) {
+                        println!("{}", control::send(control_socket, &command)?);
+                    } else {
+                        // Daemon not running, the profile takes effect on next start
+                        profile::save_active_profile(state_dir, name.as_deref())?;
+                        println!("active profile: {} (daemon not running)",
+                                 name.as_deref().unwrap_or("none"));
+                    }
                 }
+                ProfileAction::Diff { from, to } => {
+                    let from = profile::parse_name(&from);
+                    let to = profile::parse_name(&to);
+                    for line in profile::diff(config_dir, from.as_deref(), to.as_deref())? {
+                        println!("{}", line);
+                    }
+                }
+            }
+        }
+        Commands::Freeze { cgroup } => {
+            println!("{}", control::send(control_socket, &control::Command::Freeze(cgroup))?);
+        }
+        Commands::Thaw { cgroup } => {
+            println!("{}", control::send(control_socket, &control::Command::Thaw(cgroup))?);
+        }
+        Commands::Import { dir, output, force } => {
+            let target = output.as_deref().map_or(config_dir, Path::new);
+            let reports = compat::import_dir(Path::new(&dir), target, force)?;
+            let (mut converted, mut skipped, mut notes) = (0, 0, 0);
+            for report in &reports {
+                println!("{}: {} entries, {} skipped", report.path.display(), report.converted, report.skipped.len());
+                for (line, reason) in &report.skipped {
+                    println!("  line {}: skipped: {}", line, reason);
+                }
+                for (line, note) in &report.notes {
+                    println!("  line {}: {}", line, note);
+                }
+                converted += report.converted;
+                skipped += report.skipped.len();
+                notes += report.notes.len();
             }
-            Err(e) => error!("Error applying rules: {}", e),
+            println!("Imported {} files into {}: {} entries, {} skipped, {} notes",
+                     reports.len(), target.display(), converted, skipped, notes);
+        }
+        Commands::Schema { kind } => {
+            let kind = compat::Kind::from_extension(&kind).expect("kind is checked by clap");
+            println!("{}", serde_json::to_string_pretty(&schema::for_kind(kind))?);
         }
     }
-}
\ No newline at end of file
+    
+    Ok(())
+}
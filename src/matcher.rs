@@ -0,0 +1,368 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use regex::{RegexSet, RegexSetBuilder};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use crate::rules::Rule;
+use crate::utils;
+
+/// The properties of a process that rules are matched against.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessView<'a> {
+    /// Kernel command name (`comm`), truncated to 15 bytes.
+    pub name: &'a str,
+    /// File name of the executable, if it could be read.
+    pub exe: Option<&'a str>,
+    pub cmdline: &'a [String],
+    /// Real user id, if known.
+    pub uid: Option<u32>,
+}
+
+/// A boolean expression over process properties, the `match` object of a rule.
+///
+/// ```json
+/// {"all": [{"name": "java"}, {"cmdline": "minecraft"}, {"not": {"user": "ci"}}]}
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchExpr {
+    /// Every expression holds; true when empty.
+    All(Vec<MatchExpr>),
+    /// At least one expression holds; false when empty.
+    Any(Vec<MatchExpr>),
+    Not(Box<MatchExpr>),
+    /// Command or executable name, like the `name` field.
+    Name(String),
+    /// Executable file name.
+    Exe(String),
+    /// Substring of some command line argument.
+    Cmdline(String),
+    Uid(u32),
+    /// User name or numeric uid; never equal when the user does not exist.
+    User(String),
+}
+
+impl MatchExpr {
+    pub fn matches(&self, process: &ProcessView) -> bool {
+        match self {
+            MatchExpr::All(exprs) => exprs.iter().all(|expr| expr.matches(process)),
+            MatchExpr::Any(exprs) => exprs.iter().any(|expr| expr.matches(process)),
+            MatchExpr::Not(expr) => !expr.matches(process),
+            MatchExpr::Name(name) => process.name == name || process.exe == Some(name.as_str()),
+            MatchExpr::Exe(exe) => process.exe == Some(exe.as_str()),
+            MatchExpr::Cmdline(pattern) => process.cmdline.iter().any(|arg| arg.contains(pattern.as_str())),
+            MatchExpr::Uid(uid) => process.uid == Some(*uid),
+            MatchExpr::User(user) => process.uid.is_some() && process.uid == utils::user_uid(user),
+        }
+    }
+}
+
+impl fmt::Display for MatchExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let list = |f: &mut fmt::Formatter, op: &str, exprs: &[MatchExpr]| {
+            let exprs: Vec<String> = exprs.iter().map(|expr| expr.to_string()).collect();
+            write!(f, "{}({})", op, exprs.join(", "))
+        };
+        match self {
+            MatchExpr::All(exprs) => list(f, "all", exprs),
+            MatchExpr::Any(exprs) => list(f, "any", exprs),
+            MatchExpr::Not(expr) => write!(f, "not({})", expr),
+            MatchExpr::Name(name) => write!(f, "name={}", name),
+            MatchExpr::Exe(exe) => write!(f, "exe={}", exe),
+            MatchExpr::Cmdline(pattern) => write!(f, "cmdline~{}", pattern),
+            MatchExpr::Uid(uid) => write!(f, "uid={}", uid),
+            MatchExpr::User(user) => write!(f, "user={}", user),
+        }
+    }
+}
+
+/// Whether `rule` selects `process`. All criteria the rule sets must hold:
+/// `name` equals the command or executable name, `exe` equals the executable
+/// name, every `cmdlines` pattern occurs in some argument and the `match`
+/// expression is true. A rule without criteria selects every process.
+pub fn matches(rule: &Rule, process: &ProcessView) -> bool {
+    if let Some(ref name) = rule.name {
+        if process.name != name && process.exe != Some(name.as_str()) {
+            return false;
+        }
+    }
+
+    if let Some(ref exe) = rule.exe {
+        if process.exe != Some(exe.as_str()) {
+            return false;
+        }
+    }
+
+    if let Some(ref cmdlines) = rule.cmdlines {
+        let found = cmdlines.iter()
+            .all(|pattern| process.cmdline.iter().any(|arg| arg.contains(pattern.as_str())));
+        if !found {
+            return false;
+        }
+    }
+
+    rule.match_expr.as_ref().is_none_or(|expr| expr.matches(process))
+}
+
+/// The rule to apply to a process, possibly composed from several rules.
+#[derive(Debug, Clone)]
+pub struct Resolved<'a> {
+    pub rule: Cow<'a, Rule>,
+    /// The rules that contributed, highest precedence first.
+    pub sources: Vec<&'a Rule>,
+}
+
+/// Rules compiled for lookup: hash maps on names and a regex set over all
+/// `cmdlines` patterns, so finding the rules for a process does not depend on
+/// the number of rules.
+///
+/// Rules are kept in order of precedence: higher `priority` first, and load
+/// order among rules of equal priority.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    rules: Vec<Rule>,
+    /// Rules with a `name`, by that name.
+    by_name: HashMap<String, Vec<usize>>,
+    /// Rules with an `exe` but no `name`, by executable name.
+    by_exe: HashMap<String, Vec<usize>>,
+    /// Every distinct `cmdlines` pattern, as an escaped literal.
+    patterns: RegexSet,
+    /// Rules selected only by `cmdlines`, with their pattern ids in `patterns`.
+    pattern_rules: Vec<(usize, Vec<usize>)>,
+    /// Rules without flat criteria, which match every process their `match`
+    /// expression accepts.
+    catch_all: Vec<usize>,
+}
+
+impl Matcher {
+    pub fn new(mut rules: Vec<Rule>) -> Self {
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority.unwrap_or(0)));
+
+        let mut by_name: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut by_exe: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut pattern_ids: HashMap<&str, usize> = HashMap::new();
+        let mut pattern_rules = Vec::new();
+        let mut catch_all = Vec::new();
+
+        for (index, rule) in rules.iter().enumerate() {
+            match (&rule.name, &rule.exe, &rule.cmdlines) {
+                (Some(name), _, _) => by_name.entry(name.clone()).or_default().push(index),
+                (None, Some(exe), _) => by_exe.entry(exe.clone()).or_default().push(index),
+                // Arguments never contain NUL, so such a pattern cannot match
+                (None, None, Some(cmdlines)) if cmdlines.iter().any(|pattern| pattern.contains('\0')) => {}
+                (None, None, Some(cmdlines)) if !cmdlines.is_empty() => {
+                    let ids = cmdlines.iter()
+                        .map(|pattern| {
+                            let next = pattern_ids.len();
+                            *pattern_ids.entry(pattern.as_str()).or_insert(next)
+                        })
+                        .collect();
+                    pattern_rules.push((index, ids));
+                }
+                _ => catch_all.push(index),
+            }
+        }
+
+        let mut literals = vec![String::new(); pattern_ids.len()];
+        for (pattern, id) in pattern_ids {
+            literals[id] = regex::escape(pattern);
+        }
+        let patterns = RegexSetBuilder::new(literals)
+            .size_limit(1 << 30)
+            .build()
+            .expect("escaped literals always compile");
+
+        Self { rules, by_name, by_exe, patterns, pattern_rules, catch_all }
+    }
+
+    /// All rules, in order of precedence.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The matching rule of highest precedence, if any.
+    pub fn find(&self, process: &ProcessView) -> Option<&Rule> {
+        self.find_all(process).into_iter().next()
+    }
+
+    /// All rules matching `process`, in order of precedence.
+    pub fn find_all(&self, process: &ProcessView) -> Vec<&Rule> {
+        let mut candidates = self.catch_all.clone();
+        for name in std::iter::once(process.name).chain(process.exe) {
+            candidates.extend(self.by_name.get(name).into_iter().flatten());
+        }
+        if let Some(exe) = process.exe {
+            candidates.extend(self.by_exe.get(exe).into_iter().flatten());
+        }
+
+        if !self.pattern_rules.is_empty() && !process.cmdline.is_empty() {
+            // No pattern contains NUL, so none can match across two arguments
+            let found = self.patterns.matches(&process.cmdline.join("\0"));
+            if found.matched_any() {
+                candidates.extend(self.pattern_rules.iter()
+                    .filter(|(_, ids)| ids.iter().all(|&id| found.matched(id)))
+                    .map(|&(index, _)| index));
+            }
+        }
+
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates.into_iter()
+            .map(|index| &self.rules[index])
+            .filter(|rule| matches(rule, process))
+            .collect()
+    }
+
+    /// The rule to apply to `process`: the matching rule of highest
+    /// precedence, with attributes it leaves unset taken from lower matching
+    /// rules that opted in with `merge`.
+    pub fn resolve(&self, process: &ProcessView) -> Option<Resolved<'_>> {
+        let mut matched = self.find_all(process).into_iter();
+        let first = matched.next()?;
+        let mut resolved = Resolved { rule: Cow::Borrowed(first), sources: vec![first] };
+        for rule in matched.filter(|rule| rule.merge == Some(true)) {
+            if resolved.rule.to_mut().merge_from(rule) {
+                resolved.sources.push(rule);
+            }
+        }
+        Some(resolved)
+    }
+}
+
+impl Default for Matcher {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(json: &str) -> Rule {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn name_and_cmdline_matching() {
+        let cmdline = ["java".to_string(), "-jar".to_string(), "/opt/minecraft/server.jar".to_string()];
+        let process = ProcessView { name: "java", exe: Some("java-17"), cmdline: &cmdline, uid: None };
+
+        assert!(matches(&rule(r#"{"name": "java"}"#), &process));
+        assert!(matches(&rule(r#"{"name": "java-17"}"#), &process));
+        assert!(!matches(&rule(r#"{"name": "javac"}"#), &process));
+        assert!(matches(&rule(r#"{"cmdlines": ["-jar", "minecraft"]}"#), &process));
+        assert!(!matches(&rule(r#"{"cmdlines": ["-jar", "forge"]}"#), &process));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let matcher = Matcher::new(vec![
+            rule(r#"{"cmdlines": ["server.jar"], "nice": 5}"#),
+            rule(r#"{"name": "java", "nice": -5}"#),
+        ]);
+        let cmdline = ["java".to_string(), "server.jar".to_string()];
+        let process = ProcessView { name: "java", exe: None, cmdline: &cmdline, uid: None };
+        assert_eq!(matcher.find(&process).and_then(|rule| rule.nice), Some(5));
+
+        let process = ProcessView { name: "bash", exe: None, cmdline: &[], uid: None };
+        assert!(matcher.find(&process).is_none());
+    }
+
+    #[test]
+    fn index_agrees_with_linear_matching() {
+        let rules = vec![
+            rule(r#"{"name": "java-17"}"#),
+            rule(r#"{"cmdlines": ["-jar", "server"]}"#),
+            rule(r#"{"name": "java"}"#),
+            rule(r#"{"name": "java-17", "nice": 1}"#),
+            rule(r#"{"cmdlines": ["a\u0000b"]}"#),
+            rule(r#"{"cmdlines": ["bin/"]}"#),
+            rule(r#"{"nice": 19}"#),
+            rule(r#"{"name": "late"}"#),
+        ];
+        let matcher = Matcher::new(rules.clone());
+        let linear = |process: &ProcessView| rules.iter().position(|rule| matches(rule, process));
+        let indexed = |process: &ProcessView| {
+            matcher.find(process).map(|found| matcher.rules().iter().position(|rule| std::ptr::eq(rule, found)).unwrap())
+        };
+
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let processes = [
+            ("java", Some("java-17"), args(&["java", "-jar", "server.jar"])),
+            ("java", None, args(&["java", "-jar", "server.jar"])),
+            ("java", None, args(&["java", "-jar"])),
+            ("sh", None, args(&["/usr/bin/sh"])),
+            ("sh", None, args(&["a", "b"])),
+            ("late", None, args(&[])),
+            ("kworker/0:1", None, args(&[])),
+        ];
+        for (name, exe, cmdline) in &processes {
+            let process = ProcessView { name, exe: *exe, cmdline, uid: None };
+            assert_eq!(indexed(&process), linear(&process), "{}", name);
+        }
+    }
+
+    #[test]
+    fn criteria_combine_with_and() {
+        let cmdline = ["python3".to_string(), "-m".to_string(), "http.server".to_string()];
+        let process = ProcessView { name: "python3", exe: Some("python3.12"), cmdline: &cmdline, uid: None };
+
+        assert!(matches(&rule(r#"{"name": "python3", "cmdlines": ["http.server"]}"#), &process));
+        assert!(!matches(&rule(r#"{"name": "python3", "cmdlines": ["pytest"]}"#), &process));
+        assert!(matches(&rule(r#"{"exe": "python3.12", "cmdlines": ["-m"]}"#), &process));
+        assert!(!matches(&rule(r#"{"name": "python3", "exe": "python3.11"}"#), &process));
+    }
+
+    #[test]
+    fn priority_and_merge() {
+        let matcher = Matcher::new(vec![
+            rule(r#"{"name": "firefox", "nice": 5, "ioclass": "idle"}"#),
+            rule(r#"{"cmdlines": ["-contentproc"], "nice": 10, "priority": 10}"#),
+            rule(r#"{"name": "firefox", "cgroup": "browser", "nice": -1, "merge": true}"#),
+            rule(r#"{"name": "firefox", "oom_score_adj": 100}"#),
+        ]);
+        let cmdline = ["firefox".to_string(), "-contentproc".to_string()];
+        let process = ProcessView { name: "firefox", exe: None, cmdline: &cmdline, uid: None };
+
+        assert_eq!(matcher.find_all(&process).len(), 4);
+        let resolved = matcher.resolve(&process).unwrap();
+        assert_eq!(resolved.sources.len(), 2);
+        // Higher priority wins conflicts, merge rules fill gaps, others are ignored
+        assert_eq!(resolved.rule.nice, Some(10));
+        assert_eq!(resolved.rule.ioclass, None);
+        assert_eq!(resolved.rule.cgroup.as_deref(), Some("browser"));
+        assert_eq!(resolved.rule.oom_score_adj, None);
+
+        let process = ProcessView { name: "firefox", exe: None, cmdline: &[], uid: None };
+        let resolved = matcher.resolve(&process).unwrap();
+        assert_eq!(resolved.rule.nice, Some(5));
+        assert_eq!(resolved.rule.ioclass.as_deref(), Some("idle"));
+        assert_eq!(resolved.rule.cgroup.as_deref(), Some("browser"));
+    }
+
+    #[test]
+    fn match_expressions() {
+        let cmdline = ["java".to_string(), "-jar".to_string(), "minecraft.jar".to_string()];
+        let server = ProcessView { name: "java", exe: Some("java"), cmdline: &cmdline, uid: Some(1000) };
+        let ci = ProcessView { uid: Some(0), ..server };
+        let minecraft = rule(r#"{"match": {"all": [
+            {"name": "java"}, {"cmdline": "minecraft"}, {"not": {"user": "root"}}
+        ]}, "nice": 5}"#);
+
+        assert!(matches(&minecraft, &server));
+        assert!(!matches(&minecraft, &ci));
+        assert_eq!(minecraft.label(), "all(name=java, cmdline~minecraft, not(user=root))");
+
+        let shells = rule(r#"{"match": {"any": [{"exe": "bash"}, {"exe": "zsh"}, {"uid": 0}]}}"#);
+        assert!(matches(&shells, &ci));
+        assert!(!matches(&shells, &server));
+
+        // Flat fields and the expression must both hold
+        let combined = rule(r#"{"name": "java", "match": {"not": {"cmdline": "minecraft"}}}"#);
+        assert!(!matches(&combined, &server));
+        let matcher = Matcher::new(vec![combined, minecraft]);
+        assert_eq!(matcher.find(&server).and_then(|rule| rule.nice), Some(5));
+    }
+}
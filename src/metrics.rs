@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::{Result, Context};
+use once_cell::sync::Lazy;
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Process-wide metrics registry, fed by the scan loop and cgroup controllers.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    scans_total: u64,
+    scan_duration_last: f64,
+    scan_duration_total: f64,
+    processes_scanned: u64,
+    rule_matches: BTreeMap<String, u64>,
+    type_matches: BTreeMap<String, u64>,
+    apply_failures: BTreeMap<(String, String), u64>,
+    cgroup_members: BTreeMap<String, u64>,
+    cgroup_add_failures: BTreeMap<String, u64>,
+    cgroup_pids_max_events: BTreeMap<String, u64>,
+    cgroup_frozen: BTreeMap<String, bool>,
+    reloads: BTreeMap<String, u64>,
+    last_reload_success: Option<bool>,
+    active_profile: Option<String>,
+}
+
+impl Metrics {
+    pub fn record_scan(&self, duration: Duration, processes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        let secs = duration.as_secs_f64();
+        inner.scans_total += 1;
+        inner.scan_duration_last = secs;
+        inner.scan_duration_total += secs;
+        inner.processes_scanned = processes as u64;
+    }
+
+    pub fn record_match(&self, rule: &str, types: &[String]) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.rule_matches.entry(rule.to_string()).or_default() += 1;
+        for type_name in types {
+            *inner.type_matches.entry(type_name.clone()).or_default() += 1;
+        }
+    }
+
+    pub fn record_apply_failure(&self, action: &str, kind: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.apply_failures.entry((action.to_string(), kind.to_string())).or_default() += 1;
+    }
+
+    pub fn set_cgroup_members(&self, cgroup: &str, members: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.cgroup_members.insert(cgroup.to_string(), members as u64);
+    }
+
+    pub fn record_cgroup_add_failure(&self, cgroup: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.cgroup_add_failures.entry(cgroup.to_string()).or_default() += 1;
+    }
+
+    pub fn set_cgroup_pids_max_events(&self, cgroup: &str, count: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.cgroup_pids_max_events.insert(cgroup.to_string(), count);
+    }
+
+    pub fn set_cgroup_frozen(&self, cgroup: &str, frozen: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.cgroup_frozen.insert(cgroup.to_string(), frozen);
+    }
+
+    pub fn record_reload(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let result = if success { "success" } else { "failure" };
+        *inner.reloads.entry(result.to_string()).or_default() += 1;
+        inner.last_reload_success = Some(success);
+    }
+
+    pub fn set_active_profile(&self, profile: Option<&str>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.active_profile = Some(profile.unwrap_or("none").to_string());
+    }
+
+    /// Render all metrics in the Prometheus text exposition format (0.0.4).
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "rust_ananicy_scans_total", "counter", "Number of completed process scans.");
+        let _ = writeln!(out, "rust_ananicy_scans_total {}", inner.scans_total);
+
+        header(&mut out, "rust_ananicy_scan_duration_seconds", "gauge", "Duration of the most recent scan.");
+        let _ = writeln!(out, "rust_ananicy_scan_duration_seconds {}", inner.scan_duration_last);
+
+        header(&mut out, "rust_ananicy_scan_duration_seconds_total", "counter", "Total time spent scanning.");
+        let _ = writeln!(out, "rust_ananicy_scan_duration_seconds_total {}", inner.scan_duration_total);
+
+        header(&mut out, "rust_ananicy_processes_scanned", "gauge", "Processes seen by the most recent scan.");
+        let _ = writeln!(out, "rust_ananicy_processes_scanned {}", inner.processes_scanned);
+
+        header(&mut out, "rust_ananicy_rule_matches_total", "counter", "Rule matches by rule.");
+        for (rule, count) in &inner.rule_matches {
+            let _ = writeln!(out, "rust_ananicy_rule_matches_total{{rule=\"{}\"}} {}", escape(rule), count);
+        }
+
+        header(&mut out, "rust_ananicy_type_matches_total", "counter", "Rule matches by type.");
+        for (type_name, count) in &inner.type_matches {
+            let _ = writeln!(out, "rust_ananicy_type_matches_total{{type=\"{}\"}} {}", escape(type_name), count);
+        }
+
+        header(&mut out, "rust_ananicy_apply_failures_total", "counter", "Failed rule applications by action and error kind.");
+        for ((action, kind), count) in &inner.apply_failures {
+            let _ = writeln!(
+                out,
+                "rust_ananicy_apply_failures_total{{action=\"{}\",kind=\"{}\"}} {}",
+                escape(action), escape(kind), count
+            );
+        }
+
+        header(&mut out, "rust_ananicy_cgroup_members", "gauge", "Processes currently in each managed cgroup.");
+        for (cgroup, count) in &inner.cgroup_members {
+            let _ = writeln!(out, "rust_ananicy_cgroup_members{{cgroup=\"{}\"}} {}", escape(cgroup), count);
+        }
+
+        header(&mut out, "rust_ananicy_cgroup_add_failures_total", "counter", "Failed cgroup placements by cgroup.");
+        for (cgroup, count) in &inner.cgroup_add_failures {
+            let _ = writeln!(out, "rust_ananicy_cgroup_add_failures_total{{cgroup=\"{}\"}} {}", escape(cgroup), count);
+        }
+
+        header(&mut out, "rust_ananicy_cgroup_pids_max_events_total", "counter", "Forks refused by TasksMax, from pids.events.");
+        for (cgroup, count) in &inner.cgroup_pids_max_events {
+            let _ = writeln!(out, "rust_ananicy_cgroup_pids_max_events_total{{cgroup=\"{}\"}} {}", escape(cgroup), count);
+        }
+
+        header(&mut out, "rust_ananicy_cgroup_frozen", "gauge", "Whether each managed cgroup is frozen (1) or running (0).");
+        for (cgroup, frozen) in &inner.cgroup_frozen {
+            let _ = writeln!(out, "rust_ananicy_cgroup_frozen{{cgroup=\"{}\"}} {}", escape(cgroup), u8::from(*frozen));
+        }
+
+        header(&mut out, "rust_ananicy_config_reloads_total", "counter", "Configuration reloads by result.");
+        for (result, count) in &inner.reloads {
+            let _ = writeln!(out, "rust_ananicy_config_reloads_total{{result=\"{}\"}} {}", result, count);
+        }
+
+        if let Some(success) = inner.last_reload_success {
+            header(&mut out, "rust_ananicy_config_last_reload_successful", "gauge", "Whether the last reload succeeded.");
+            let _ = writeln!(out, "rust_ananicy_config_last_reload_successful {}", success as u8);
+        }
+
+        if let Some(ref profile) = inner.active_profile {
+            header(&mut out, "rust_ananicy_active_profile", "gauge", "Currently active profile.");
+            let _ = writeln!(out, "rust_ananicy_active_profile{{profile=\"{}\"}} 1", escape(profile));
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serve `/metrics` on `listen`, which is either `host:port` or `unix:/path/to.sock`.
+pub async fn serve(listen: String) -> Result<()> {
+    if let Some(path) = listen.strip_prefix("unix:") {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("bind metrics socket {}", path))?;
+        info!("Serving metrics on unix:{}", path);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(handle(stream));
+        }
+    } else {
+        let listener = TcpListener::bind(&listen)
+            .await
+            .with_context(|| format!("bind metrics listener {}", listen))?;
+        info!("Serving metrics on http://{}/metrics", listen);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(handle(stream));
+        }
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+    let mut buf = [0u8; 1024];
+    let n = match stream.read(&mut buf).await {
+        Ok(n) => n,
+        Err(_) => return,
+    };
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+
+    let response = if path == "/metrics" {
+        let body = METRICS.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    };
+
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        warn!("Failed to write metrics response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_exposition_format() {
+        let metrics = Metrics::default();
+        metrics.record_scan(Duration::from_millis(250), 42);
+        metrics.record_match("firefox", &["browser".to_string()]);
+        metrics.record_match("firefox", &["browser".to_string()]);
+        metrics.record_apply_failure("nice", "permission_denied");
+        metrics.set_cgroup_members("say \"hi\"\\now", 3);
+        metrics.set_cgroup_frozen("background", true);
+        metrics.record_reload(false);
+        metrics.set_active_profile(None);
+
+        let text = metrics.render();
+        for line in [
+            "# HELP rust_ananicy_scans_total Number of completed process scans.",
+            "# TYPE rust_ananicy_scans_total counter",
+            "rust_ananicy_scans_total 1",
+            "rust_ananicy_scan_duration_seconds 0.25",
+            "rust_ananicy_processes_scanned 42",
+            "rust_ananicy_rule_matches_total{rule=\"firefox\"} 2",
+            "rust_ananicy_type_matches_total{type=\"browser\"} 2",
+            "rust_ananicy_apply_failures_total{action=\"nice\",kind=\"permission_denied\"} 1",
+            "rust_ananicy_cgroup_members{cgroup=\"say \\\"hi\\\"\\\\now\"} 3",
+            "rust_ananicy_cgroup_frozen{cgroup=\"background\"} 1",
+            "rust_ananicy_config_reloads_total{result=\"failure\"} 1",
+            "rust_ananicy_config_last_reload_successful 0",
+            "rust_ananicy_active_profile{profile=\"none\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+        // Every sample belongs to a family announced before it
+        let mut declared = Vec::new();
+        for line in text.lines() {
+            match line.strip_prefix("# TYPE ") {
+                Some(rest) => declared.push(rest.split(' ').next().unwrap().to_string()),
+                None if !line.starts_with('#') => {
+                    let name = line.split(['{', ' ']).next().unwrap();
+                    assert!(declared.iter().any(|family| family == name), "{}", line);
+                }
+                None => {}
+            }
+        }
+    }
+
+    #[test]
+    fn reload_gauge_only_after_a_reload() {
+        let metrics = Metrics::default();
+        assert!(!metrics.render().contains("rust_ananicy_config_last_reload_successful"));
+        metrics.record_reload(true);
+        assert!(metrics.render().contains("rust_ananicy_config_last_reload_successful 1"));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_path_only() {
+        let request = |path: &str| {
+            let path = path.to_string();
+            async move {
+                let (mut client, server) = tokio::io::duplex(1 << 16);
+                let task = tokio::spawn(handle(server));
+                client.write_all(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path).as_bytes()).await.unwrap();
+                let mut response = String::new();
+                client.read_to_string(&mut response).await.unwrap();
+                task.await.unwrap();
+                response
+            }
+        };
+
+        let ok = request("/metrics").await;
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"), "{}", ok);
+        assert!(ok.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        let body = ok.split("\r\n\r\n").nth(1).unwrap();
+        assert!(ok.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("# TYPE rust_ananicy_scans_total counter"));
+
+        assert!(request("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
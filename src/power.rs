@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::Path;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerSource {
+    Ac,
+    Battery,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerState {
+    pub source: PowerSource,
+    /// Lowest capacity of all batteries, in percent.
+    pub capacity: Option<u8>,
+}
+
+impl Default for PowerState {
+    fn default() -> Self {
+        Self { source: PowerSource::Ac, capacity: None }
+    }
+}
+
+/// Condition under which a rule applies, e.g. `{"power": "battery"}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Condition {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power: Option<PowerSource>,
+
+    /// Only apply while battery capacity is below this percentage.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(range(max = 100))]
+    pub battery_below: Option<u8>,
+}
+
+impl Condition {
+    pub fn matches(&self, state: &PowerState) -> bool {
+        if let Some(source) = self.power {
+            if source != state.source {
+                return false;
+            }
+        }
+        if let Some(threshold) = self.battery_below {
+            match state.capacity {
+                Some(capacity) if capacity < threshold => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Read the power state from a `/sys/class/power_supply`-like directory.
+///
+/// Machines without any battery, or with at least one online mains/USB
+/// supply, are considered to be on AC.
+pub fn read_power_state(root: &Path) -> PowerState {
+    let mut has_battery = false;
+    let mut on_ac = false;
+    let mut capacity: Option<u8> = None;
+
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return PowerState::default(),
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let supply_type = read_trimmed(&path.join("type")).unwrap_or_default();
+
+        match supply_type.as_str() {
+            "Battery" => {
+                // Peripheral batteries (mice, headsets) report scope "Device"
+                if read_trimmed(&path.join("scope")).as_deref() == Some("Device") {
+                    continue;
+                }
+                has_battery = true;
+                if let Some(value) = read_trimmed(&path.join("capacity")).and_then(|v| v.parse().ok()) {
+                    capacity = Some(capacity.map_or(value, |c: u8| c.min(value)));
+                }
+            }
+            _ => {
+                if read_trimmed(&path.join("online")).as_deref() == Some("1") {
+                    on_ac = true;
+                }
+            }
+        }
+    }
+
+    let source = if has_battery && !on_ac { PowerSource::Battery } else { PowerSource::Ac };
+    PowerState { source, capacity }
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(root: &Path, name: &str, files: &[(&str, &str)]) {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, value) in files {
+            fs::write(dir.join(file), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    fn condition(json: &str) -> Condition {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn no_supply_is_ac() {
+        let root = tempfile::tempdir().unwrap();
+        assert_eq!(read_power_state(root.path()), PowerState::default());
+        assert_eq!(read_power_state(&root.path().join("missing")), PowerState::default());
+    }
+
+    #[test]
+    fn online_mains_wins_over_battery() {
+        let root = tempfile::tempdir().unwrap();
+        supply(root.path(), "BAT0", &[("type", "Battery"), ("capacity", "80")]);
+        supply(root.path(), "AC", &[("type", "Mains"), ("online", "1")]);
+
+        let state = read_power_state(root.path());
+        assert_eq!(state, PowerState { source: PowerSource::Ac, capacity: Some(80) });
+        assert!(condition(r#"{"power": "ac"}"#).matches(&state));
+        assert!(!condition(r#"{"power": "battery"}"#).matches(&state));
+    }
+
+    #[test]
+    fn battery_below_threshold() {
+        let root = tempfile::tempdir().unwrap();
+        supply(root.path(), "AC", &[("type", "Mains"), ("online", "0")]);
+        supply(root.path(), "BAT0", &[("type", "Battery"), ("capacity", "40")]);
+        supply(root.path(), "BAT1", &[("type", "Battery"), ("capacity", "15")]);
+        // A mouse battery neither counts as a system battery nor lowers the capacity
+        supply(root.path(), "hidpp_battery_0", &[("type", "Battery"), ("scope", "Device"), ("capacity", "5")]);
+
+        let state = read_power_state(root.path());
+        assert_eq!(state, PowerState { source: PowerSource::Battery, capacity: Some(15) });
+        assert!(condition(r#"{"power": "battery", "battery_below": 20}"#).matches(&state));
+        assert!(!condition(r#"{"battery_below": 15}"#).matches(&state));
+        assert!(!condition(r#"{"battery_below": 20}"#).matches(&PowerState::default()));
+        assert!(Condition::default().matches(&state));
+    }
+}
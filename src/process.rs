@@ -1,192 +1,149 @@
 use std::fs;
-use std::collections::HashMap;
-use anyhow::{Result, anyhow};
-use procfs::process::{Process, Stat};
+use std::io;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use procfs::process::Process;
+use procfs::ProcError;
 use log::{debug, warn};
+use crate::actuator::{self, Actuator};
+use crate::error::{Error, Result};
+use crate::matcher::{self, Matcher, ProcessView};
 use crate::rules::Rule;
 use crate::cgroup::CgroupController;
+use crate::metrics::METRICS;
 
+/// A snapshot of the process properties rules look at.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ProcessInfo {
-    process: Process,
-    stat: Stat,
-    cmdline: Vec<String>,
+    pub pid: i32,
+    /// The kernel's `comm` name, at most 15 bytes.
+    pub name: String,
+    /// File name of the executable, if it could be read.
+    pub exe: Option<String>,
+    pub cmdline: Vec<String>,
+    pub uid: u32,
+    pub ppid: i32,
+    /// Path of the unified cgroup, or of the first v1 hierarchy on legacy hosts.
+    pub cgroup: String,
+    pub nice: i32,
+    pub oom_score_adj: i32,
+    /// Kernel scheduling policy number (`SCHED_OTHER` is 0).
+    pub sched_policy: u32,
 }
 
 impl ProcessInfo {
-    pub fn new(pid: i32) -> Result<Self> {
-        let process = Process::new(pid)?;
+    pub fn new(proc_root: &Path, pid: i32) -> Result<Self> {
+        Self::read(proc_root, pid).map_err(|e| Error::Process { pid, source: proc_io_error(e) })
+    }
+
+    fn read(proc_root: &Path, pid: i32) -> Result<Self, ProcError> {
+        let dir = proc_root.join(pid.to_string());
+        let process = Process::new_with_root(dir.clone())?;
         let stat = process.stat()?;
+        let exe = process.exe().ok()
+            .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()));
         let cmdline = process.cmdline()
             .unwrap_or_default()
             .into_iter()
             .map(|s| s.to_string())
             .collect();
-        
-        Ok(Self { process, stat, cmdline })
-    }
-    
-    pub fn pid(&self) -> i32 {
-        self.stat.pid
-    }
-    
-    pub fn name(&self) -> &str {
-        &self.stat.comm
-    }
-    
-    pub fn nice(&self) -> i32 {
-        self.stat.nice as i32
+        let uid = process.uid()?;
+        let cgroup = fs::read_to_string(dir.join("cgroup"))
+            .map(|content| cgroup_path(&content))
+            .unwrap_or_default();
+
+        Ok(Self {
+            pid: stat.pid,
+            name: stat.comm,
+            exe,
+            cmdline,
+            uid,
+            ppid: stat.ppid,
+            cgroup,
+            nice: stat.nice as i32,
+            oom_score_adj: process.oom_score_adj().map_or(0, i32::from),
+            sched_policy: stat.policy.unwrap_or(0),
+        })
     }
-    
-    pub fn set_nice(&self, nice: i32) -> Result<()> {
-        unsafe {
-            let result = libc::setpriority(libc::PRIO_PROCESS, self.pid() as u32, nice);
-            if result != 0 {
-                return Err(anyhow!("Failed to set nice value"));
-            }
+
+    /// The properties rules are matched against.
+    pub fn view(&self) -> ProcessView<'_> {
+        ProcessView {
+            name: &self.name,
+            exe: self.exe.as_deref(),
+            cmdline: &self.cmdline,
+            uid: Some(self.uid),
         }
-        debug!("Set nice {} for process {}", nice, self.pid());
-        Ok(())
-    }
-    
-    pub fn set_oom_score_adj(&self, score: i32) -> Result<()> {
-        let path = format!("/proc/{}/oom_score_adj", self.pid());
-        fs::write(&path, score.to_string())?;
-        debug!("Set OOM score {} for process {}", score, self.pid());
-        Ok(())
     }
-    
-    pub fn set_ionice(&self, class: Option<&str>, level: Option<i32>) -> Result<()> {
-        let mut cmd = std::process::Command::new("ionice");
-        cmd.arg("-p").arg(self.pid().to_string());
-        
-        if let Some(class) = class {
-            let class_num = match class {
-                "none" | "best-effort" => 2,
-                "idle" => 3,
-                "realtime" => 1,
-                _ => 2,
-            };
-            cmd.arg("-c").arg(class_num.to_string());
-        }
-        
-        if let Some(level) = level {
-            cmd.arg("-n").arg(level.to_string());
-        }
-        
-        let output = cmd.output()?;
-        if !output.status.success() {
-            return Err(anyhow!("Failed to set ionice"));
-        }
-        
-        debug!("Set ionice for process {}", self.pid());
-        Ok(())
+
+    pub fn matches_rule(&self, rule: &Rule) -> bool {
+        matcher::matches(rule, &self.view())
     }
-    
-    pub fn set_scheduler(&self, sched: &str, rtprio: Option<i32>) -> Result<()> {
-        let mut cmd = std::process::Command::new("schedtool");
-        
-        let sched_arg = match sched {
-            "other" | "normal" => "-N",
-            "rr" => "-R",
-            "fifo" => "-F",
-            "batch" => "-B",
-            "iso" => "-I",
-            "idle" => "-D",
-            _ => "-N",
-        };
-        
-        cmd.arg(sched_arg);
-        
-        if let Some(prio) = rtprio {
-            if sched == "rr" || sched == "fifo" {
-                cmd.arg("-p").arg(prio.to_string());
-            }
-        }
-        
-        cmd.arg(self.pid().to_string());
-        
-        let output = cmd.output()?;
-        if !output.status.success() {
-            warn!("Failed to set scheduler for process {}", self.pid());
-        } else {
-            debug!("Set scheduler {} for process {}", sched, self.pid());
-        }
-        
-        Ok(())
+}
+
+/// The I/O error behind `error`, keeping whether the process is gone or
+/// may not be read.
+fn proc_io_error(error: ProcError) -> io::Error {
+    match error {
+        ProcError::Io(error, _) => error,
+        ProcError::NotFound(_) => io::Error::new(io::ErrorKind::NotFound, error.to_string()),
+        ProcError::PermissionDenied(_) => io::Error::new(io::ErrorKind::PermissionDenied, error.to_string()),
+        error => io::Error::other(error.to_string()),
     }
-    
-    pub fn apply_rule(&self, rule: &Rule, cgroups: &HashMap<String, CgroupController>) -> Result<()> {
-        if let Some(nice) = rule.nice {
-            self.set_nice(nice)?;
-        }
-        
-        if rule.ioclass.is_some() || rule.ionice.is_some() {
-            self.set_ionice(rule.ioclass.as_deref(), rule.ionice)?;
-        }
-        
-        if let Some(ref sched) = rule.sched {
-            self.set_scheduler(sched, rule.rtprio)?;
-        }
-        
-        if let Some(oom) = rule.oom_score_adj {
-            self.set_oom_score_adj(oom)?;
-        }
-        
-        if let Some(ref cgroup_name) = rule.cgroup {
-            if let Some(cgroup) = cgroups.get(cgroup_name) {
-                cgroup.add_pid(self.pid())?;
-            }
-        }
-        
-        Ok(())
+}
+
+/// Pick the path to report from the contents of `/proc/<pid>/cgroup`.
+fn cgroup_path(content: &str) -> String {
+    let entries: Vec<(&str, &str)> = content
+        .lines()
+        .filter_map(|line| {
+            let (id, rest) = line.split_once(':')?;
+            let (_, path) = rest.split_once(':')?;
+            Some((id, path))
+        })
+        .collect();
+    entries.iter()
+        .find(|(id, _)| *id == "0")
+        .or(entries.first())
+        .map(|(_, path)| path.to_string())
+        .unwrap_or_default()
+}
+
+/// Where process snapshots come from.
+pub trait ProcessSource {
+    /// All processes currently running. Processes that exit while they are
+    /// being read are left out.
+    fn processes(&self) -> io::Result<Vec<ProcessInfo>>;
+}
+
+/// Processes read from a procfs mount.
+pub struct ProcFs {
+    root: PathBuf,
+}
+
+impl ProcFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
     }
-    
-    pub fn matches_rule(&self, rule: &Rule) -> bool {
-        // Check by process name
-        if let Some(ref rule_name) = rule.name {
-            // Check process name (comm field)
-            if self.name() == rule_name {
-                return true;
-            }
-            
-            // Check executable name
-            if let Ok(exe_path) = self.process.exe() {
-                if let Some(exe_name) = exe_path.file_name() {
-                    if let Some(exe_str) = exe_name.to_str() {
-                        if exe_str == rule_name {
-                            return true;
-                        }
-                    }
-                }
-            }
-            
-            // If we got here, name didn't match
-            return false;
-        }
-        
-        // Check by cmdline patterns
-        if let Some(ref cmdlines) = rule.cmdlines {
-            for pattern in cmdlines {
-                if !self.cmdline.iter().any(|arg| arg.contains(pattern)) {
-                    return false;
-                }
-            }
-        }
-        
-        true
+}
+
+impl ProcessSource for ProcFs {
+    fn processes(&self) -> io::Result<Vec<ProcessInfo>> {
+        scan_processes(&self.root)
     }
 }
 
-pub fn scan_processes() -> Result<Vec<ProcessInfo>> {
+pub fn scan_processes(proc_root: &Path) -> io::Result<Vec<ProcessInfo>> {
     let mut processes = Vec::new();
     
-    for entry in fs::read_dir("/proc")? {
+    for entry in fs::read_dir(proc_root)? {
         let entry = entry?;
         let name = entry.file_name();
         
         if let Some(name_str) = name.to_str() {
             if let Ok(pid) = name_str.parse::<i32>() {
-                if let Ok(proc_info) = ProcessInfo::new(pid) {
+                if let Ok(proc_info) = ProcessInfo::new(proc_root, pid) {
                     processes.push(proc_info);
                 }
             }
@@ -195,44 +152,93 @@ pub fn scan_processes() -> Result<Vec<ProcessInfo>> {
     
     Ok(processes)
 }
-///
-/// Scan the process table and apply the given rules to matching processes.
-
-pub fn scan_and_apply_rules(
-    rules: &[Rule], 
-    cgroups: &HashMap<String, CgroupController>
-) -> Result<usize> {
-    let processes = scan_processes()?;
-    let mut applied = 0;
+
+/// Outcome of one pass over all processes.
+#[derive(Debug, Default)]
+pub(crate) struct ScanSummary {
+    pub applied: usize,
+    /// Cgroups named in `freeze_cgroups` of rules that matched a running process.
+    pub freeze: BTreeSet<String>,
+}
+
+/// Read all processes from `source` and apply the rule `matcher` finds for
+/// each through `actuator`.
+pub(crate) fn scan_and_apply_rules(
+    matcher: &Matcher,
+    cgroups: &HashMap<String, CgroupController>,
+    source: &dyn ProcessSource,
+    actuator: &dyn Actuator,
+) -> io::Result<ScanSummary> {
+    let started = Instant::now();
+    let processes = source.processes()?;
+    let scanned = processes.len();
+    let mut summary = ScanSummary::default();
     
     for proc in processes {
-        for rule in rules {
-            if proc.matches_rule(rule) {
-                debug!(
-                    "Rule matched for PID {} ({}): name={:?} type={:?} nice={:?} ioclass={:?} ionice={:?} sched={:?} rtprio={:?} oom={:?} cgroup={:?} cmdlines={:?}",
-                    proc.pid(),
-                    proc.name(),
-                    rule.name,
-                    rule.type_name,
-                    rule.nice,
-                    rule.ioclass,
-                    rule.ionice,
-                    rule.sched,
-                    rule.rtprio,
-                    rule.oom_score_adj,
-                    rule.cgroup,
-                    rule.cmdlines
-                );
-                if let Err(e) = proc.apply_rule(rule, cgroups) {
-                    warn!("Failed to apply rule to PID {} ({}): {}", proc.pid(), proc.name(), e);
-                } else {
-                    debug!("Applied rule to PID {} ({})", proc.pid(), proc.name());
-                    applied += 1;
-                }
-                break; // Apply only first matching rule
-            }
+        let Some(resolved) = matcher.resolve(&proc.view()) else {
+            continue;
+        };
+        let rule = &*resolved.rule;
+        debug!(
+            "Rule matched for PID {} ({}): name={:?} type={:?} nice={:?} ioclass={:?} ionice={:?} sched={:?} rtprio={:?} oom={:?} cgroup={:?} cmdlines={:?}",
+            proc.pid,
+            proc.name,
+            rule.name,
+            rule.type_name,
+            rule.nice,
+            rule.ioclass,
+            rule.ionice,
+            rule.sched,
+            rule.rtprio,
+            rule.oom_score_adj,
+            rule.cgroup,
+            rule.cmdlines
+        );
+        if resolved.sources.len() > 1 {
+            let labels: Vec<String> = resolved.sources.iter().map(|source| source.label()).collect();
+            debug!("Merged rules for PID {}: {}", proc.pid, labels.join(", "));
         }
+        for source in &resolved.sources {
+            METRICS.record_match(&source.label(), source.types());
+        }
+        if let Some(ref groups) = rule.freeze_cgroups {
+            summary.freeze.extend(groups.iter().cloned());
+        }
+        if let Err(e) = actuator::apply_rule(actuator, &proc, rule, cgroups) {
+            warn!("Failed to apply rule to PID {} ({}): {}", proc.pid, proc.name, e);
+            METRICS.record_apply_failure(e.action, e.kind());
+        } else {
+            debug!("Applied rule to PID {} ({})", proc.pid, proc.name);
+            summary.applied += 1;
+        }
+    }
+    
+    for cgroup in cgroups.values() {
+        METRICS.set_cgroup_members(cgroup.name(), cgroup.pid_count());
     }
+    METRICS.record_scan(started.elapsed(), scanned);
     
-    Ok(applied)
-}
\ No newline at end of file
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup_path_prefers_unified_entry() {
+        assert_eq!(cgroup_path("0::/user.slice/app.scope\n"), "/user.slice/app.scope");
+        assert_eq!(cgroup_path("4:cpu,cpuacct:/games\n0::/user.slice\n"), "/user.slice");
+        assert_eq!(cgroup_path("4:cpu,cpuacct:/games\n3:memory:/\n"), "/games");
+        assert_eq!(cgroup_path(""), "");
+    }
+
+    #[test]
+    fn missing_process_is_a_typed_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        match ProcessInfo::new(tmp.path(), 4242) {
+            Err(Error::Process { pid: 4242, source }) => assert_eq!(source.kind(), io::ErrorKind::NotFound),
+            other => panic!("expected a process error, got {:?}", other),
+        }
+    }
+}
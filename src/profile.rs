@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context, bail};
+use serde_json::Value;
+use crate::cgroup;
+use crate::rules;
+
+/// Directory under the config dir holding one sub-directory per profile.
+pub const PROFILES_DIR: &str = "profiles";
+
+const ACTIVE_PROFILE_FILE: &str = "profile";
+
+/// Config layers for `profile`, lowest precedence first.
+pub fn layers(config_dir: &Path, profile: Option<&str>) -> Result<Vec<PathBuf>> {
+    let mut layers = vec![config_dir.to_path_buf()];
+    if let Some(name) = profile {
+        layers.push(profile_dir(config_dir, name)?);
+    }
+    Ok(layers)
+}
+
+pub fn profile_dir(config_dir: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        bail!("Invalid profile name '{}'", name);
+    }
+    let dir = config_dir.join(PROFILES_DIR).join(name);
+    if !dir.is_dir() {
+        bail!("Unknown profile '{}' (no directory {})", name, dir.display());
+    }
+    Ok(dir)
+}
+
+pub fn list_profiles(config_dir: &Path) -> Result<Vec<String>> {
+    let dir = config_dir.join(PROFILES_DIR);
+    let mut profiles = Vec::new();
+
+    if dir.is_dir() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.path().is_dir() {
+                profiles.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    profiles.sort();
+    Ok(profiles)
+}
+
+/// The persisted active profile, if any.
+pub fn active_profile(state_dir: &Path) -> Option<String> {
+    fs::read_to_string(state_dir.join(ACTIVE_PROFILE_FILE))
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Persist the active profile, replacing the state file atomically.
+pub fn save_active_profile(state_dir: &Path, profile: Option<&str>) -> Result<()> {
+    fs::create_dir_all(state_dir)
+        .with_context(|| format!("mkdir {}", state_dir.display()))?;
+    let path = state_dir.join(ACTIVE_PROFILE_FILE);
+    let tmp = state_dir.join(format!(".{}.tmp", ACTIVE_PROFILE_FILE));
+    fs::write(&tmp, profile.unwrap_or_default())
+        .with_context(|| format!("write {}", tmp.display()))?;
+    fs::rename(&tmp, &path)
+        .with_context(|| format!("rename {} to {}", tmp.display(), path.display()))?;
+    Ok(())
+}
+
+/// Parse a profile argument, where `none` or `base` selects no profile.
+pub fn parse_name(name: &str) -> Option<String> {
+    match name {
+        "none" | "base" | "" => None,
+        other => Some(other.to_string()),
+    }
+}
+
+/// Effective rules, types and cgroups of a profile, keyed for comparison.
+fn snapshot(config_dir: &Path, profile: Option<&str>) -> Result<BTreeMap<String, Value>> {
+    let layers = layers(config_dir, profile)?;
+    let mut entries = BTreeMap::new();
+
+    for rule in rules::load_all_rules(&layers)? {
+        let key = format!("rule {}", rule.label());
+        entries.entry(key).or_insert(serde_json::to_value(&rule)?);
+    }
+    for (name, type_def) in rules::resolve_types(&rules::load_types(&layers)?) {
+        entries.insert(format!("type {}", name), serde_json::to_value(&type_def)?);
+    }
+    for (name, def) in cgroup::load_cgroup_defs(&layers)? {
+        entries.insert(format!("cgroup {}", name), serde_json::to_value(&def)?);
+    }
+
+    Ok(entries)
+}
+
+/// Render the differences between two profiles as `+`/`-`/`~` lines.
+pub fn diff(config_dir: &Path, from: Option<&str>, to: Option<&str>) -> Result<Vec<String>> {
+    let old = snapshot(config_dir, from)?;
+    let new = snapshot(config_dir, to)?;
+    let mut lines = Vec::new();
+
+    for (key, value) in &old {
+        match new.get(key) {
+            None => lines.push(format!("- {}: {}", key, value)),
+            Some(other) if other != value => lines.push(format!("~ {}: {} -> {}", key, value, other)),
+            Some(_) => {}
+        }
+    }
+    for (key, value) in &new {
+        if !old.contains_key(key) {
+            lines.push(format!("+ {}: {}", key, value));
+        }
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Base rules plus a `gaming` profile overriding one rule, adding a
+    /// cgroup and dropping nothing.
+    fn config() -> tempfile::TempDir {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("00-base.rules"), concat!(
+            r#"{"name": "game", "nice": 0}"#, "\n",
+            r#"{"name": "make", "nice": 10}"#, "\n",
+        )).unwrap();
+        let gaming = tmp.path().join(PROFILES_DIR).join("gaming");
+        fs::create_dir_all(&gaming).unwrap();
+        fs::write(gaming.join("00-gaming.rules"), r#"{"name": "game", "nice": -10}"#).unwrap();
+        fs::write(gaming.join("00-gaming.cgroups"), r#"{"cgroup": "background", "CPUQuotaTotal": 20}"#).unwrap();
+        fs::create_dir_all(tmp.path().join(PROFILES_DIR).join("quiet")).unwrap();
+        tmp
+    }
+
+    #[test]
+    fn profile_layers_override_base() {
+        let tmp = config();
+        let dirs = layers(tmp.path(), Some("gaming")).unwrap();
+        assert_eq!(dirs, [tmp.path().to_path_buf(), tmp.path().join("profiles/gaming")]);
+
+        // The profile's rule comes first and so wins the match
+        let rules = rules::load_all_rules(&dirs).unwrap();
+        let game: Vec<i32> = rules.iter().filter(|rule| rule.name.as_deref() == Some("game")).filter_map(|rule| rule.nice).collect();
+        assert_eq!(game, [-10, 0]);
+
+        assert_eq!(list_profiles(tmp.path()).unwrap(), ["gaming", "quiet"]);
+        assert!(layers(tmp.path(), Some("missing")).is_err());
+        assert!(layers(tmp.path(), Some("../gaming")).is_err());
+        assert!(layers(tmp.path(), Some(".hidden")).is_err());
+    }
+
+    #[test]
+    fn diff_lists_changes_between_profiles() {
+        let tmp = config();
+        let lines = diff(tmp.path(), None, Some("gaming")).unwrap();
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines[0].starts_with("~ rule game: "), "{}", lines[0]);
+        assert!(lines[0].contains(r#""nice":0"#) && lines[0].contains(r#""nice":-10"#), "{}", lines[0]);
+        assert!(lines[1].starts_with("+ cgroup background: "), "{}", lines[1]);
+
+        let back = diff(tmp.path(), Some("gaming"), None).unwrap();
+        assert!(back[0].starts_with("- cgroup background: "), "{:?}", back);
+        assert!(diff(tmp.path(), Some("quiet"), None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn active_profile_is_saved_and_cleared() {
+        let tmp = tempfile::tempdir().unwrap();
+        let state = tmp.path().join("state");
+        assert_eq!(active_profile(&state), None);
+
+        save_active_profile(&state, Some("gaming")).unwrap();
+        assert_eq!(active_profile(&state).as_deref(), Some("gaming"));
+        save_active_profile(&state, None).unwrap();
+        assert_eq!(active_profile(&state), None);
+        // Nothing is left of the temporary file
+        let files: Vec<_> = fs::read_dir(&state).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, [ACTIVE_PROFILE_FILE]);
+
+        assert_eq!(parse_name("none"), None);
+        assert_eq!(parse_name("base"), None);
+        assert_eq!(parse_name("gaming").as_deref(), Some("gaming"));
+    }
+}
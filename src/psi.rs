@@ -0,0 +1,344 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, Context, anyhow, bail};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use log::{debug, info, warn};
+use crate::cgroup::{CgroupController, IO_WEIGHT_MAX, IO_WEIGHT_MIN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Resource {
+    Cpu,
+    Io,
+    Memory,
+}
+
+impl Resource {
+    fn file_name(self) -> &'static str {
+        match self {
+            Resource::Cpu => "cpu",
+            Resource::Io => "io",
+            Resource::Memory => "memory",
+        }
+    }
+}
+
+/// One line of a PSI file, averages in percent.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PressureLine {
+    pub avg10: f64,
+    pub avg60: f64,
+    pub avg300: f64,
+    pub total: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Pressure {
+    pub some: PressureLine,
+    /// Not reported for system-wide cpu pressure on older kernels.
+    pub full: Option<PressureLine>,
+}
+
+pub fn parse_pressure(content: &str) -> Result<Pressure> {
+    let mut pressure = Pressure::default();
+    let mut seen_some = false;
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let kind = fields.next().unwrap_or_default();
+        let mut parsed = PressureLine::default();
+
+        for field in fields {
+            let (key, value) = field.split_once('=')
+                .ok_or_else(|| anyhow!("malformed pressure field '{}'", field))?;
+            match key {
+                "avg10" => parsed.avg10 = value.parse()?,
+                "avg60" => parsed.avg60 = value.parse()?,
+                "avg300" => parsed.avg300 = value.parse()?,
+                "total" => parsed.total = value.parse()?,
+                _ => {}
+            }
+        }
+
+        match kind {
+            "some" => {
+                pressure.some = parsed;
+                seen_some = true;
+            }
+            "full" => pressure.full = Some(parsed),
+            _ => {}
+        }
+    }
+
+    if !seen_some {
+        return Err(anyhow!("no 'some' line in pressure data"));
+    }
+    Ok(pressure)
+}
+
+/// System-wide pressure from `pressure/<resource>` below `proc_root`.
+pub fn system_pressure(proc_root: &Path, resource: Resource) -> Result<Pressure> {
+    read_pressure(&proc_root.join("pressure").join(resource.file_name()))
+}
+
+/// Pressure of a single cgroup v2 directory, from `<resource>.pressure`.
+pub fn cgroup_pressure(cgroup_dir: &Path, resource: Resource) -> Result<Pressure> {
+    read_pressure(&cgroup_dir.join(format!("{}.pressure", resource.file_name())))
+}
+
+fn read_pressure(path: &Path) -> Result<Pressure> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("read {}", path.display()))?;
+    parse_pressure(&content).with_context(|| format!("parse {}", path.display()))
+}
+
+/// Bounds for PSI-driven adjustment of a cgroup, the `Adaptive` key of a `.cgroups` entry.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Adaptive {
+    /// Which system pressure to watch.
+    #[serde(rename = "Resource", default = "default_resource")]
+    pub resource: Resource,
+
+    /// Tighten once `some avg10` stays above this percentage.
+    #[serde(rename = "PressureHigh", default = "default_pressure_high")]
+    pub pressure_high: f64,
+
+    /// Relax once `some avg10` stays below this percentage.
+    #[serde(rename = "PressureLow", default = "default_pressure_low")]
+    pub pressure_low: f64,
+
+    /// Consecutive samples required beyond a threshold before adjusting.
+    #[serde(rename = "Hold", default = "default_hold")]
+    pub hold: u32,
+
+    /// Number of adjustments between the lower and upper bound.
+    #[serde(rename = "Steps", default = "default_steps")]
+    pub steps: u32,
+
+    #[serde(rename = "CPUQuotaMin", default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota_min: Option<u32>,
+
+    #[serde(rename = "CPUQuotaMax", default, skip_serializing_if = "Option::is_none")]
+    pub cpu_quota_max: Option<u32>,
+
+    #[serde(rename = "IOWeightMin", default, skip_serializing_if = "Option::is_none")]
+    pub io_weight_min: Option<u32>,
+
+    #[serde(rename = "IOWeightMax", default, skip_serializing_if = "Option::is_none")]
+    pub io_weight_max: Option<u32>,
+}
+
+impl Adaptive {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=100.0).contains(&self.pressure_low) || !(0.0..=100.0).contains(&self.pressure_high) {
+            bail!("PressureLow and PressureHigh are percentages between 0 and 100");
+        }
+        if self.pressure_low >= self.pressure_high {
+            bail!("PressureLow ({}) must be below PressureHigh ({})", self.pressure_low, self.pressure_high);
+        }
+        if let (Some(min), Some(max)) = (self.cpu_quota_min, self.cpu_quota_max) {
+            if min > max {
+                bail!("CPUQuotaMin ({}) cannot exceed CPUQuotaMax ({})", min, max);
+            }
+        }
+        for weight in [self.io_weight_min, self.io_weight_max].into_iter().flatten() {
+            if !(IO_WEIGHT_MIN..=IO_WEIGHT_MAX).contains(&weight) {
+                bail!("IOWeightMin and IOWeightMax must be between {} and {}, got {}", IO_WEIGHT_MIN, IO_WEIGHT_MAX, weight);
+            }
+        }
+        if let (Some(min), Some(max)) = (self.io_weight_min, self.io_weight_max) {
+            if min > max {
+                bail!("IOWeightMin ({}) cannot exceed IOWeightMax ({})", min, max);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_resource() -> Resource { Resource::Cpu }
+fn default_pressure_high() -> f64 { 40.0 }
+fn default_pressure_low() -> f64 { 10.0 }
+fn default_hold() -> u32 { 2 }
+fn default_steps() -> u32 { 4 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adjustment {
+    Tighten,
+    Relax,
+}
+
+/// Hysteresis state of one adaptively throttled cgroup.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    above: u32,
+    below: u32,
+}
+
+impl Throttle {
+    /// Feed one pressure sample and return the adjustment to make, if any.
+    ///
+    /// Between the two thresholds nothing changes and both counters reset,
+    /// so a value oscillating around one threshold cannot cause flapping.
+    pub fn sample(&mut self, adaptive: &Adaptive, avg10: f64) -> Option<Adjustment> {
+        if avg10 > adaptive.pressure_high {
+            self.above += 1;
+            self.below = 0;
+            if self.above >= adaptive.hold {
+                self.above = 0;
+                return Some(Adjustment::Tighten);
+            }
+        } else if avg10 < adaptive.pressure_low {
+            self.below += 1;
+            self.above = 0;
+            if self.below >= adaptive.hold {
+                self.below = 0;
+                return Some(Adjustment::Relax);
+            }
+        } else {
+            self.above = 0;
+            self.below = 0;
+        }
+        None
+    }
+}
+
+/// Move `current` one step towards the bound selected by `adjustment`.
+pub fn step_value(current: u32, min: u32, max: u32, steps: u32, adjustment: Adjustment) -> u32 {
+    let step = ((max.saturating_sub(min)) / steps.max(1)).max(1);
+    match adjustment {
+        Adjustment::Tighten => current.saturating_sub(step).max(min),
+        Adjustment::Relax => current.saturating_add(step).min(max),
+    }
+}
+
+/// A configured pair of bounds; an inverted pair is ignored rather than clamped.
+fn bounds(min: Option<u32>, max: Option<u32>) -> Option<(u32, u32)> {
+    min.zip(max).filter(|(min, max)| min <= max)
+}
+
+/// Sample pressure for one adaptive cgroup and apply at most one step.
+///
+/// Only the system-wide pressure decides; the group's own pressure is logged
+/// next to it to show how much of the stall the group suffers itself.
+pub fn adapt(cgroup: &mut CgroupController, throttle: &mut Throttle, proc_root: &Path) {
+    let adaptive = match cgroup.def().adaptive.clone() {
+        Some(adaptive) => adaptive,
+        None => return,
+    };
+
+    let system = match system_pressure(proc_root, adaptive.resource) {
+        Ok(pressure) => pressure.some.avg10,
+        Err(e) => {
+            debug!("No pressure information for cgroup {}: {:#}", cgroup.name(), e);
+            return;
+        }
+    };
+    let adjustment = match throttle.sample(&adaptive, system) {
+        Some(adjustment) => adjustment,
+        None => return,
+    };
+    let own = cgroup_pressure(cgroup.path(), adaptive.resource)
+        .map(|p| format!("{:.1}%", p.some.avg10))
+        .unwrap_or_else(|_| "n/a".to_string());
+
+    if let Some((min, max)) = bounds(adaptive.cpu_quota_min, adaptive.cpu_quota_max) {
+        let quota = cgroup.cpu_quota();
+        let current = quota.percent();
+        let next = step_value(current.clamp(min, max), min, max, adaptive.steps, adjustment);
+        if next != current {
+            info!(
+                "{:?} cgroup {}: CPU quota {}% -> {}% ({:?} pressure {:.1}%, cgroup {})",
+                adjustment, cgroup.name(), current, next, adaptive.resource, system, own
+            );
+            if let Err(e) = cgroup.set_cpu_quota(quota.with_percent(next)) {
+                warn!("Could not adjust CPU quota of cgroup {}: {}", cgroup.name(), e);
+            }
+        }
+    }
+
+    if let Some((min, max)) = bounds(adaptive.io_weight_min, adaptive.io_weight_max) {
+        let current = cgroup.io_weight().unwrap_or(max);
+        let next = step_value(current.clamp(min, max), min, max, adaptive.steps, adjustment);
+        if next != current || cgroup.io_weight().is_none() {
+            info!(
+                "{:?} cgroup {}: IO weight {} -> {} ({:?} pressure {:.1}%, cgroup {})",
+                adjustment, cgroup.name(), current, next, adaptive.resource, system, own
+            );
+            if let Err(e) = cgroup.set_io_weight(next) {
+                warn!("Could not adjust IO weight of cgroup {}: {}", cgroup.name(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adaptive(json: &str) -> Adaptive {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parses_some_and_full_lines() {
+        let pressure = parse_pressure(
+            "some avg10=12.50 avg60=3.00 avg300=0.75 total=123456\n\
+             full avg10=1.00 avg60=0.50 avg300=0.00 total=42\n",
+        ).unwrap();
+        assert_eq!(pressure.some, PressureLine { avg10: 12.5, avg60: 3.0, avg300: 0.75, total: 123456 });
+        assert_eq!(pressure.full.map(|full| full.total), Some(42));
+
+        let cpu = parse_pressure("some avg10=0.00 avg60=0.00 avg300=0.00 total=0\n").unwrap();
+        assert_eq!(cpu.full, None);
+        assert!(parse_pressure("full avg10=1.00 avg60=0.00 avg300=0.00 total=1\n").is_err());
+        assert!(parse_pressure("some avg10 avg60=0.00\n").is_err());
+        assert!(parse_pressure("some avg10=lots\n").is_err());
+    }
+
+    #[test]
+    fn system_pressure_is_read_below_proc_root() {
+        let proc = tempfile::tempdir().unwrap();
+        fs::create_dir_all(proc.path().join("pressure")).unwrap();
+        fs::write(proc.path().join("pressure/io"), "some avg10=7.25 avg60=0.00 avg300=0.00 total=9\n").unwrap();
+
+        assert_eq!(system_pressure(proc.path(), Resource::Io).unwrap().some.avg10, 7.25);
+        assert!(system_pressure(proc.path(), Resource::Cpu).is_err());
+    }
+
+    #[test]
+    fn steps_stay_within_bounds() {
+        assert_eq!(step_value(60, 10, 60, 4, Adjustment::Tighten), 48);
+        assert_eq!(step_value(12, 10, 60, 4, Adjustment::Tighten), 10);
+        assert_eq!(step_value(10, 10, 60, 4, Adjustment::Tighten), 10);
+        assert_eq!(step_value(55, 10, 60, 4, Adjustment::Relax), 60);
+        // A range narrower than the step count still moves one unit at a time
+        assert_eq!(step_value(5, 4, 6, 10, Adjustment::Relax), 6);
+        assert_eq!(step_value(5, 5, 5, 0, Adjustment::Relax), 5);
+        assert_eq!(bounds(Some(60), Some(10)), None);
+        assert_eq!(bounds(Some(10), None), None);
+    }
+
+    #[test]
+    fn throttle_waits_for_hold_and_resets_between_thresholds() {
+        let adaptive = adaptive(r#"{"PressureHigh": 40, "PressureLow": 10, "Hold": 2}"#);
+        let mut throttle = Throttle::default();
+
+        assert_eq!(throttle.sample(&adaptive, 50.0), None);
+        assert_eq!(throttle.sample(&adaptive, 50.0), Some(Adjustment::Tighten));
+        // The counter starts over after acting
+        assert_eq!(throttle.sample(&adaptive, 50.0), None);
+        // A sample between the thresholds resets the count
+        assert_eq!(throttle.sample(&adaptive, 25.0), None);
+        assert_eq!(throttle.sample(&adaptive, 50.0), None);
+        assert_eq!(throttle.sample(&adaptive, 50.0), Some(Adjustment::Tighten));
+
+        // Crossing to the other side restarts the count as well
+        assert_eq!(throttle.sample(&adaptive, 50.0), None);
+        assert_eq!(throttle.sample(&adaptive, 5.0), None);
+        assert_eq!(throttle.sample(&adaptive, 5.0), Some(Adjustment::Relax));
+        // Values exactly on a threshold count as in between
+        assert_eq!(throttle.sample(&adaptive, 40.0), None);
+        assert_eq!(throttle.sample(&adaptive, 40.0), None);
+    }
+}
@@ -1,152 +1,488 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
 use std::collections::HashMap;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use anyhow::Result;
-use log::info;
+use log::{info, warn};
+use crate::compat::{self, Dialect};
+use crate::error::{Error, Result};
+use crate::matcher::MatchExpr;
+use crate::power::{Condition, PowerState};
+use crate::schema::{IoClass, SchedPolicy};
+use crate::toml_rules;
+use crate::topology::TOPOLOGY;
+use crate::users::USERS_DIR;
+use crate::utils;
+use crate::profile::PROFILES_DIR;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Rule {
+    /// Process name (`comm`) or executable file name.
     pub name: Option<String>,
     
+    /// Type, or list of types whose attributes fill unset ones in order.
     #[serde(rename = "type")]
-    pub type_name: Option<String>,
+    pub type_name: Option<TypeRef>,
     
+    /// Nice value, lower is higher priority.
+    #[schemars(range(min = "utils::NICE_MIN", max = "utils::NICE_MAX"))]
     pub nice: Option<i32>,
     
+    /// I/O scheduling class.
+    #[schemars(with = "Option<IoClass>")]
     pub ioclass: Option<String>,
     
+    /// I/O priority within the class, lower is higher priority.
+    #[schemars(range(min = "utils::IONICE_MIN", max = "utils::IONICE_MAX"))]
     pub ionice: Option<i32>,
     
+    /// CPU scheduling policy.
+    #[schemars(with = "Option<SchedPolicy>")]
     pub sched: Option<String>,
     
+    /// Realtime priority for the `rr` and `fifo` policies.
+    #[schemars(range(min = "utils::RTPRIO_MIN", max = "utils::RTPRIO_MAX"))]
     pub rtprio: Option<i32>,
     
+    /// Added to the OOM killer's badness score.
+    #[schemars(range(min = "utils::OOM_SCORE_ADJ_MIN", max = "utils::OOM_SCORE_ADJ_MAX"))]
     pub oom_score_adj: Option<i32>,
     
+    /// Name of a cgroup from a `.cgroups` file to move the process into.
     pub cgroup: Option<String>,
     
+    /// Strings that must all occur in the command line.
     pub cmdlines: Option<Vec<String>>,
+    
+    /// Executable file name the process must run, checked along with `name`
+    /// and `cmdlines`.
+    pub exe: Option<String>,
+    
+    /// Boolean expression over process properties that must also hold.
+    #[serde(rename = "match")]
+    pub match_expr: Option<MatchExpr>,
+    
+    /// Precedence over other matching rules; higher wins, default 0.
+    pub priority: Option<i32>,
+    
+    /// Contribute attributes that higher precedence matching rules leave unset.
+    pub merge: Option<bool>,
+    
+    /// CPU list or symbolic set (`performance`, `efficiency`, `vcache`).
+    pub cpu_affinity: Option<String>,
+    
+    /// Cgroups kept frozen while a process matching this rule runs.
+    pub freeze_cgroups: Option<Vec<String>>,
+    
+    /// Only apply the rule while this condition holds.
+    pub when: Option<Condition>,
+    
+    /// Uid of the user whose rule directory the rule was loaded from. Such
+    /// rules only match that user's processes and never raise priority.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub owner: Option<u32>,
+}
+
+impl Rule {
+    /// Human readable identifier, used in logs and metric labels.
+    pub fn label(&self) -> String {
+        let name = match (&self.name, &self.exe, &self.match_expr) {
+            (Some(name), _, _) | (None, Some(name), _) => name.clone(),
+            (None, None, Some(expr)) => expr.to_string(),
+            (None, None, None) => "*".to_string(),
+        };
+        match self.cmdlines {
+            Some(ref cmdlines) => format!("{}[{}]", name, cmdlines.join(" ")),
+            None => name,
+        }
+    }
+    
+    /// Fill attributes unset on this rule from `other`, returning whether any
+    /// were taken.
+    pub fn merge_from(&mut self, other: &Rule) -> bool {
+        let mut merged = fill(&mut self.nice, &other.nice);
+        merged |= fill(&mut self.ioclass, &other.ioclass);
+        merged |= fill(&mut self.ionice, &other.ionice);
+        merged |= fill(&mut self.sched, &other.sched);
+        merged |= fill(&mut self.rtprio, &other.rtprio);
+        merged |= fill(&mut self.oom_score_adj, &other.oom_score_adj);
+        merged |= fill(&mut self.cgroup, &other.cgroup);
+        merged |= fill(&mut self.cpu_affinity, &other.cpu_affinity);
+        merged |= fill(&mut self.freeze_cgroups, &other.freeze_cgroups);
+        merged
+    }
+    
+    /// Fill attributes unset on this rule from a resolved type.
+    pub fn inherit(&mut self, type_def: &Type) {
+        fill(&mut self.nice, &type_def.nice);
+        fill(&mut self.ioclass, &type_def.ioclass);
+        fill(&mut self.ionice, &type_def.ionice);
+        fill(&mut self.sched, &type_def.sched);
+        fill(&mut self.rtprio, &type_def.rtprio);
+        fill(&mut self.oom_score_adj, &type_def.oom_score_adj);
+        fill(&mut self.cgroup, &type_def.cgroup);
+        fill(&mut self.cpu_affinity, &type_def.cpu_affinity);
+        fill(&mut self.freeze_cgroups, &type_def.freeze_cgroups);
+    }
+    
+    /// Names of the types the rule uses, highest precedence first.
+    pub fn types(&self) -> &[String] {
+        self.type_name.as_ref().map_or(&[], TypeRef::names)
+    }
+    
+    pub fn is_active(&self, power: &PowerState) -> bool {
+        self.when.as_ref().is_none_or(|when| when.matches(power))
+    }
+    
+    /// Check that all configured values are within their valid ranges.
+    pub fn validate(&self) -> Result<()> {
+        validate_attributes(
+            self.nice, self.ioclass.as_deref(), self.ionice, self.sched.as_deref(),
+            self.rtprio, self.oom_score_adj, self.cpu_affinity.as_deref(),
+        )
+    }
+}
+
+/// Range and choice checks shared by rules and types.
+fn validate_attributes(
+    nice: Option<i32>,
+    ioclass: Option<&str>,
+    ionice: Option<i32>,
+    sched: Option<&str>,
+    rtprio: Option<i32>,
+    oom_score_adj: Option<i32>,
+    cpu_affinity: Option<&str>,
+) -> Result<()> {
+    if let Some(nice) = nice {
+        utils::validate_nice(nice)?;
+    }
+    if let Some(class) = ioclass {
+        utils::validate_choice("ioclass", class, utils::IOCLASSES)?;
+    }
+    if let Some(ionice) = ionice {
+        utils::validate_ionice(ionice)?;
+    }
+    if let Some(policy) = sched {
+        utils::validate_choice("sched", policy, utils::SCHED_POLICIES)?;
+    }
+    if let Some(rtprio) = rtprio {
+        utils::validate_rtprio(rtprio)?;
+    }
+    if let Some(adj) = oom_score_adj {
+        utils::validate_oom_score_adj(adj)?;
+    }
+    if let Some(spec) = cpu_affinity {
+        TOPOLOGY.resolve_affinity(spec)?;
+    }
+    Ok(())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Set `target` from `source` if it is unset, returning whether it was.
+fn fill<T: Clone>(target: &mut Option<T>, source: &Option<T>) -> bool {
+    if target.is_none() && source.is_some() {
+        target.clone_from(source);
+        return true;
+    }
+    false
+}
+
+/// A single type name or a list of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum TypeRef {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TypeRef {
+    pub fn names(&self) -> &[String] {
+        match self {
+            TypeRef::One(name) => std::slice::from_ref(name),
+            TypeRef::Many(names) => names,
+        }
+    }
+}
+
+impl std::fmt::Display for TypeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.names().join("+"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Type {
     #[serde(rename = "type")]
     pub name: String,
     
+    /// Types whose attributes fill unset ones of this type, earlier first.
+    pub extends: Option<TypeRef>,
+    
+    /// Nice value, lower is higher priority.
+    #[schemars(range(min = "utils::NICE_MIN", max = "utils::NICE_MAX"))]
     pub nice: Option<i32>,
     
+    /// I/O scheduling class.
+    #[schemars(with = "Option<IoClass>")]
     pub ioclass: Option<String>,
     
+    /// I/O priority within the class, lower is higher priority.
+    #[schemars(range(min = "utils::IONICE_MIN", max = "utils::IONICE_MAX"))]
     pub ionice: Option<i32>,
     
+    /// CPU scheduling policy.
+    #[schemars(with = "Option<SchedPolicy>")]
     pub sched: Option<String>,
     
+    /// Realtime priority for the `rr` and `fifo` policies.
+    #[schemars(range(min = "utils::RTPRIO_MIN", max = "utils::RTPRIO_MAX"))]
     pub rtprio: Option<i32>,
     
+    /// Added to the OOM killer's badness score.
+    #[schemars(range(min = "utils::OOM_SCORE_ADJ_MIN", max = "utils::OOM_SCORE_ADJ_MAX"))]
     pub oom_score_adj: Option<i32>,
     
+    /// Name of a cgroup from a `.cgroups` file to move the process into.
     pub cgroup: Option<String>,
+    
+    /// CPU list or symbolic set (`performance`, `efficiency`, `vcache`).
+    pub cpu_affinity: Option<String>,
+    
+    /// Cgroups kept frozen while a process matching this rule runs.
+    pub freeze_cgroups: Option<Vec<String>>,
 }
 
-pub fn load_types(config_dir: &Path) -> Result<HashMap<String, Type>> {
-    let mut types = HashMap::new();
+impl Type {
+    /// Fill attributes unset on this type from a resolved parent.
+    fn inherit(&mut self, parent: &Type) {
+        fill(&mut self.nice, &parent.nice);
+        fill(&mut self.ioclass, &parent.ioclass);
+        fill(&mut self.ionice, &parent.ionice);
+        fill(&mut self.sched, &parent.sched);
+        fill(&mut self.rtprio, &parent.rtprio);
+        fill(&mut self.oom_score_adj, &parent.oom_score_adj);
+        fill(&mut self.cgroup, &parent.cgroup);
+        fill(&mut self.cpu_affinity, &parent.cpu_affinity);
+        fill(&mut self.freeze_cgroups, &parent.freeze_cgroups);
+    }
     
-    for entry in walkdir(config_dir)? {
-        if let Some(file_name) = entry.file_name() {
-            if file_name.to_string_lossy().ends_with(".types") {
-                info!("Loading types from: {:?}", entry);
-                let content = fs::read_to_string(&entry)?;
-            
-            for line in content.lines() {
-                let line = line.trim();
-                if line.is_empty() || line.starts_with('#') {
-                    continue;
-                }
-                
-                if let Ok(type_def) = serde_json::from_str::<Type>(line) {
-                    types.insert(type_def.name.clone(), type_def);
-                }
+    /// Check the type's own values; inherited ones are checked on the rules
+    /// using it.
+    pub fn validate(&self) -> Result<()> {
+        validate_attributes(
+            self.nice, self.ioclass.as_deref(), self.ionice, self.sched.as_deref(),
+            self.rtprio, self.oom_score_adj, self.cpu_affinity.as_deref(),
+        )
+    }
+}
+
+/// The type `name` with all attributes it inherits through `extends` filled
+/// in. Parents are resolved depth first in the order they are listed, so a
+/// type's own attributes win over its first parent's, which win over the
+/// second parent's.
+pub fn resolve_type(types: &HashMap<String, Type>, name: &str) -> Result<Type> {
+    fn resolve(types: &HashMap<String, Type>, name: &str, chain: &mut Vec<String>) -> Result<Type> {
+        if chain.iter().any(|seen| seen == name) {
+            chain.push(name.to_string());
+            return Err(Error::Type {
+                name: chain[0].clone(),
+                reason: format!("inheritance cycle {}", chain.join(" -> ")),
+            });
+        }
+        let type_def = types.get(name).ok_or_else(|| Error::Type {
+            name: chain.first().map_or(name, String::as_str).to_string(),
+            reason: match chain.last() {
+                Some(child) => format!("{} extends unknown type '{}'", child, name),
+                None => "unknown type".to_string(),
+            },
+        })?;
+        
+        chain.push(name.to_string());
+        let mut resolved = type_def.clone();
+        for parent in type_def.extends.iter().flat_map(TypeRef::names) {
+            resolved.inherit(&resolve(types, parent, chain)?);
+        }
+        chain.pop();
+        Ok(resolved)
+    }
+    
+    resolve(types, name, &mut Vec::new())
+}
+
+/// Resolve all types, reporting and leaving out those with a broken
+/// inheritance chain.
+pub fn resolve_types(types: &HashMap<String, Type>) -> HashMap<String, Type> {
+    let mut resolved = HashMap::new();
+    for name in types.keys() {
+        match resolve_type(types, name) {
+            Ok(type_def) => {
+                resolved.insert(name.clone(), type_def);
             }
+            Err(e) => warn!("Skipping {}", e),
         }
     }
+    resolved
+}
+
+/// Load types from all `layers`; later layers override earlier ones by name.
+pub fn load_types(layers: &[PathBuf]) -> Result<HashMap<String, Type>> {
+    let mut types = HashMap::new();
+    
+    for layer in layers {
+        for entry in walkdir(layer)? {
+            let name = entry.to_string_lossy();
+            if !name.ends_with(".rules.toml") && !name.ends_with(".types") {
+                continue;
+            }
+            let content = fs::read_to_string(&entry).map_err(Error::io(&entry))?;
+            read_types(&entry, &content, &mut types);
+        }
     }
     
     Ok(types)
 }
 
-pub fn load_rules(config_dir: &Path) -> Result<Vec<Rule>> {
-    let mut rules = Vec::new();
+/// Add the types defined in `content` of the file `path`, replacing earlier
+/// ones of the same name.
+pub(crate) fn read_types(path: &Path, content: &str, types: &mut HashMap<String, Type>) {
+    if path.to_string_lossy().ends_with(".rules.toml") {
+        // Problems in the file are reported when its rules are loaded
+        if let Ok(file) = toml_rules::parse(content) {
+            for type_def in file.types {
+                types.insert(type_def.name.clone(), type_def);
+            }
+        }
+        return;
+    }
+    if !path.to_string_lossy().ends_with(".types") {
+        return;
+    }
+    info!("Loading types from: {:?}", path);
     
-    for entry in walkdir(config_dir)? {
-        if let Some(file_name) = entry.file_name() {
-            if file_name.to_string_lossy().ends_with(".rules") {
-                info!("Loading rules from: {:?}", entry);
-                let content = fs::read_to_string(&entry)?;
-            
-            for line in content.lines() {
-                let line = line.trim();
-                if line.is_empty() || line.starts_with('#') {
-                    continue;
-                }
-                
-                if let Ok(rule) = serde_json::from_str::<Rule>(line) {
-                    rules.push(rule);
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        
+        match compat::parse::<Type>(line, Dialect::Native) {
+            Ok(parsed) => {
+                for note in parsed.notes {
+                    warn!("{}:{}: {}", path.display(), number + 1, note);
                 }
+                types.insert(parsed.value.name.clone(), parsed.value);
             }
+            Err(e) => warn!("Skipping type at {}:{}: {}", path.display(), number + 1, e),
         }
     }
+}
+
+/// Load rules from all `layers`; rules of later layers come first so they win.
+pub fn load_rules(layers: &[PathBuf]) -> Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    
+    for layer in layers.iter().rev() {
+        for entry in walkdir(layer)? {
+            if !entry.to_string_lossy().ends_with(".rules") && !entry.to_string_lossy().ends_with(".rules.toml") {
+                continue;
+            }
+            let content = fs::read_to_string(&entry).map_err(Error::io(&entry))?;
+            read_rules(&entry, &content, &mut rules);
+        }
     }
     
     Ok(rules)
 }
 
-pub fn load_all_rules(config_dir: &Path) -> Result<Vec<Rule>> {
-    let types = load_types(config_dir)?;
-    let mut rules = load_rules(config_dir)?;
-    
-    // Apply type definitions to rules
-    for rule in &mut rules {
-        if let Some(ref type_name) = rule.type_name {
-            if let Some(type_def) = types.get(type_name) {
-                // Merge type properties into rule (rule properties take precedence)
-                if rule.nice.is_none() {
-                    rule.nice = type_def.nice;
-                }
-                if rule.ioclass.is_none() {
-                    rule.ioclass = type_def.ioclass.clone();
-                }
-                if rule.ionice.is_none() {
-                    rule.ionice = type_def.ionice;
-                }
-                if rule.sched.is_none() {
-                    rule.sched = type_def.sched.clone();
-                }
-                if rule.rtprio.is_none() {
-                    rule.rtprio = type_def.rtprio;
-                }
-                if rule.oom_score_adj.is_none() {
-                    rule.oom_score_adj = type_def.oom_score_adj;
-                }
-                if rule.cgroup.is_none() {
-                    rule.cgroup = type_def.cgroup.clone();
-                }
+/// Add the valid rules in `content` of the file `path`.
+pub(crate) fn read_rules(path: &Path, content: &str, rules: &mut Vec<Rule>) {
+    if path.to_string_lossy().ends_with(".rules.toml") {
+        info!("Loading rules from: {:?}", path);
+        let file = match toml_rules::parse(content) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Skipping {:?}: {:#}", path, e);
+                return;
             }
+        };
+        for note in file.notes {
+            warn!("{}: {}", path.display(), note);
         }
+        rules.extend(file.rules);
+        return;
+    }
+    if !path.to_string_lossy().ends_with(".rules") {
+        return;
     }
+    info!("Loading rules from: {:?}", path);
     
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        
+        let rule = match compat::parse::<Rule>(line, Dialect::Native) {
+            Ok(parsed) => {
+                for note in parsed.notes {
+                    warn!("{}:{}: {}", path.display(), number + 1, note);
+                }
+                parsed.value
+            }
+            Err(e) => {
+                warn!("Skipping rule at {}:{}: {}", path.display(), number + 1, e);
+                continue;
+            }
+        };
+        rules.push(rule);
+    }
+}
+
+pub fn load_all_rules(layers: &[PathBuf]) -> Result<Vec<Rule>> {
+    let types = resolve_types(&load_types(layers)?);
+    let mut rules = load_rules(layers)?;
+    apply_types(&mut rules, &types);
     Ok(rules)
 }
 
-fn walkdir(dir: &Path) -> Result<Vec<std::path::PathBuf>> {
+/// Fill the attributes rules leave unset from their resolved types, then drop
+/// the rules whose values, own or inherited, are out of range.
+pub(crate) fn apply_types(rules: &mut Vec<Rule>, types: &HashMap<String, Type>) {
+    // Rule attributes win over those of its types, earlier types over later ones
+    for rule in rules.iter_mut() {
+        for type_name in rule.types().to_vec() {
+            match types.get(&type_name) {
+                Some(type_def) => rule.inherit(type_def),
+                None => warn!("Rule '{}' uses unknown or invalid type '{}'", rule.label(), type_name),
+            }
+        }
+    }
+    rules.retain(|rule| match rule.validate() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Skipping rule '{}': {}", rule.label(), e);
+            false
+        }
+    });
+}
+
+fn walkdir(dir: &Path) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
     
     if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
+        let mut entries = fs::read_dir(dir)
+            .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+            .map_err(Error::io(dir))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        
+        for entry in entries {
             let path = entry.path();
             
             if path.is_dir() {
+                // Profiles and user rules are loaded explicitly as their own layers
+                if entry.file_name() == PROFILES_DIR || entry.file_name() == USERS_DIR {
+                    continue;
+                }
                 files.extend(walkdir(&path)?);
             } else {
                 files.push(path);
@@ -155,4 +491,102 @@ fn walkdir(dir: &Path) -> Result<Vec<std::path::PathBuf>> {
     }
     
     Ok(files)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPES: &str = r#"
+{"type": "app", "nice": 0, "ioclass": "best-effort", "cgroup": "apps"}
+{"type": "web-browser", "extends": "app", "nice": -2, "oom_score_adj": 100}
+{"type": "heavy", "nice": 10, "cgroup": "background", "cpu_affinity": "0"}
+{"type": "chromium", "extends": ["web-browser", "heavy"], "ionice": 4}
+{"type": "loop-a", "extends": "loop-b", "nice": 1}
+{"type": "loop-b", "extends": "loop-a"}
+{"type": "orphan", "extends": "missing"}
+"#;
+
+    fn layer(rules: &str) -> (tempfile::TempDir, Vec<PathBuf>) {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("test.types"), TYPES).unwrap();
+        fs::write(tmp.path().join("test.rules"), rules).unwrap();
+        let layers = vec![tmp.path().to_path_buf()];
+        (tmp, layers)
+    }
+
+    #[test]
+    fn types_inherit_depth_first() {
+        let (_tmp, layers) = layer("");
+        let types = load_types(&layers).unwrap();
+        let chromium = resolve_type(&types, "chromium").unwrap();
+
+        assert_eq!(chromium.ionice, Some(4));
+        assert_eq!(chromium.nice, Some(-2));
+        assert_eq!(chromium.oom_score_adj, Some(100));
+        // The first parent's chain wins over the second parent
+        assert_eq!(chromium.cgroup.as_deref(), Some("apps"));
+        assert_eq!(chromium.cpu_affinity.as_deref(), Some("0"));
+
+        let cycle = resolve_type(&types, "loop-a").unwrap_err().to_string();
+        assert_eq!(cycle, "type 'loop-a': inheritance cycle loop-a -> loop-b -> loop-a");
+        let orphan = resolve_type(&types, "orphan").unwrap_err().to_string();
+        assert_eq!(orphan, "type 'orphan': orphan extends unknown type 'missing'");
+
+        let resolved = resolve_types(&types);
+        assert_eq!(resolved.len(), 4);
+        assert!(!resolved.contains_key("loop-b"));
+    }
+
+    #[test]
+    fn rules_mix_in_several_types() {
+        let (_tmp, layers) = layer(r#"
+{"name": "chrome", "type": ["heavy", "web-browser"], "ionice": 1}
+{"name": "firefox", "type": "web-browser", "nice": -5}
+{"name": "broken", "type": ["loop-a", "app"]}
+"#);
+        let rules = load_all_rules(&layers).unwrap();
+
+        assert_eq!(rules[0].types(), ["heavy", "web-browser"]);
+        assert_eq!((rules[0].nice, rules[0].ionice, rules[0].oom_score_adj), (Some(10), Some(1), Some(100)));
+        assert_eq!(rules[0].cgroup.as_deref(), Some("background"));
+        assert_eq!((rules[1].nice, rules[1].cgroup.as_deref()), (Some(-5), Some("apps")));
+        // Unresolvable types are skipped, the others still apply
+        assert_eq!((rules[2].nice, rules[2].cgroup.as_deref()), (Some(0), Some("apps")));
+    }
+
+    #[test]
+    fn rules_are_validated_with_their_types() {
+        let (_tmp, layers) = layer(r#"
+{"name": "loud", "type": "app", "nice": 40}
+{"name": "spinner", "type": "spin"}
+{"name": "quiet", "type": "spin", "rtprio": 10}
+{"name": "fine", "type": "app"}
+"#);
+        let types_file = layers[0].join("test.types");
+        let types = fs::read_to_string(&types_file).unwrap() + r#"{"type": "spin", "rtprio": 500}"# + "\n";
+        fs::write(&types_file, types).unwrap();
+
+        let rules = load_all_rules(&layers).unwrap();
+        // The rule's own value overrides the type's invalid one
+        let names: Vec<&str> = rules.iter().filter_map(|rule| rule.name.as_deref()).collect();
+        assert_eq!(names, ["quiet", "fine"]);
+    }
+
+    #[test]
+    fn invalid_values_inherited_through_extends_are_rejected() {
+        let (_tmp, layers) = layer(r#"
+{"name": "deep", "type": "chromium-lowlat"}
+{"name": "shallow", "type": "chromium"}
+"#);
+        let types_file = layers[0].join("test.types");
+        let types = fs::read_to_string(&types_file).unwrap()
+            + r#"{"type": "lowlat", "rtprio": 500}"# + "\n"
+            + r#"{"type": "chromium-lowlat", "extends": ["chromium", "lowlat"]}"# + "\n";
+        fs::write(&types_file, types).unwrap();
+
+        let rules = load_all_rules(&layers).unwrap();
+        let names: Vec<&str> = rules.iter().filter_map(|rule| rule.name.as_deref()).collect();
+        assert_eq!(names, ["shallow"]);
+    }
+}
@@ -0,0 +1,149 @@
+//! JSON Schema of rule, type and cgroup entries for editor completion and
+//! validation.
+//!
+//! The schemas are derived from the structs the loaders deserialize into, and
+//! ranges and allowed values come from the same constants the validators use.
+
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{
+    InstanceType, Metadata, NumberValidation, RootSchema, Schema, SchemaObject, StringValidation, SubschemaValidation,
+};
+use schemars::{schema_for, JsonSchema};
+use crate::cgroup::CgroupDef;
+use crate::compat::Kind;
+use crate::rules::{Rule, Type};
+use crate::utils;
+
+/// The schema of a single entry of a file of `kind`.
+pub fn for_kind(kind: Kind) -> RootSchema {
+    match kind {
+        Kind::Rules => schema_for!(Rule),
+        Kind::Types => schema_for!(Type),
+        Kind::Cgroups => schema_for!(CgroupDef),
+    }
+}
+
+/// A string restricted to `values`.
+fn choice(values: &[&str]) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(values.iter().map(|&value| value.into()).collect()),
+        ..Default::default()
+    }
+    .into()
+}
+
+/// `ioclass`, as understood by [`crate::actuator::SystemActuator`].
+pub(crate) struct IoClass;
+
+impl JsonSchema for IoClass {
+    fn schema_name() -> String {
+        "IoClass".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        choice(utils::IOCLASSES)
+    }
+}
+
+/// `sched`, as understood by [`crate::actuator::SystemActuator`].
+pub(crate) struct SchedPolicy;
+
+impl JsonSchema for SchedPolicy {
+    fn schema_name() -> String {
+        "SchedPolicy".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        choice(utils::SCHED_POLICIES)
+    }
+}
+
+/// A value written as a plain number or as a string matching `pattern`, for
+/// the limits of `.cgroups` entries that parse their own string forms.
+pub(crate) fn number_or_string(number: InstanceType, pattern: &str, description: &str) -> Schema {
+    let number = SchemaObject {
+        instance_type: Some(number.into()),
+        number: Some(Box::new(NumberValidation {
+            minimum: Some(0.0),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    let text = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some(pattern.to_string()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(description.to_string()),
+            ..Default::default()
+        })),
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![number.into(), text.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn property(kind: Kind, name: &str) -> Value {
+        let schema = serde_json::to_value(for_kind(kind)).unwrap();
+        schema["properties"][name].clone()
+    }
+
+    #[test]
+    fn ranges_and_choices_follow_the_validators() {
+        let nice = property(Kind::Rules, "nice");
+        assert_eq!((nice["minimum"].clone(), nice["maximum"].clone()), (json!(-20.0), json!(19.0)));
+        let rtprio = property(Kind::Types, "rtprio");
+        assert_eq!((rtprio["minimum"].clone(), rtprio["maximum"].clone()), (json!(1.0), json!(99.0)));
+
+        let schema = serde_json::to_value(for_kind(Kind::Rules)).unwrap();
+        assert_eq!(schema["definitions"]["IoClass"]["enum"], json!(utils::IOCLASSES));
+        assert_eq!(schema["definitions"]["SchedPolicy"]["enum"], json!(utils::SCHED_POLICIES));
+        assert!(schema["required"].as_array().is_none_or(Vec::is_empty), "{}", schema["required"]);
+        assert!(schema["properties"]["type"].is_object());
+        assert!(schema["properties"]["match"].is_object());
+
+        let cgroups = serde_json::to_value(for_kind(Kind::Cgroups)).unwrap();
+        assert_eq!(cgroups["required"], json!(["cgroup"]));
+        assert_eq!(cgroups["properties"]["CPUQuotaTotal"]["maximum"], json!(100.0));
+        assert!(cgroups["properties"]["MemoryMax"].is_object());
+    }
+
+    #[test]
+    fn limit_patterns_accept_what_the_parsers_accept() {
+        let pattern = |kind: &str| -> regex::Regex {
+            let cgroups = serde_json::to_value(for_kind(Kind::Cgroups)).unwrap();
+            let text = &cgroups["definitions"][kind]["anyOf"][1]["pattern"];
+            regex::Regex::new(text.as_str().unwrap()).unwrap()
+        };
+
+        let memory = pattern("MemoryLimit");
+        for value in ["max", "infinity", "512M", "2g", "1024", "25%", " 4 G "] {
+            assert!(memory.is_match(value), "{}", value);
+            assert!(serde_json::from_value::<crate::cgroup::MemoryLimit>(json!(value)).is_ok(), "{}", value);
+        }
+        for value in ["lots", "5X", "%"] {
+            assert!(!memory.is_match(value), "{}", value);
+        }
+
+        let latency = pattern("LatencyTarget");
+        for value in ["25ms", "500us", "0.1s", "2"] {
+            assert!(latency.is_match(value), "{}", value);
+            assert!(serde_json::from_value::<crate::cgroup::LatencyTarget>(json!(value)).is_ok(), "{}", value);
+        }
+        assert!(!latency.is_match("soon"));
+    }
+}
@@ -0,0 +1,502 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{Result, Context};
+use log::{debug, info, warn};
+use zbus::blocking::Connection;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::{OwnedObjectPath, Value};
+use crate::cgroup::{CgroupDef, CpuQuota, IoLimit};
+use crate::hierarchy::CgroupFs;
+use crate::power::PowerSource;
+use crate::topology::{self, CpuSet, TOPOLOGY};
+
+/// Slice holding every slice created by the daemon.
+pub const ROOT_SLICE: &str = "ananicy.slice";
+const UNIT_PREFIX: &str = "ananicy";
+const UNIT_EXISTS: &str = "org.freedesktop.systemd1.UnitExists";
+
+#[zbus::proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1",
+    gen_async = false
+)]
+trait Manager {
+    fn start_transient_unit(
+        &self,
+        name: &str,
+        mode: &str,
+        properties: &[(&str, Value<'_>)],
+        aux: &[(&str, &[(&str, Value<'_>)])],
+    ) -> zbus::Result<OwnedObjectPath>;
+
+    fn set_unit_properties(
+        &self,
+        name: &str,
+        runtime: bool,
+        properties: &[(&str, Value<'_>)],
+    ) -> zbus::Result<()>;
+
+    fn stop_unit(&self, name: &str, mode: &str) -> zbus::Result<OwnedObjectPath>;
+
+    fn freeze_unit(&self, name: &str) -> zbus::Result<()>;
+
+    fn thaw_unit(&self, name: &str) -> zbus::Result<()>;
+}
+
+/// Unit properties in the form expected by the systemd D-Bus API.
+pub type Properties = Vec<(&'static str, Value<'static>)>;
+
+/// Client for the systemd manager, creating transient slices and scopes.
+pub struct Systemd {
+    proxy: ManagerProxy<'static>,
+}
+
+impl fmt::Debug for Systemd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Systemd").finish_non_exhaustive()
+    }
+}
+
+impl Systemd {
+    /// Connect to the manager on the system bus.
+    pub fn connect() -> Result<Self> {
+        let connection = Connection::system().context("connect to the system bus")?;
+        Self::with_connection(&connection)
+    }
+
+    pub fn with_connection(connection: &Connection) -> Result<Self> {
+        let proxy = ManagerProxy::builder(connection)
+            .cache_properties(CacheProperties::No)
+            .build()?;
+        Ok(Self { proxy })
+    }
+
+    /// Create the transient slice `unit`, or update its properties if it exists.
+    pub fn ensure_slice(&self, unit: &str, properties: Properties) -> Result<()> {
+        match self.proxy.start_transient_unit(unit, "fail", &properties, &[]) {
+            Ok(_) => {
+                info!("Created transient unit {}", unit);
+                Ok(())
+            }
+            Err(zbus::Error::MethodError(name, _, _)) if name.as_str() == UNIT_EXISTS => {
+                debug!("Updating existing unit {}", unit);
+                self.set_properties(unit, properties)
+            }
+            Err(e) => Err(e).with_context(|| format!("start transient unit {}", unit)),
+        }
+    }
+
+    /// Change runtime properties of `unit`.
+    pub fn set_properties(&self, unit: &str, properties: Properties) -> Result<()> {
+        self.proxy.set_unit_properties(unit, true, &properties)
+            .with_context(|| format!("set properties of {}", unit))
+    }
+
+    /// Stop `unit` together with every unit below it.
+    pub fn stop_unit(&self, unit: &str) -> Result<()> {
+        self.proxy.stop_unit(unit, "replace")
+            .map(|_| info!("Stopped unit {}", unit))
+            .with_context(|| format!("stop {}", unit))
+    }
+
+    /// Freeze or thaw every process below `unit`.
+    pub fn set_frozen(&self, unit: &str, frozen: bool) -> Result<()> {
+        if frozen {
+            self.proxy.freeze_unit(unit).with_context(|| format!("freeze {}", unit))
+        } else {
+            self.proxy.thaw_unit(unit).with_context(|| format!("thaw {}", unit))
+        }
+    }
+
+    /// Move `pid` into the transient scope `scope` below `slice`.
+    pub fn start_scope(&self, scope: &str, slice: &str, pid: i32) -> Result<()> {
+        let properties: Properties = vec![
+            ("Description", Value::from(format!("Process {} placed by rust-ananicy", pid))),
+            ("Slice", Value::from(slice.to_string())),
+            ("PIDs", Value::from(vec![pid as u32])),
+        ];
+        match self.proxy.start_transient_unit(scope, "fail", &properties, &[]) {
+            Ok(_) => Ok(()),
+            // Already placed by an earlier scan
+            Err(zbus::Error::MethodError(name, _, _)) if name.as_str() == UNIT_EXISTS => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("start transient unit {}", scope)),
+        }
+    }
+}
+
+/// Escape one component of a unit name like `systemd-escape` does.
+pub fn escape(name: &str) -> String {
+    let mut escaped = String::new();
+    for (i, byte) in name.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if i == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Slice unit for the cgroup at `rel_path`, e.g. `ananicy-background-indexers.slice`.
+pub fn slice_name(rel_path: &Path) -> String {
+    let mut name = UNIT_PREFIX.to_string();
+    for component in rel_path.iter() {
+        name.push('-');
+        name.push_str(&escape(&component.to_string_lossy()));
+    }
+    name + ".slice"
+}
+
+/// Path of the slice's cgroup below the unified hierarchy root.
+pub fn slice_cgroup(rel_path: &Path) -> PathBuf {
+    let mut path = PathBuf::from(ROOT_SLICE);
+    let mut prefix = PathBuf::new();
+    for component in rel_path.iter() {
+        prefix.push(component);
+        path.push(slice_name(&prefix));
+    }
+    path
+}
+
+pub fn scope_name(cgroup: &str, pid: i32) -> String {
+    format!("{}-{}-{}.scope", UNIT_PREFIX, escape(cgroup), pid)
+}
+
+/// CPU bandwidth and weight for `cpu_quota` on `ncpu` usable CPUs.
+pub fn cpu_quota_properties(cpu_quota: CpuQuota, ncpu: usize) -> Properties {
+    let per_sec = cpu_quota.usec_per_sec(ncpu).unwrap_or(u64::MAX);
+    vec![
+        ("CPUQuotaPerSecUSec", Value::U64(per_sec)),
+        ("CPUWeight", Value::U64((cpu_quota.total_percent(ncpu) as u64 * 100).clamp(1, 10000))),
+    ]
+}
+
+/// CPUs a slice for `def` may use: its `AllowedCPUs`, or every online CPU.
+pub fn slice_cpus(def: &CgroupDef, sys_root: &Path) -> usize {
+    def.allowed_cpus.as_deref()
+        .and_then(|spec| TOPOLOGY.resolve(spec).ok())
+        .map_or_else(|| topology::online_cpus(sys_root), |cpus| cpus.len())
+}
+
+pub fn io_weight_properties(weight: u32) -> Properties {
+    vec![("IOWeight", Value::U64(weight.clamp(1, 10000) as u64))]
+}
+
+/// All settings of `def` as slice properties.
+pub fn slice_properties(def: &CgroupDef, source: PowerSource, cgroup_fs: &CgroupFs) -> Properties {
+    let proc_root = cgroup_fs.proc_root();
+    let mut properties = vec![("Description", Value::from(format!("rust-ananicy cgroup {}", def.cgroup)))];
+    properties.extend(cpu_quota_properties(def.cpu_quota_for(source), slice_cpus(def, cgroup_fs.sys_root())));
+
+    let memory = [
+        ("MemoryLow", def.memory_low),
+        ("MemoryHigh", def.memory_high),
+        ("MemoryMax", def.memory_max),
+        ("MemorySwapMax", def.memory_swap_max),
+    ];
+    for (name, limit) in memory {
+        match limit.map(|limit| limit.bytes(proc_root)) {
+            Some(Ok(bytes)) => properties.push((name, Value::U64(bytes.unwrap_or(u64::MAX)))),
+            Some(Err(e)) => warn!("Cgroup {}: not setting {}: {:#}", def.cgroup, name, e),
+            None => {}
+        }
+    }
+    if let Some(weight) = def.io_weight {
+        properties.extend(io_weight_properties(weight));
+    }
+
+    let io = [
+        ("IOReadBandwidthMax", &def.io_read_bandwidth_max),
+        ("IOWriteBandwidthMax", &def.io_write_bandwidth_max),
+        ("IOReadIOPSMax", &def.io_read_iops_max),
+        ("IOWriteIOPSMax", &def.io_write_iops_max),
+    ];
+    for (name, limits) in io {
+        if !limits.is_empty() {
+            // systemd resolves device nodes and paths itself
+            let value: Vec<(String, u64)> = limits.iter()
+                .map(|(device, limit)| (device.clone(), io_limit_value(*limit)))
+                .collect();
+            properties.push((name, Value::from(value)));
+        }
+    }
+    if !def.io_latency_target.is_empty() {
+        let value: Vec<(String, u64)> = def.io_latency_target.iter()
+            .map(|(device, target)| (device.clone(), target.0))
+            .collect();
+        properties.push(("IODeviceLatencyTargetUSec", Value::from(value)));
+    }
+
+    if let Some(ref spec) = def.allowed_cpus {
+        if let Ok(cpus) = TOPOLOGY.resolve(spec) {
+            properties.push(("AllowedCPUs", Value::from(cpu_mask(&cpus))));
+        }
+    }
+    if let Some(ref nodes) = def.allowed_memory_nodes {
+        if let Ok(nodes) = CpuSet::parse_list(nodes) {
+            properties.push(("AllowedMemoryNodes", Value::from(cpu_mask(&nodes))));
+        }
+    }
+    if let Some(limit) = def.tasks_max {
+        properties.push(("TasksMax", Value::U64(limit.value(proc_root).parse().unwrap_or(u64::MAX))));
+    }
+    properties
+}
+
+fn io_limit_value(limit: IoLimit) -> u64 {
+    match limit {
+        IoLimit::Value(value) => value,
+        IoLimit::Max => u64::MAX,
+    }
+}
+
+/// Bit mask with bit `n` of byte `n / 8` set for every member, as used by `AllowedCPUs`.
+fn cpu_mask(set: &CpuSet) -> Vec<u8> {
+    let mut mask = Vec::new();
+    for cpu in set.iter() {
+        let byte = cpu as usize / 8;
+        if mask.len() <= byte {
+            mask.resize(byte + 1, 0);
+        }
+        mask[byte] |= 1 << (cpu % 8);
+    }
+    mask
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use zbus::zvariant::OwnedValue;
+
+    #[derive(Debug)]
+    enum Call {
+        Start(String, Vec<(String, OwnedValue)>),
+        Set(String, Vec<(String, OwnedValue)>),
+        Stop(String),
+        Freeze(String),
+        Thaw(String),
+    }
+
+    #[derive(Debug, zbus::DBusError)]
+    #[zbus(prefix = "org.freedesktop.systemd1")]
+    enum MockError {
+        #[zbus(error)]
+        ZBus(zbus::Error),
+        UnitExists(String),
+    }
+
+    /// Stand-in for the systemd manager, remembering units and calls.
+    #[derive(Debug, Clone, Default)]
+    struct MockManager {
+        units: Arc<Mutex<BTreeSet<String>>>,
+        calls: Arc<Mutex<Vec<Call>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.systemd1.Manager")]
+    impl MockManager {
+        fn start_transient_unit(
+            &self,
+            name: String,
+            _mode: String,
+            properties: Vec<(String, OwnedValue)>,
+            _aux: Vec<(String, Vec<(String, OwnedValue)>)>,
+        ) -> Result<OwnedObjectPath, MockError> {
+            self.calls.lock().unwrap().push(Call::Start(name.clone(), properties));
+            if !self.units.lock().unwrap().insert(name.clone()) {
+                return Err(MockError::UnitExists(format!("Unit {} already exists.", name)));
+            }
+            Ok(OwnedObjectPath::try_from("/org/freedesktop/systemd1/job/1").unwrap())
+        }
+
+        fn set_unit_properties(
+            &self,
+            name: String,
+            _runtime: bool,
+            properties: Vec<(String, OwnedValue)>,
+        ) -> Result<(), MockError> {
+            self.calls.lock().unwrap().push(Call::Set(name, properties));
+            Ok(())
+        }
+
+        fn stop_unit(&self, name: String, _mode: String) -> Result<OwnedObjectPath, MockError> {
+            self.calls.lock().unwrap().push(Call::Stop(name.clone()));
+            self.units.lock().unwrap().remove(&name);
+            Ok(OwnedObjectPath::try_from("/org/freedesktop/systemd1/job/2").unwrap())
+        }
+
+        fn freeze_unit(&self, name: String) -> Result<(), MockError> {
+            self.calls.lock().unwrap().push(Call::Freeze(name));
+            Ok(())
+        }
+
+        fn thaw_unit(&self, name: String) -> Result<(), MockError> {
+            self.calls.lock().unwrap().push(Call::Thaw(name));
+            Ok(())
+        }
+    }
+
+    /// Connect a client to `mock` over a socket pair; the server connection must be kept alive.
+    fn connect(mock: &MockManager) -> (Systemd, Connection) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mock = mock.clone();
+        let server = std::thread::spawn(move || {
+            zbus::blocking::connection::Builder::unix_stream(server)
+                .server(zbus::Guid::generate()).unwrap()
+                .p2p()
+                .serve_at("/org/freedesktop/systemd1", mock).unwrap()
+                .build().unwrap()
+        });
+        let client = zbus::blocking::connection::Builder::unix_stream(client)
+            .p2p()
+            .build().unwrap();
+        let server = server.join().unwrap();
+        (Systemd::with_connection(&client).unwrap(), server)
+    }
+
+    fn property(properties: &[(String, OwnedValue)], name: &str) -> Option<u64> {
+        properties.iter()
+            .find(|(key, _)| key == name)
+            .and_then(|(_, value)| u64::try_from(value).ok())
+    }
+
+    #[test]
+    fn ensure_slice_starts_then_updates() {
+        let mock = MockManager::default();
+        let (systemd, _server) = connect(&mock);
+
+        systemd.ensure_slice("ananicy-cpu50.slice", cpu_quota_properties(CpuQuota::Total(50), 4)).unwrap();
+        systemd.ensure_slice("ananicy-cpu50.slice", cpu_quota_properties(CpuQuota::Total(30), 4)).unwrap();
+
+        let calls = mock.calls.lock().unwrap();
+        assert_eq!(calls.len(), 3);
+        match &calls[0] {
+            Call::Start(name, properties) => {
+                assert_eq!(name, "ananicy-cpu50.slice");
+                assert_eq!(property(properties, "CPUQuotaPerSecUSec"), Some(2_000_000));
+                assert_eq!(property(properties, "CPUWeight"), Some(5000));
+            }
+            other => panic!("unexpected call {:?}", other),
+        }
+        assert!(matches!(&calls[1], Call::Start(name, _) if name == "ananicy-cpu50.slice"));
+        match &calls[2] {
+            Call::Set(name, properties) => {
+                assert_eq!(name, "ananicy-cpu50.slice");
+                assert_eq!(property(properties, "CPUWeight"), Some(3000));
+            }
+            other => panic!("unexpected call {:?}", other),
+        }
+    }
+
+    #[test]
+    fn start_scope_places_pid_in_slice() {
+        let mock = MockManager::default();
+        let (systemd, _server) = connect(&mock);
+
+        let scope = scope_name("games", 4242);
+        systemd.start_scope(&scope, "ananicy-games.slice", 4242).unwrap();
+        // A second placement of the same process is not an error
+        systemd.start_scope(&scope, "ananicy-games.slice", 4242).unwrap();
+
+        let calls = mock.calls.lock().unwrap();
+        match &calls[0] {
+            Call::Start(name, properties) => {
+                assert_eq!(name, "ananicy-games-4242.scope");
+                let slice = properties.iter().find(|(key, _)| key == "Slice").unwrap();
+                assert_eq!(String::try_from(slice.1.try_clone().unwrap()).unwrap(), "ananicy-games.slice");
+                let pids = properties.iter().find(|(key, _)| key == "PIDs").unwrap();
+                assert_eq!(Vec::<u32>::try_from(pids.1.try_clone().unwrap()).unwrap(), vec![4242]);
+            }
+            other => panic!("unexpected call {:?}", other),
+        }
+    }
+
+    #[test]
+    fn set_frozen_freezes_and_thaws_unit() {
+        let mock = MockManager::default();
+        let (systemd, _server) = connect(&mock);
+
+        systemd.set_frozen("ananicy-background.slice", true).unwrap();
+        systemd.set_frozen("ananicy-background.slice", false).unwrap();
+
+        let calls = mock.calls.lock().unwrap();
+        assert!(matches!(&calls[0], Call::Freeze(name) if name == "ananicy-background.slice"));
+        assert!(matches!(&calls[1], Call::Thaw(name) if name == "ananicy-background.slice"));
+    }
+
+    #[test]
+    fn remove_all_empties_and_stops_slices() {
+        use std::fs;
+        use crate::cgroup::{self, Backend, CgroupController};
+        use crate::hierarchy::CgroupFs;
+
+        let mock = MockManager::default();
+        let (systemd, _server) = connect(&mock);
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("cgroup");
+        fs::create_dir_all(root.join("user.slice")).unwrap();
+        fs::write(root.join("cgroup.controllers"), "cpu io memory pids").unwrap();
+        let proc = tmp.path().join("proc");
+        fs::create_dir_all(proc.join("9009")).unwrap();
+        fs::write(proc.join("9009/cgroup"), "0::/user.slice\n").unwrap();
+        let cgroup_fs = Arc::new(CgroupFs::detect(&proc, Some(&root)));
+
+        let def = serde_json::from_str(r#"{"cgroup": "games", "CPUQuota": 50}"#).unwrap();
+        let backend = Backend::Systemd(Arc::new(systemd));
+        let cg = CgroupController::new(&def, Path::new("games"), PowerSource::Ac, &backend, &cgroup_fs).unwrap();
+        cg.add_pid(9009).unwrap();
+        // What systemd would have done for the scope
+        let scope = root.join(slice_cgroup(Path::new("games"))).join(scope_name("games", 9009));
+        fs::create_dir_all(&scope).unwrap();
+        fs::write(scope.join("cgroup.procs"), "9009\n").unwrap();
+
+        let cgroups = [("games".to_string(), cg)].into_iter().collect();
+        let cleanup = cgroup::remove_all(&tmp.path().join("state"), &cgroups, &cgroup_fs).unwrap();
+        assert_eq!(cleanup.removed, vec![PathBuf::from("games")]);
+        assert_eq!(fs::read_to_string(root.join("user.slice/cgroup.procs")).unwrap(), "9009");
+
+        let calls = mock.calls.lock().unwrap();
+        let stopped: Vec<&str> = calls.iter()
+            .filter_map(|call| match call {
+                Call::Stop(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(stopped, ["ananicy-games.slice", ROOT_SLICE]);
+    }
+
+    #[test]
+    fn unit_names_follow_slice_hierarchy() {
+        assert_eq!(slice_name(Path::new("background/indexers")), "ananicy-background-indexers.slice");
+        assert_eq!(slice_name(Path::new("my-app")), "ananicy-my\\x2dapp.slice");
+        assert_eq!(
+            slice_cgroup(Path::new("background/indexers")),
+            PathBuf::from("ananicy.slice/ananicy-background.slice/ananicy-background-indexers.slice")
+        );
+    }
+
+    #[test]
+    fn slice_properties_cover_definition() {
+        let def: CgroupDef = serde_json::from_str(
+            r#"{"cgroup": "builds", "CPUQuota": 100, "MemoryHigh": "50%", "MemoryMax": "1G", "TasksMax": 512, "AllowedCPUs": "0-1,8"}"#,
+        ).unwrap();
+        // No meminfo below the root, so the percentage is left out rather than zero
+        let cgroup_fs = CgroupFs::detect(Path::new("/nonexistent"), Some(Path::new("/nonexistent")));
+        let properties: Vec<(String, OwnedValue)> = slice_properties(&def, PowerSource::Ac, &cgroup_fs).into_iter()
+            .map(|(name, value)| (name.to_string(), OwnedValue::try_from(value).unwrap()))
+            .collect();
+
+        // One full CPU out of the three allowed, as with systemd's CPUQuota=100%
+        assert_eq!(property(&properties, "CPUQuotaPerSecUSec"), Some(1_000_000));
+        assert_eq!(property(&properties, "MemoryMax"), Some(1 << 30));
+        assert_eq!(property(&properties, "MemoryHigh"), None);
+        assert_eq!(property(&properties, "TasksMax"), Some(512));
+        let cpus = properties.iter().find(|(key, _)| key == "AllowedCPUs").unwrap();
+        assert_eq!(Vec::<u8>::try_from(cpus.1.try_clone().unwrap()).unwrap(), vec![0b11, 0b1]);
+    }
+}
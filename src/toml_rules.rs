@@ -0,0 +1,181 @@
+//! The TOML rule format of `*.rules.toml` files.
+//!
+//! ```toml
+//! [[rule]]
+//! name = "firefox"  # comments may follow any value
+//! nice = -2
+//!
+//! [[rule]]
+//! names = ["chrome", "chromium", "brave"]
+//! cgroup = "browser"
+//!
+//! # Rules grouped under a type; attributes next to `rule` define the type
+//! [type.Game]
+//! nice = -5
+//! ioclass = "best-effort"
+//! rule = [
+//!     { name = "steam" },
+//!     { names = ["dota2", "cs2"], oom_score_adj = -100 },
+//! ]
+//! ```
+//!
+//! Entries go through the same lenient parsing as JSON lines, see
+//! [`compat::parse_map`].
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+use crate::compat::{self, Dialect};
+use crate::rules::{Rule, Type};
+
+/// The entries of a `*.rules.toml` file, in file order.
+#[derive(Debug, Default)]
+pub struct RuleFile {
+    pub rules: Vec<Rule>,
+    pub types: Vec<Type>,
+    /// Skipped entries and dropped fields, prefixed with where they are.
+    pub notes: Vec<String>,
+}
+
+pub fn parse(content: &str) -> Result<RuleFile> {
+    let document: Map<String, Value> = toml::from_str(content)?;
+    let mut file = RuleFile::default();
+
+    for (key, value) in document {
+        match (key.as_str(), value) {
+            ("rule", Value::Array(entries)) => {
+                for (index, entry) in entries.into_iter().enumerate() {
+                    file.add_rules(entry, None, &format!("rule {}", index + 1));
+                }
+            }
+            ("type", Value::Object(groups)) => {
+                for (name, group) in groups {
+                    let Value::Object(group) = group else {
+                        bail!("type.{} must be a table", name);
+                    };
+                    file.add_group(&name, group);
+                }
+            }
+            ("rule", _) => bail!("rule must be an array of tables"),
+            ("type", _) => bail!("type must be a table of types"),
+            (other, _) => file.notes.push(format!("unknown key {}, ignored", other)),
+        }
+    }
+
+    Ok(file)
+}
+
+impl RuleFile {
+    /// A `[type.<name>]` table: the type itself if it sets any attributes,
+    /// and the rules listed in its `rule` array.
+    fn add_group(&mut self, name: &str, mut group: Map<String, Value>) {
+        let rules = group.shift_remove("rule");
+        if !group.is_empty() {
+            group.insert("type".to_string(), name.into());
+            match compat::parse_map::<Type>(group, Dialect::Native) {
+                Ok(entry) => {
+                    self.notes.extend(entry.notes.into_iter().map(|note| format!("type {}: {}", name, note)));
+                    self.types.push(entry.value);
+                }
+                Err(e) => self.notes.push(format!("type {}: skipped: {}", name, e)),
+            }
+        }
+
+        match rules {
+            Some(Value::Array(entries)) => {
+                for (index, entry) in entries.into_iter().enumerate() {
+                    self.add_rules(entry, Some(name), &format!("type {} rule {}", name, index + 1));
+                }
+            }
+            Some(_) => self.notes.push(format!("type {}: rule must be an array of tables, skipped", name)),
+            None => {}
+        }
+    }
+
+    /// One rule entry, expanded to one rule per name of `names`.
+    fn add_rules(&mut self, entry: Value, group: Option<&str>, location: &str) {
+        let Value::Object(mut entry) = entry else {
+            self.notes.push(format!("{}: not a table, skipped", location));
+            return;
+        };
+
+        let names = match entry.shift_remove("names") {
+            None => vec![None],
+            Some(_) if entry.contains_key("name") => {
+                self.notes.push(format!("{}: set either name or names, skipped", location));
+                return;
+            }
+            Some(Value::Array(names)) if names.iter().all(Value::is_string) => {
+                names.into_iter().map(Some).collect()
+            }
+            Some(_) => {
+                self.notes.push(format!("{}: names must be a list of strings, skipped", location));
+                return;
+            }
+        };
+
+        // The group's type comes after any the rule lists itself
+        if let Some(group) = group {
+            let types = match entry.shift_remove("type") {
+                None => Value::from(group),
+                Some(Value::Array(mut types)) => {
+                    types.push(group.into());
+                    Value::Array(types)
+                }
+                Some(own) => Value::Array(vec![own, group.into()]),
+            };
+            entry.insert("type".to_string(), types);
+        }
+
+        for name in names {
+            let mut entry = entry.clone();
+            if let Some(name) = name {
+                entry.insert("name".to_string(), name);
+            }
+            match compat::parse_map::<Rule>(entry, Dialect::Native) {
+                Ok(parsed) => {
+                    self.notes.extend(parsed.notes.into_iter().map(|note| format!("{}: {}", location, note)));
+                    self.rules.push(parsed.value);
+                }
+                Err(e) => self.notes.push(format!("{}: skipped: {}", location, e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_and_name_lists_expand() {
+        let file = parse(r#"
+[[rule]]
+name = "firefox"  # browser
+nice = -2
+
+[type.Game]
+nice = -5
+rule = [
+    { name = "steam" },
+    { names = ["dota2", "cs2"], type = "LowLatency", oom_score_adj = -100 },
+    { name = "bad", names = ["x"] },
+]
+
+[[rule]]
+names = ["chrome", "chromium"]
+cgroup = "browser"
+latency_nice = 5
+"#).unwrap();
+
+        let names: Vec<_> = file.rules.iter().map(|rule| rule.name.as_deref().unwrap()).collect();
+        assert_eq!(names, ["firefox", "chrome", "chromium", "steam", "dota2", "cs2"]);
+        assert_eq!(file.rules[3].types(), ["Game"]);
+        assert_eq!(file.rules[4].types(), ["LowLatency", "Game"]);
+        assert_eq!(file.rules[5].oom_score_adj, Some(-100));
+        assert_eq!(file.rules[2].cgroup.as_deref(), Some("browser"));
+
+        assert_eq!(file.types.len(), 1);
+        assert_eq!((file.types[0].name.as_str(), file.types[0].nice), ("Game", Some(-5)));
+        assert_eq!(file.notes.len(), 3, "{:?}", file.notes);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io;
+use log::warn;
+use once_cell::sync::{Lazy, OnceCell};
+use crate::error::{Error, Result};
+
+const DEFAULT_SYS_ROOT: &str = "/sys";
+
+/// Root of the sysfs tree [`TOPOLOGY`] is read from, `/sys` unless set.
+static SYS_ROOT: OnceCell<PathBuf> = OnceCell::new();
+
+/// Highest id accepted in a list, the kernel's largest `NR_CPUS`. Keeps a
+/// typo like `0-4000000000` from allocating billions of entries.
+const MAX_LIST_ID: u32 = 8191;
+
+/// A set of CPU (or memory node) ids in kernel list format, e.g. `0-3,8`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuSet(BTreeSet<u32>);
+
+impl CpuSet {
+    pub fn parse_list(list: &str) -> Result<Self> {
+        let invalid = |reason: String| Error::CpuSet { spec: list.trim().to_string(), reason };
+        let parse = |id: &str| id.trim().parse::<u32>().map_err(|e| invalid(format!("'{}': {}", id.trim(), e)));
+        let mut set = BTreeSet::new();
+        for part in list.trim().split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse(start)?, parse(end)?);
+                    if start > end {
+                        return Err(invalid(format!("range '{}' is reversed", part)));
+                    }
+                    if end > MAX_LIST_ID {
+                        return Err(invalid(format!("{} is above the highest id {}", end, MAX_LIST_ID)));
+                    }
+                    set.extend(start..=end);
+                }
+                None => {
+                    let id = parse(part)?;
+                    if id > MAX_LIST_ID {
+                        return Err(invalid(format!("{} is above the highest id {}", id, MAX_LIST_ID)));
+                    }
+                    set.insert(id);
+                }
+            }
+        }
+        Ok(Self(set))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().copied()
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ranges = Vec::new();
+        let mut iter = self.0.iter().copied().peekable();
+        while let Some(start) = iter.next() {
+            let mut end = start;
+            while iter.peek() == Some(&(end + 1)) {
+                end = iter.next().unwrap();
+            }
+            ranges.push(if start == end { start.to_string() } else { format!("{}-{}", start, end) });
+        }
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+/// Classification of the online CPUs into symbolic sets.
+#[derive(Debug, Clone, Default)]
+pub struct Topology {
+    pub online: CpuSet,
+    /// Big / P-cores; all CPUs on homogeneous machines.
+    pub performance: CpuSet,
+    /// Little / E-cores; all CPUs on homogeneous machines.
+    pub efficiency: CpuSet,
+    /// CPUs sharing the largest L3 cache, e.g. the V-Cache CCD.
+    pub vcache: CpuSet,
+}
+
+pub static TOPOLOGY: Lazy<Topology> = Lazy::new(|| Topology::detect(&sys_root().join("devices")));
+
+/// Read [`TOPOLOGY`] below `sys_root` instead of `/sys`, like the configured
+/// `sys_root`. Only takes effect before the topology is first used.
+pub fn use_sys_root(sys_root: &Path) {
+    if SYS_ROOT.set(sys_root.to_path_buf()).is_err() && SYS_ROOT.get().map(PathBuf::as_path) != Some(sys_root) {
+        warn!("CPU topology is already read from {}, ignoring {}", self::sys_root().display(), sys_root.display());
+    }
+}
+
+fn sys_root() -> &'static Path {
+    SYS_ROOT.get_or_init(|| PathBuf::from(DEFAULT_SYS_ROOT))
+}
+
+impl Topology {
+    /// Detect the topology from a `/sys/devices`-like tree.
+    pub fn detect(sys_devices: &Path) -> Self {
+        let cpu_dir = sys_devices.join("system/cpu");
+        let online = read_list(&cpu_dir.join("online")).unwrap_or_default();
+
+        // Intel hybrid parts expose a PMU per core type
+        let core = read_list(&sys_devices.join("cpu_core/cpus"));
+        let atom = read_list(&sys_devices.join("cpu_atom/cpus"));
+        let (performance, efficiency) = match (core, atom) {
+            (Some(core), Some(atom)) if !core.is_empty() && !atom.is_empty() => (core, atom),
+            _ => split_by_capacity(&cpu_dir, &online),
+        };
+
+        let vcache = largest_l3(&cpu_dir, &online);
+        Self { online, performance, efficiency, vcache }
+    }
+
+    /// Resolve a symbolic name or a CPU list to a set of CPUs.
+    pub fn resolve(&self, spec: &str) -> Result<CpuSet> {
+        let set = match spec.trim() {
+            "all" => self.online.clone(),
+            "performance" | "p-cores" => self.performance.clone(),
+            "efficiency" | "e-cores" => self.efficiency.clone(),
+            "vcache" => self.vcache.clone(),
+            list => CpuSet::parse_list(list)?,
+        };
+        if set.is_empty() {
+            return Err(Error::CpuSet { spec: spec.to_string(), reason: "empty on this machine".to_string() });
+        }
+        Ok(set)
+    }
+
+    /// Resolve `spec` to CPUs a process can be pinned to: online, and within
+    /// the mask `sched_setaffinity` takes.
+    pub fn resolve_affinity(&self, spec: &str) -> Result<CpuSet> {
+        let set = self.resolve(spec)?;
+        let invalid = |reason: String| Error::CpuSet { spec: spec.to_string(), reason };
+        if let Some(cpu) = set.iter().find(|&cpu| cpu as usize >= libc::CPU_SETSIZE as usize) {
+            return Err(invalid(format!("CPU {} is beyond the {} CPUs an affinity mask holds", cpu, libc::CPU_SETSIZE)));
+        }
+        // Without a readable sysfs nothing is known to be online
+        if let Some(cpu) = set.iter().find(|&cpu| !self.online.is_empty() && !self.online.0.contains(&cpu)) {
+            return Err(invalid(format!("CPU {} is not online (online: {})", cpu, self.online)));
+        }
+        Ok(set)
+    }
+}
+
+/// Number of CPUs online right now according to the sysfs tree at
+/// `sys_root`; unlike `TOPOLOGY` this follows hotplug.
+pub fn online_cpus(sys_root: &Path) -> usize {
+    read_list(&sys_root.join("devices/system/cpu/online"))
+        .map(|cpus| cpus.len())
+        .filter(|&len| len > 0)
+        .unwrap_or_else(num_cpus::get)
+}
+
+/// Split by `cpu_capacity` (ARM big.LITTLE, recent x86); equal capacities mean
+/// a homogeneous machine where both sets contain every CPU.
+fn split_by_capacity(cpu_dir: &Path, online: &CpuSet) -> (CpuSet, CpuSet) {
+    let capacities: BTreeMap<u32, u32> = online.iter()
+        .filter_map(|cpu| {
+            let path = cpu_dir.join(format!("cpu{}/cpu_capacity", cpu));
+            fs::read_to_string(path).ok()?.trim().parse().ok().map(|c| (cpu, c))
+        })
+        .collect();
+
+    let max = capacities.values().copied().max();
+    let min = capacities.values().copied().min();
+    match (max, min) {
+        (Some(max), Some(min)) if max != min && capacities.len() == online.len() => {
+            let big = capacities.iter().filter(|(_, &c)| c == max).map(|(&cpu, _)| cpu).collect();
+            let little = capacities.iter().filter(|(_, &c)| c != max).map(|(&cpu, _)| cpu).collect();
+            (CpuSet(big), CpuSet(little))
+        }
+        _ => (online.clone(), online.clone()),
+    }
+}
+
+fn largest_l3(cpu_dir: &Path, online: &CpuSet) -> CpuSet {
+    let mut best: Option<(u64, CpuSet)> = None;
+
+    for cpu in online.iter() {
+        let cache = cpu_dir.join(format!("cpu{}/cache/index3", cpu));
+        let size = fs::read_to_string(cache.join("size")).ok()
+            .and_then(|s| s.trim().trim_end_matches('K').parse::<u64>().ok());
+        let shared = read_list(&cache.join("shared_cpu_list"));
+        if let (Some(size), Some(shared)) = (size, shared) {
+            if best.as_ref().is_none_or(|(best_size, _)| size > *best_size) {
+                best = Some((size, shared));
+            }
+        }
+    }
+
+    best.map(|(_, set)| set).unwrap_or_else(|| online.clone())
+}
+
+fn read_list(path: &Path) -> Option<CpuSet> {
+    fs::read_to_string(path).ok().and_then(|s| CpuSet::parse_list(&s).ok())
+}
+
+/// Restrict every thread of the process at `proc_dir` (`/proc/<pid>`) to `cpus`.
+pub fn set_affinity(proc_dir: &Path, cpus: &CpuSet) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus.iter() {
+        // CPU_SET does not check bounds
+        if cpu as usize >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        unsafe { libc::CPU_SET(cpu as usize, &mut set) };
+    }
+
+    let tasks = fs::read_dir(proc_dir.join("task"))?;
+    for task in tasks.flatten() {
+        let tid: libc::pid_t = match task.file_name().to_string_lossy().parse() {
+            Ok(tid) => tid,
+            Err(_) => continue,
+        };
+        let result = unsafe { libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+        if result != 0 {
+            let error = std::io::Error::last_os_error();
+            // Threads may exit while we iterate
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                return Err(error);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn huge_lists_are_rejected_while_parsing() {
+        assert_eq!(CpuSet::parse_list("0-3,8").unwrap().to_string(), "0-3,8");
+        assert!(CpuSet::parse_list("0-4000000000").is_err());
+        assert!(CpuSet::parse_list("9000").is_err());
+        assert!(CpuSet::parse_list("3-1").is_err());
+    }
+
+    #[test]
+    fn resolved_cpus_must_be_online_and_fit_a_mask() {
+        let online = CpuSet::parse_list("0-7").unwrap();
+        let topology = Topology { online: online.clone(), performance: online.clone(), ..Topology::default() };
+        assert_eq!(topology.resolve_affinity("2-3").unwrap().to_string(), "2-3");
+        assert_eq!(topology.resolve_affinity("all").unwrap(), online);
+        assert!(topology.resolve_affinity("6-9").is_err());
+        assert!(topology.resolve_affinity("1024").is_err());
+        assert!(topology.resolve_affinity("vcache").is_err());
+
+        // Unknown online CPUs still bound the list by the mask size
+        let unknown = Topology::default();
+        assert!(unknown.resolve_affinity("5").is_ok());
+        assert!(unknown.resolve_affinity("5000").is_err());
+    }
+
+    #[test]
+    fn online_cpus_are_read_below_sys_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cpu_dir = tmp.path().join("devices/system/cpu");
+        fs::create_dir_all(&cpu_dir).unwrap();
+        fs::write(cpu_dir.join("online"), "0-5,8\n").unwrap();
+        assert_eq!(online_cpus(tmp.path()), 7);
+        assert_eq!(Topology::detect(&tmp.path().join("devices")).online.to_string(), "0-5,8");
+        assert_eq!(online_cpus(&tmp.path().join("missing")), num_cpus::get());
+    }
+}
@@ -0,0 +1,375 @@
+//! Rules of individual users, from `~/.config/ananicy.d/` of logged-in users
+//! and `users/<name>/` of the config directory.
+//!
+//! User rules only match processes of their user and may only lower
+//! priority. Rules that would do anything else are rejected when loading,
+//! after their types are applied, and their nice value, scheduling policy and
+//! OOM score are only applied when they lower the process's current ones.
+
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use nix::unistd::{Uid, User};
+use crate::matcher::MatchExpr;
+use crate::rules::{self, Rule};
+use crate::topology::TOPOLOGY;
+use crate::utils;
+
+/// Directory of the config directory holding a rule directory per user name.
+pub const USERS_DIR: &str = "users";
+
+/// Rule directory below a user's home.
+const HOME_RULES_DIR: &str = ".config/ananicy.d";
+
+/// Files in home directories larger than this are not read.
+const MAX_FILE_SIZE: u64 = 1 << 20;
+
+const REALTIME_POLICIES: &[&str] = &["rr", "fifo", "iso"];
+
+/// Files of user rule directories with their modification times.
+pub type Stamp = Vec<(PathBuf, Option<SystemTime>)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    pub home: Option<PathBuf>,
+}
+
+impl Account {
+    /// Rule directories with the owner their files must have, lowest
+    /// precedence first. Files the administrator provides are trusted.
+    fn rule_dirs(&self, config_dir: &Path) -> Vec<(PathBuf, Option<u32>)> {
+        let mut dirs = Vec::new();
+        if let Some(ref home) = self.home {
+            dirs.push((home.join(HOME_RULES_DIR), Some(self.uid)));
+        }
+        dirs.push((config_dir.join(USERS_DIR).join(&self.name), None));
+        dirs
+    }
+}
+
+/// Users with a session, from the runtime directories logind creates below
+/// `runtime_root`, and users with a directory in `users/` of `config_dir`.
+pub fn accounts(config_dir: &Path, runtime_root: &Path) -> Vec<Account> {
+    let mut uids: BTreeSet<u32> = fs::read_dir(runtime_root).into_iter().flatten().flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect();
+
+    for entry in fs::read_dir(config_dir.join(USERS_DIR)).into_iter().flatten().flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.path().is_dir() {
+            continue;
+        }
+        match utils::user_uid(&name) {
+            Some(uid) => {
+                uids.insert(uid);
+            }
+            None => warn!("Ignoring {:?}: there is no user {}", entry.path(), name),
+        }
+    }
+
+    uids.into_iter()
+        .filter_map(|uid| match User::from_uid(Uid::from_raw(uid)) {
+            Ok(Some(user)) => Some(Account { name: user.name, uid, home: Some(user.dir) }),
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Could not look up uid {}: {}", uid, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Files in the rule directories of `accounts` with their modification
+/// times, which change when a user adds, edits or removes rules.
+pub fn stamp(config_dir: &Path, accounts: &[Account]) -> Stamp {
+    let mut files = Vec::new();
+    for account in accounts {
+        for (dir, owner) in account.rule_dirs(config_dir) {
+            list(&dir, owner.is_none(), &mut files);
+        }
+    }
+    files.into_iter()
+        .map(|path| {
+            let modified = fs::symlink_metadata(&path).and_then(|meta| meta.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+/// Load the rules of all `accounts`, with the types of the system `layers`
+/// available to them. Rules that could raise priority are rejected.
+pub fn load_rules(config_dir: &Path, layers: &[PathBuf], accounts: &[Account]) -> Result<Vec<Rule>> {
+    let system_types = rules::load_types(layers)?;
+    let mut loaded = Vec::new();
+
+    for account in accounts {
+        let files: Vec<Vec<(PathBuf, String)>> = account.rule_dirs(config_dir).into_iter()
+            .map(|(dir, owner)| read_files(&dir, owner))
+            .collect();
+        if files.iter().all(Vec::is_empty) {
+            continue;
+        }
+        info!("Loading rules of user {}", account.name);
+
+        let mut types = system_types.clone();
+        for (path, content) in files.iter().flatten() {
+            rules::read_types(path, content, &mut types);
+        }
+        let types = rules::resolve_types(&types);
+
+        // Rules of later directories come first so they win, as with layers
+        for (path, content) in files.iter().rev().flatten() {
+            let mut file_rules = Vec::new();
+            rules::read_rules(path, content, &mut file_rules);
+            rules::apply_types(&mut file_rules, &types);
+            for rule in file_rules {
+                match restrict(rule, account) {
+                    Ok(rule) => loaded.push(rule),
+                    Err((label, reason)) => {
+                        warn!("Rejecting rule '{}' of user {} in {:?}: {}", label, account.name, path, reason)
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Limit `rule` to processes of `account`, or return its label and why it
+/// is not allowed. Attributes inherited from types are checked as well.
+fn restrict(mut rule: Rule, account: &Account) -> Result<Rule, (String, String)> {
+    if let Some(reason) = violation(&rule) {
+        return Err((rule.label(), reason));
+    }
+    rule.owner = Some(account.uid);
+    // Below every system rule, whatever priority that has
+    rule.priority = Some(i32::MIN);
+    rule.match_expr = Some(match rule.match_expr.take() {
+        Some(expr) => MatchExpr::All(vec![MatchExpr::Uid(account.uid), expr]),
+        None => MatchExpr::Uid(account.uid),
+    });
+    Ok(rule)
+}
+
+fn violation(rule: &Rule) -> Option<String> {
+    if let Some(nice) = rule.nice.filter(|&nice| nice < 0) {
+        return Some(format!("nice {} raises priority, only 0 and above are allowed", nice));
+    }
+    if let Some(class) = rule.ioclass.as_deref().filter(|&class| class != "idle") {
+        return Some(format!("ioclass {} is not allowed, only idle", class));
+    }
+    if rule.ionice.is_some() {
+        return Some("ionice levels are not allowed, use ioclass idle".to_string());
+    }
+    if let Some(policy) = rule.sched.as_deref().filter(|policy| REALTIME_POLICIES.contains(policy)) {
+        return Some(format!("realtime scheduling ({}) is not allowed", policy));
+    }
+    if rule.rtprio.is_some() {
+        return Some("rtprio is not allowed".to_string());
+    }
+    if let Some(score) = rule.oom_score_adj.filter(|&score| score < 0) {
+        return Some(format!("oom_score_adj {} protects from the OOM killer, only 0 and above are allowed", score));
+    }
+    if rule.cgroup.is_some() || rule.freeze_cgroups.is_some() {
+        return Some("cgroups can only be used by system rules".to_string());
+    }
+    if let Some(Err(e)) = rule.cpu_affinity.as_deref().map(|spec| TOPOLOGY.resolve_affinity(spec)) {
+        return Some(e.to_string());
+    }
+    if rule.priority.is_some() || rule.merge == Some(true) {
+        return Some("priority and merge are not allowed, user rules rank below system rules".to_string());
+    }
+    None
+}
+
+/// Rule and type files below `dir` with their content, in name order.
+///
+/// With an `owner`, only regular files of that user are read and symbolic
+/// links are not followed, so nobody can make the daemon read a file they
+/// could not read themselves.
+fn read_files(dir: &Path, owner: Option<u32>) -> Vec<(PathBuf, String)> {
+    let mut paths = Vec::new();
+    list(dir, owner.is_none(), &mut paths);
+
+    let mut files = Vec::new();
+    for path in paths {
+        let name = path.to_string_lossy();
+        if name.ends_with(".cgroups") {
+            warn!("Ignoring {:?}: cgroups can only be defined by the system configuration", path);
+            continue;
+        }
+        if !name.ends_with(".rules") && !name.ends_with(".types") && !name.ends_with(".rules.toml") {
+            continue;
+        }
+        match read_file(&path, owner) {
+            Ok(content) => files.push((path, content)),
+            Err(e) => warn!("Ignoring {:?}: {:#}", path, e),
+        }
+    }
+    files
+}
+
+fn read_file(path: &Path, owner: Option<u32>) -> Result<String> {
+    let Some(uid) = owner else {
+        return Ok(fs::read_to_string(path)?);
+    };
+
+    // Non-blocking so a FIFO cannot stall the daemon before it is rejected
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)?;
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        bail!("not a regular file");
+    }
+    if meta.uid() != uid {
+        bail!("owned by uid {} instead of the user", meta.uid());
+    }
+    if meta.len() > MAX_FILE_SIZE {
+        bail!("larger than {} bytes", MAX_FILE_SIZE);
+    }
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Files below `dir` in name order, entering symbolically linked directories
+/// only with `follow`.
+fn list(dir: &Path, follow: bool, files: &mut Vec<PathBuf>) {
+    let mut entries = match fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Could not read {:?}: {}", dir, e);
+            return;
+        }
+    };
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let is_dir = if follow {
+            path.is_dir()
+        } else {
+            entry.file_type().is_ok_and(|kind| kind.is_dir())
+        };
+        if is_dir {
+            list(&path, follow, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actuator::apply_rule;
+    use crate::fake::{Action, RecordingActuator};
+    use crate::process::ProcessInfo;
+
+    fn account(home: &Path) -> Account {
+        Account { name: "dev".to_string(), uid: unsafe { libc::getuid() }, home: Some(home.to_path_buf()) }
+    }
+
+    #[test]
+    fn user_rules_are_checked_and_limited_to_the_user() {
+        let tmp = tempfile::tempdir().unwrap();
+        let etc = tmp.path().join("etc");
+        fs::create_dir_all(&etc).unwrap();
+        fs::write(etc.join("00-types.types"), concat!(
+            "{\"type\": \"Game\", \"nice\": -5}\n",
+            "{\"type\": \"Background\", \"nice\": 15, \"ioclass\": \"idle\"}\n",
+            "{\"type\": \"Pinned\", \"cpu_affinity\": \"5000\"}\n",
+        )).unwrap();
+
+        let home = tmp.path().join("home");
+        let rules_dir = home.join(HOME_RULES_DIR);
+        fs::create_dir_all(&rules_dir).unwrap();
+        fs::write(rules_dir.join("dev.rules"), concat!(
+            "{\"name\": \"cargo\", \"type\": \"Background\"}\n",
+            "{\"name\": \"steam\", \"type\": \"Game\"}\n",
+            "{\"name\": \"ffmpeg\", \"sched\": \"fifo\"}\n",
+            "{\"name\": \"rustc\", \"oom_score_adj\": 300, \"match\": {\"cmdline\": \"--release\"}}\n",
+            "{\"name\": \"make\", \"cgroup\": \"background\"}\n",
+            "{\"name\": \"x\", \"cpu_affinity\": \"5000\"}\n",
+            "{\"name\": \"y\", \"type\": \"Pinned\"}\n",
+        )).unwrap();
+        fs::write(rules_dir.join("dev.cgroups"), "{\"cgroup\": \"mine\"}\n").unwrap();
+        std::os::unix::fs::symlink(etc.join("00-types.types"), rules_dir.join("linked.types")).unwrap();
+
+        // Per-user directories of the config directory belong to that user only
+        fs::create_dir_all(etc.join("users/dev")).unwrap();
+        fs::write(etc.join("users/dev/build.rules"), "{\"name\": \"ninja\", \"nice\": 5}\n").unwrap();
+        assert!(rules::load_rules(std::slice::from_ref(&etc)).unwrap().is_empty());
+
+        let account = account(&home);
+        let loaded = load_rules(&etc, std::slice::from_ref(&etc), std::slice::from_ref(&account)).unwrap();
+        let names: Vec<_> = loaded.iter().map(|rule| rule.name.as_deref().unwrap()).collect();
+        assert_eq!(names, ["ninja", "cargo", "rustc"]);
+        assert_eq!((loaded[1].nice, loaded[1].ioclass.as_deref()), (Some(15), Some("idle")));
+        assert!(loaded.iter().all(|rule| rule.owner == Some(account.uid)));
+        assert_eq!(
+            loaded[2].match_expr,
+            Some(MatchExpr::All(vec![MatchExpr::Uid(account.uid), MatchExpr::Cmdline("--release".to_string())])),
+        );
+
+        let mine = ProcessInfo { pid: 1, name: "cargo".to_string(), uid: account.uid, ..ProcessInfo::default() };
+        let other = ProcessInfo { uid: account.uid + 1, ..mine.clone() };
+        assert!(mine.matches_rule(&loaded[1]));
+        assert!(!other.matches_rule(&loaded[1]));
+
+        let pinned: Rule = serde_json::from_str(r#"{"name": "y", "cpu_affinity": "0-4000000000"}"#).unwrap();
+        let (label, reason) = restrict(pinned, &account).unwrap_err();
+        assert_eq!(label, "y");
+        assert!(reason.contains("highest id"), "{}", reason);
+
+        let stamp = stamp(&etc, std::slice::from_ref(&account));
+        assert_eq!(stamp.len(), 4);
+    }
+
+    #[test]
+    fn user_nice_only_makes_processes_nicer() {
+        let mut rule: Rule = serde_json::from_str(r#"{"name": "cargo", "nice": 5}"#).unwrap();
+        rule.owner = Some(1000);
+        let actuator = RecordingActuator::new();
+        let process = |pid, nice| ProcessInfo { pid, name: "cargo".to_string(), uid: 1000, nice, ..ProcessInfo::default() };
+
+        apply_rule(&actuator, &process(1, 0), &rule, &()).unwrap();
+        apply_rule(&actuator, &process(2, 10), &rule, &()).unwrap();
+        assert_eq!(actuator.take(), vec![Action::Nice { pid: 1, nice: 5 }]);
+    }
+
+    #[test]
+    fn user_rules_never_raise_oom_score_or_policy() {
+        let mut rule: Rule = serde_json::from_str(r#"{"name": "cargo", "sched": "normal", "oom_score_adj": 0}"#).unwrap();
+        rule.owner = Some(1000);
+        let actuator = RecordingActuator::new();
+        let process = |pid, oom_score_adj, sched_policy| ProcessInfo {
+            pid, name: "cargo".to_string(), uid: 1000, oom_score_adj, sched_policy, ..ProcessInfo::default()
+        };
+
+        // Already more likely to be killed, and on SCHED_IDLE
+        apply_rule(&actuator, &process(1, 300, libc::SCHED_IDLE as u32), &rule, &()).unwrap();
+        assert_eq!(actuator.take(), vec![]);
+        apply_rule(&actuator, &process(2, -100, libc::SCHED_FIFO as u32), &rule, &()).unwrap();
+        assert_eq!(actuator.take(), vec![
+            Action::Scheduler { pid: 2, policy: "normal".to_string(), rtprio: None },
+            Action::OomScoreAdj { pid: 2, score: 0 },
+        ]);
+
+        // System rules apply as configured
+        rule.owner = None;
+        apply_rule(&actuator, &process(3, 300, libc::SCHED_IDLE as u32), &rule, &()).unwrap();
+        assert_eq!(actuator.take().len(), 2);
+    }
+}
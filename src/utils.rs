@@ -1,7 +1,12 @@
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
-use anyhow::Result;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use log::warn;
+use nix::unistd::User;
+use once_cell::sync::Lazy;
+use crate::error::{Error, Result};
 
 pub fn check_disk_schedulers() {
     let block_dir = Path::new("/sys/class/block");
@@ -35,30 +40,104 @@ pub fn check_disk_schedulers() {
     }
 }
 
-pub fn validate_nice(nice: i32) -> Result<i32> {
-    if nice < -20 || nice > 19 {
-        anyhow::bail!("Nice value must be between -20 and 19");
+fn validate_range(field: &'static str, value: i32, min: i32, max: i32) -> Result<i32> {
+    if !(min..=max).contains(&value) {
+        return Err(Error::OutOfRange { field, value: value.into(), min: min.into(), max: max.into() });
     }
-    Ok(nice)
+    Ok(value)
+}
+
+pub const NICE_MIN: i32 = -20;
+pub const NICE_MAX: i32 = 19;
+pub const IONICE_MIN: i32 = 0;
+pub const IONICE_MAX: i32 = 7;
+pub const RTPRIO_MIN: i32 = 1;
+pub const RTPRIO_MAX: i32 = 99;
+pub const OOM_SCORE_ADJ_MIN: i32 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i32 = 1000;
+
+/// I/O scheduling classes understood by `ionice`.
+pub const IOCLASSES: &[&str] = &["none", "best-effort", "idle", "realtime"];
+
+/// Scheduling policies understood by `schedtool`.
+pub const SCHED_POLICIES: &[&str] = &["other", "normal", "rr", "fifo", "batch", "iso", "idle"];
+
+pub fn validate_nice(nice: i32) -> Result<i32> {
+    validate_range("nice", nice, NICE_MIN, NICE_MAX)
 }
 
 pub fn validate_ionice(ionice: i32) -> Result<i32> {
-    if ionice < 0 || ionice > 7 {
-        anyhow::bail!("IOnice value must be between 0 and 7");
-    }
-    Ok(ionice)
+    validate_range("ionice", ionice, IONICE_MIN, IONICE_MAX)
 }
 
 pub fn validate_rtprio(rtprio: i32) -> Result<i32> {
-    if rtprio < 1 || rtprio > 99 {
-        anyhow::bail!("RT priority must be between 1 and 99");
-    }
-    Ok(rtprio)
+    validate_range("rtprio", rtprio, RTPRIO_MIN, RTPRIO_MAX)
 }
 
 pub fn validate_oom_score_adj(adj: i32) -> Result<i32> {
-    if adj < -1000 || adj > 1000 {
-        anyhow::bail!("OOM score adjustment must be between -1000 and 1000");
+    validate_range("oom_score_adj", adj, OOM_SCORE_ADJ_MIN, OOM_SCORE_ADJ_MAX)
+}
+
+pub fn validate_choice(field: &'static str, value: &str, allowed: &'static [&'static str]) -> Result<()> {
+    if !allowed.contains(&value) {
+        return Err(Error::Choice { field, value: value.to_string(), allowed });
+    }
+    Ok(())
+}
+
+/// How long a user that does not exist is remembered as missing.
+const USER_MISS_TTL: Duration = Duration::from_secs(60);
+
+/// Uid of a user name, `None` if it did not exist, and when it was looked up.
+type UserEntry = (Option<u32>, Instant);
+
+static USERS: Lazy<Mutex<HashMap<String, UserEntry>>> = Lazy::new(Default::default);
+
+/// Uid of the user `name`, or of a numeric `name` itself. Found users are
+/// cached for the lifetime of the process; misses for [`USER_MISS_TTL`], so
+/// an account created after startup is picked up without looking up a
+/// missing one for every process.
+pub fn user_uid(name: &str) -> Option<u32> {
+    if let Ok(uid) = name.parse() {
+        return Some(uid);
     }
-    Ok(adj)
-}
\ No newline at end of file
+    let cached = USERS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(name).copied();
+    match cached {
+        Some((Some(uid), _)) => return Some(uid),
+        Some((None, at)) if at.elapsed() < USER_MISS_TTL => return None,
+        _ => {}
+    }
+    // NSS may ask a remote directory, so the cache is not locked meanwhile
+    let uid = match User::from_name(name) {
+        Ok(user) => user.map(|user| user.uid.as_raw()),
+        Err(e) => {
+            warn!("Could not look up user {}: {}", name, e);
+            None
+        }
+    };
+    USERS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(name.to_string(), (uid, Instant::now()));
+    uid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_users_are_cached_for_a_while() {
+        assert_eq!(user_uid("1000"), Some(1000));
+        assert_eq!(user_uid("root"), Some(0));
+        assert_eq!(user_uid("no-such-user-ananicy"), None);
+        {
+            let users = USERS.lock().unwrap();
+            assert_eq!(users.get("root").map(|entry| entry.0), Some(Some(0)));
+            assert_eq!(users.get("no-such-user-ananicy").map(|entry| entry.0), Some(None));
+            assert!(!users.contains_key("1000"));
+        }
+
+        // An expired miss is looked up again
+        let Some(expired) = Instant::now().checked_sub(USER_MISS_TTL * 2) else { return };
+        USERS.lock().unwrap().insert("root".to_string(), (None, expired));
+        assert_eq!(user_uid("root"), Some(0));
+    }
+}